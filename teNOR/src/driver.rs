@@ -0,0 +1,684 @@
+//! A model of fortISSimO itself, which plays songs tick by tick, writing to the APU's registers like
+//! the driver does.
+//!
+//! This follows `fortISSimO.asm` closely, quirks included, but works from the [`Song`] directly
+//! instead of exported data; cells are still encoded the way teNOR exports them (see [`Cell`]), so
+//! that effects see their parameters the way the driver does.
+//! The few things that the driver asserts can't happen (e.g. vibrato on CH4) are not modelled.
+
+use crate::{
+    export::RawInstr,
+    optimise::{Cell, CompactedMapping},
+    song::{EffectId, Note, Song, Subpattern},
+    LAST_NOTE, PATTERN_LENGTH,
+};
+
+// The APU's registers, by the low byte of their address (like `ldh [c], a` uses).
+pub const NR10: u8 = 0x10;
+pub const NR11: u8 = 0x11;
+pub const NR12: u8 = 0x12;
+pub const NR13: u8 = 0x13;
+pub const NR14: u8 = 0x14;
+pub const NR21: u8 = 0x16;
+pub const NR22: u8 = 0x17;
+pub const NR23: u8 = 0x18;
+pub const NR24: u8 = 0x19;
+pub const NR30: u8 = 0x1A;
+pub const NR31: u8 = 0x1B;
+pub const NR32: u8 = 0x1C;
+pub const NR33: u8 = 0x1D;
+pub const NR34: u8 = 0x1E;
+pub const NR41: u8 = 0x20;
+pub const NR42: u8 = 0x21;
+pub const NR43: u8 = 0x22;
+pub const NR44: u8 = 0x23;
+pub const NR50: u8 = 0x24;
+pub const NR51: u8 = 0x25;
+pub const NR52: u8 = 0x26;
+pub const WAVE_RAM: u8 = 0x30;
+
+/// What the driver writes to the APU through; reads must behave like the hardware's, i.e. with
+/// write-only bits reading back as 1.
+pub trait Registers {
+    fn read(&mut self, reg: u8) -> u8;
+    fn write(&mut self, reg: u8, value: u8);
+}
+
+/// Which bits of a register read back as 1 regardless of what was written (see [`Registers::read`]).
+pub(crate) fn read_mask(reg: u8) -> u8 {
+    const READ_MASKS: [u8; 0x17] = [
+        0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR1x
+        0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR2x
+        0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR3x
+        0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR4x
+        0x00, 0x00, 0x70, // NR5x
+    ];
+    match reg {
+        NR10..=NR52 => READ_MASKS[usize::from(reg - NR10)],
+        WAVE_RAM..=0x3F => 0x00,
+        _ => 0xFF,
+    }
+}
+
+/// `hUGE_NO_WAVE`.
+const NO_WAVE: u8 = 100;
+/// `AUDENV_UP`; written to NRx2 to mute a channel without turning its DAC off.
+const ENV_UP: u8 = 0x08;
+const LFSR_7STEP: u8 = 0x08;
+
+/// `hUGE_note_table.inc`.
+const PERIODS: [u16; LAST_NOTE as usize] = [
+    44, 156, 262, 363, 457, 547, 631, 710, 786, 854, 923, 986, 1046, 1102, 1155, 1205, 1253, 1297,
+    1339, 1379, 1417, 1452, 1486, 1517, 1546, 1575, 1602, 1627, 1650, 1673, 1694, 1714, 1732, 1750,
+    1767, 1783, 1798, 1812, 1825, 1837, 1849, 1860, 1871, 1881, 1890, 1899, 1907, 1915, 1923, 1930,
+    1936, 1943, 1949, 1954, 1959, 1964, 1969, 1974, 1978, 1982, 1985, 1988, 1992, 1995, 1998, 2001,
+    2004, 2006, 2009, 2011, 2013, 2015,
+];
+
+/// The driver's state, i.e. its variables in RAM.
+#[derive(Debug, Clone)]
+pub struct Driver<'song, 'input> {
+    song: &'song Song<'input>,
+
+    muted_channels: u8,
+    allowed_channels: u8,
+    loaded_wave: u8,
+
+    arp_state: u8,
+    row_timer: u8,
+    ticks_per_row: u8,
+    /// Like the driver, this and `order_idx` are in bytes, i.e. twice the order row's index.
+    last_order_idx: u8,
+    order_idx: u8,
+    pattern_idx: u8,
+    force_row: u8,
+
+    channels: [Channel<'song>; 4],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Channel<'song> {
+    fx_params: u8,
+    instr_and_fx: u8,
+    note: u8,
+    subpattern: Option<&'song Subpattern>,
+    subpattern_row: u8,
+    length_bit: u8,
+    period: u16,
+    /// Like in the driver, this shares its bytes with the vibrato's offset (low) and state (high).
+    porta_target: u16,
+    vibrato_prev_arg: u8,
+    // CH4 only.
+    lfsr_width: u8,
+    polynom: u8,
+}
+
+impl<'song, 'input> Driver<'song, 'input> {
+    /// Starts playing a song, like `hUGE_SelectSong`; `muted_channels` is the value of
+    /// `hUGE_MutedChannels` at that point.
+    pub fn new(song: &'song Song<'input>, muted_channels: u8, regs: &mut impl Registers) -> Self {
+        let driver = Self {
+            song,
+            muted_channels,
+            allowed_channels: 0,
+            loaded_wave: NO_WAVE,
+            arp_state: 1,
+            row_timer: 1, // The next tick will switch to a new row.
+            ticks_per_row: song.ticks_per_row,
+            last_order_idx: ((song.order_matrix.len() - 1) * 2) as u8,
+            // Begin at order 0 and row 0, since `force_row` will cause the order to advance.
+            order_idx: 0u8.wrapping_sub(2),
+            pattern_idx: 0,
+            force_row: 0u8.wrapping_sub(PATTERN_LENGTH),
+            channels: Default::default(),
+        };
+
+        // Kill the channels that aren't muted, but re-enable them right away to avoid pops.
+        for (channel, reg) in [NR12, NR22, NR30, NR42].into_iter().enumerate() {
+            if driver.muted_channels & 1 << channel == 0 {
+                if reg == NR30 {
+                    regs.write(NR30, 0x1A); // `ld [hl], l`
+                    regs.write(NR30, 0xFF); // `ld [hl], h`
+                } else {
+                    regs.write(reg, 0);
+                    regs.write(reg, ENV_UP);
+                }
+            }
+        }
+
+        driver
+    }
+
+    /// Like writing to `hUGE_MutedChannels`: channels whose bit is set are left alone by the
+    /// driver, and the others are only taken back once a new note plays on them.
+    pub fn set_muted_channels(&mut self, muted_channels: u8) {
+        self.muted_channels = muted_channels;
+    }
+
+    /// Like writing `hUGE_NO_WAVE` to `hUGE_LoadedWaveID`: forces the next wave instrument to
+    /// reload its wave, which must be done if something else modified wave RAM.
+    pub fn invalidate_wave(&mut self) {
+        self.loaded_wave = NO_WAVE;
+    }
+
+    /// Runs one tick, like `hUGE_TickSound`.
+    pub fn tick(&mut self, regs: &mut impl Registers) {
+        self.allowed_channels &= !self.muted_channels;
+
+        self.arp_state -= 1;
+        if self.arp_state == 0 {
+            self.arp_state = 3;
+        }
+
+        self.row_timer = self.row_timer.wrapping_sub(1);
+        if self.row_timer != 0 {
+            for channel in 0..4 {
+                self.run_continuous_fx(channel, regs);
+                self.tick_subpattern(channel, regs);
+            }
+            return;
+        }
+
+        // This is the first tick; switch to the next row.
+        self.row_timer = self.ticks_per_row;
+        if self.force_row != 0 {
+            self.pattern_idx = self.force_row;
+            self.next_order();
+        } else {
+            self.pattern_idx = self.pattern_idx.wrapping_add(1);
+            if self.pattern_idx == 0 {
+                self.pattern_idx = 0u8.wrapping_sub(PATTERN_LENGTH);
+                self.next_order();
+            }
+        }
+        let row = usize::from(self.pattern_idx & (PATTERN_LENGTH - 1));
+        self.force_row = 0;
+
+        // If the previous FX was not a vibrato, reset the "vibrato arg". (CH4 doesn't support it.)
+        for channel in &mut self.channels[..3] {
+            if channel.instr_and_fx & 0x0F != EffectId::Vibrato as u8 {
+                channel.vibrato_prev_arg = 0;
+            }
+        }
+
+        for channel in 0..4 {
+            if let Some(note) = self.read_row(channel, row) {
+                self.play_note(channel, note, regs);
+            }
+        }
+
+        for channel in 0..4 {
+            self.run_tick0_fx(channel, regs);
+            self.tick_subpattern(channel, regs);
+        }
+    }
+
+    fn next_order(&mut self) {
+        self.order_idx = if self.order_idx == self.last_order_idx {
+            0
+        } else {
+            self.order_idx.wrapping_add(2)
+        };
+    }
+
+    /// Returns the note to play, if any.
+    fn read_row(&mut self, channel: usize, row: usize) -> Option<u8> {
+        let order_row = &self.song.order_matrix[usize::from(self.order_idx / 2)];
+        let cell = Cell::from(&self.song.patterns[order_row[channel]][row]);
+        let chan = &mut self.channels[channel];
+        chan.fx_params = cell.first_byte();
+        chan.instr_and_fx = cell.second_byte();
+
+        let note = cell.third_byte();
+        if note == Note::None as u8 {
+            return None;
+        }
+        chan.note = note;
+        // Tone porta and note delay don't play the note yet.
+        (chan.instr_and_fx & 0x0F & !0x04 != EffectId::TonePorta as u8).then_some(note)
+    }
+
+    fn mask(channel: usize) -> u8 {
+        1 << channel
+    }
+
+    fn is_allowed(&self, channel: usize) -> bool {
+        self.allowed_channels & Self::mask(channel) != 0
+    }
+
+    /// The register NRxy of the channel; "NR20" and "NR40" don't exist, but the driver still
+    /// writes to where they would be.
+    fn nr(channel: usize, y: u8) -> u8 {
+        NR10 + 5 * channel as u8 + y
+    }
+
+    fn play_note(&mut self, channel: usize, note: u8, regs: &mut impl Registers) {
+        if self.muted_channels & Self::mask(channel) != 0 {
+            return;
+        }
+
+        let instr_id = self.channels[channel].instr_and_fx >> 4;
+        if instr_id != 0 {
+            let bank = match channel {
+                0 | 1 => &self.song.instruments.duty,
+                2 => &self.song.instruments.wave,
+                _ => &self.song.instruments.noise,
+            };
+            let instrument = &bank[usize::from(instr_id - 1)];
+            let raw = RawInstr::new(instrument, &CompactedMapping::identity());
+            self.allowed_channels |= Self::mask(channel);
+
+            let chan = &mut self.channels[channel];
+            chan.subpattern = instrument.subpattern.as_ref();
+            chan.subpattern_row = 0;
+            match channel {
+                0 | 1 => {
+                    for (y, value) in raw.head.iter().enumerate() {
+                        regs.write(Self::nr(channel, y as u8), *value);
+                    }
+                    chan.length_bit = raw.tail[0];
+                }
+                2 => {
+                    regs.write(NR31, raw.head[0]);
+                    regs.write(NR32, raw.head[1]);
+                    chan.length_bit = raw.tail[0];
+                    if self.loaded_wave != raw.tail[1] {
+                        self.load_wave(raw.tail[1], regs);
+                    }
+                    // Triggering CH3 while it's reading wave RAM can corrupt it, so kill it first.
+                    regs.write(NR30, 0x1A);
+                    regs.write(NR30, 0xFF);
+                }
+                _ => {
+                    regs.write(NR42, raw.head[0]);
+                    regs.write(NR41, raw.tail[0] & 0x3F);
+                    chan.length_bit = 0x80 | raw.tail[0] & 0x40;
+                    chan.lfsr_width = if raw.tail[0] & 0x80 != 0 {
+                        LFSR_7STEP
+                    } else {
+                        0
+                    };
+                }
+            }
+        }
+
+        if channel == 3 {
+            self.set_noise_freq(note, regs);
+        } else {
+            self.play_new_note(channel, note, regs);
+        }
+    }
+
+    /// `PlayNewNoteStandard`.
+    fn play_new_note(&mut self, channel: usize, note: u8, regs: &mut impl Registers) {
+        let chan = &mut self.channels[channel];
+        chan.period = period(note);
+        regs.write(Self::nr(channel, 3), chan.period as u8);
+        regs.write(
+            Self::nr(channel, 4),
+            (chan.period >> 8) as u8 | chan.length_bit,
+        );
+        // The only "control bit" that should persist is the length enable.
+        chan.length_bit &= 0x7F;
+    }
+
+    /// `PlayNoiseNote.setFreq`.
+    fn set_noise_freq(&mut self, note: u8, regs: &mut impl Registers) {
+        let chan = &mut self.channels[3];
+        chan.polynom = noise_polynom(note);
+        regs.write(NR43, chan.polynom | chan.lfsr_width);
+        regs.write(NR44, chan.length_bit);
+        chan.length_bit &= 0x7F;
+    }
+
+    /// `LoadWave`; the ID is the wave's offset, i.e. 16 times its index.
+    fn load_wave(&mut self, id: u8, regs: &mut impl Registers) {
+        self.loaded_wave = id;
+        let wave = &self.song.waves[usize::from(id >> 4)];
+
+        // Temporarily "disconnect" CH3, to mitigate the pop from turning the DAC off.
+        let panning = regs.read(NR51);
+        regs.write(NR51, panning & !0x44);
+        regs.write(NR30, 0);
+        for (i, byte) in wave.iter().enumerate() {
+            regs.write(WAVE_RAM + i as u8, *byte);
+        }
+        regs.write(NR30, 0x80);
+        regs.write(NR51, panning);
+    }
+
+    fn run_tick0_fx(&mut self, channel: usize, regs: &mut impl Registers) {
+        let chan = &self.channels[channel];
+        let param = chan.fx_params;
+        match effect_id(chan.instr_and_fx) {
+            EffectId::Arpeggio => self.arpeggio(channel, param, regs),
+            EffectId::TonePorta => {
+                let chan = &mut self.channels[channel];
+                chan.porta_target = period(chan.note);
+            }
+            EffectId::Vibrato => {
+                // If the previous vibrato's arg was the same, simply continue it.
+                let chan = &mut self.channels[channel];
+                if param != chan.vibrato_prev_arg {
+                    chan.vibrato_prev_arg = param;
+                    chan.porta_target = 0; // Reset the offset and the state.
+                }
+                self.vibrato(channel, param, regs);
+            }
+            EffectId::SetMasterVol => regs.write(NR50, param),
+            EffectId::SetPanning => regs.write(NR51, param),
+            EffectId::ChangeTimbre => self.change_timbre(channel, param, regs),
+            EffectId::VolSlide => self.volume_slide(channel, param, regs),
+            EffectId::PosJump => {
+                self.order_idx = param;
+                // If a row is already being forced, this keeps it, but selects row 0 otherwise.
+                self.force_row |= 0u8.wrapping_sub(PATTERN_LENGTH);
+            }
+            EffectId::SetVol => self.set_volume(channel, param, regs),
+            EffectId::PatternBreak => self.force_row = param,
+            EffectId::NoteCut => self.note_cut(channel, param, 0, regs),
+            EffectId::SetTempo => {
+                // The new tempo takes effect immediately.
+                self.ticks_per_row = param;
+                self.row_timer = param;
+            }
+            EffectId::PortaUp
+            | EffectId::PortaDown
+            | EffectId::CallRoutine
+            | EffectId::NoteDelay => {}
+        }
+    }
+
+    fn run_continuous_fx(&mut self, channel: usize, regs: &mut impl Registers) {
+        let chan = &self.channels[channel];
+        let param = chan.fx_params;
+        let elapsed = self.ticks_per_row.wrapping_sub(self.row_timer);
+        match effect_id(chan.instr_and_fx) {
+            EffectId::Arpeggio => self.arpeggio(channel, param, regs),
+            EffectId::PortaUp => self.porta(channel, param, true, regs),
+            EffectId::PortaDown => self.porta(channel, param, false, regs),
+            EffectId::TonePorta => self.tone_porta(channel, param, regs),
+            EffectId::Vibrato => self.vibrato(channel, param, regs),
+            EffectId::NoteDelay => {
+                if elapsed == param {
+                    let note = self.channels[channel].note;
+                    self.play_note(channel, note, regs);
+                }
+            }
+            EffectId::NoteCut => self.note_cut(channel, param, elapsed, regs),
+            EffectId::SetMasterVol
+            | EffectId::CallRoutine
+            | EffectId::SetPanning
+            | EffectId::ChangeTimbre
+            | EffectId::VolSlide
+            | EffectId::PosJump
+            | EffectId::SetVol
+            | EffectId::PatternBreak
+            | EffectId::SetTempo => {}
+        }
+    }
+
+    fn tick_subpattern(&mut self, channel: usize, regs: &mut impl Registers) {
+        let allowed = self.is_allowed(channel);
+        let chan = &mut self.channels[channel];
+        let length_bit = chan.length_bit;
+        let Some(subpattern) = chan.subpattern else {
+            return;
+        };
+        let cell = &subpattern[usize::from(chan.subpattern_row)];
+        chan.subpattern_row = cell.next_row_idx;
+
+        // Offsets that are too high are ignored.
+        if cell.offset < LAST_NOTE && allowed {
+            let note = chan
+                .note
+                .wrapping_add(cell.offset)
+                .wrapping_sub(LAST_NOTE / 2);
+            if channel == 3 {
+                chan.polynom = noise_polynom(note);
+                regs.write(NR43, chan.lfsr_width | chan.polynom);
+                regs.write(NR44, length_bit);
+            } else {
+                chan.period = period(note);
+                regs.write(Self::nr(channel, 3), chan.period as u8);
+                regs.write(Self::nr(channel, 4), (chan.period >> 8) as u8 | length_bit);
+            }
+        }
+
+        let param = match cell.effect_code {
+            // These aren't supported in subpatterns (and their params may not even be valid).
+            EffectId::TonePorta
+            | EffectId::Vibrato
+            | EffectId::PosJump
+            | EffectId::PatternBreak
+            | EffectId::NoteCut
+            | EffectId::SetTempo => return,
+            _ => Cell::from(cell).first_byte(),
+        };
+        match cell.effect_code {
+            EffectId::Arpeggio => self.arpeggio(channel, param, regs),
+            EffectId::PortaUp => self.porta(channel, param, true, regs),
+            EffectId::PortaDown => self.porta(channel, param, false, regs),
+            EffectId::SetMasterVol => regs.write(NR50, param),
+            // Repurposed as "fixed mode": plays an absolute note, regardless of muting.
+            EffectId::NoteDelay => {
+                if channel == 3 {
+                    self.set_noise_freq(param, regs);
+                } else {
+                    self.play_new_note(channel, param, regs);
+                }
+            }
+            EffectId::SetPanning => regs.write(NR51, param),
+            EffectId::ChangeTimbre => self.change_timbre(channel, param, regs),
+            EffectId::VolSlide => self.volume_slide(channel, param, regs),
+            EffectId::SetVol => self.set_volume(channel, param, regs),
+            _ => {}
+        }
+    }
+
+    fn arpeggio(&mut self, channel: usize, param: u8, regs: &mut impl Registers) {
+        // `000` is an empty row, even on CH4.
+        if param == 0 || !self.is_allowed(channel) || channel == 3 {
+            return;
+        }
+        let offset = match self.arp_state {
+            1 => 0,
+            2 => param & 0x0F,
+            _ => param >> 4,
+        };
+        let note = self.channels[channel].note.wrapping_add(offset);
+        self.play_new_note(channel, note, regs);
+    }
+
+    fn porta(&mut self, channel: usize, param: u8, up: bool, regs: &mut impl Registers) {
+        if !self.is_allowed(channel) || channel == 3 {
+            return;
+        }
+        let chan = &mut self.channels[channel];
+        chan.period = if up {
+            chan.period.wrapping_add(param.into())
+        } else {
+            chan.period.wrapping_sub(param.into())
+        };
+        regs.write(Self::nr(channel, 3), chan.period as u8);
+        regs.write(
+            Self::nr(channel, 4),
+            (chan.period >> 8) as u8 | chan.length_bit,
+        );
+    }
+
+    fn tone_porta(&mut self, channel: usize, param: u8, regs: &mut impl Registers) {
+        if !self.is_allowed(channel) || channel == 3 {
+            return;
+        }
+        let chan = &mut self.channels[channel];
+        // Move the (signed) delta towards 0, clamping it if it overshoots.
+        let delta = chan.period.wrapping_sub(chan.porta_target);
+        let (delta, overshot) = if delta & 0x8000 != 0 {
+            delta.overflowing_add(param.into())
+        } else {
+            delta.overflowing_sub(param.into())
+        };
+        chan.period = if overshot { 0 } else { delta }.wrapping_add(chan.porta_target);
+        regs.write(Self::nr(channel, 3), chan.period as u8);
+        regs.write(
+            Self::nr(channel, 4),
+            (chan.period >> 8) as u8 | chan.length_bit,
+        );
+    }
+
+    fn vibrato(&mut self, channel: usize, param: u8, regs: &mut impl Registers) {
+        if !self.is_allowed(channel) || channel == 3 {
+            return;
+        }
+        let chan = &mut self.channels[channel];
+        let [offset, state] = chan.porta_target.to_le_bytes();
+
+        // The upper 4 bits count down; when they underflow, they are reloaded from the param, and
+        // the direction (bit 0) flips.
+        let state = match state.checked_sub(0x10) {
+            Some(state) => state,
+            None => param & 0xFE | (state & 1 ^ 1),
+        };
+        let depth = param & 0x0F;
+        let offset = if state & 1 != 0 {
+            offset.wrapping_add(depth)
+        } else {
+            offset.wrapping_sub(depth)
+        };
+        chan.porta_target = u16::from_le_bytes([offset, state]);
+
+        let (low, carry) = (chan.period as u8).overflowing_add(offset);
+        regs.write(Self::nr(channel, 3), low);
+        regs.write(
+            Self::nr(channel, 4),
+            ((chan.period >> 8) as u8 + carry as u8) | chan.length_bit,
+        );
+    }
+
+    fn change_timbre(&mut self, channel: usize, param: u8, regs: &mut impl Registers) {
+        if !self.is_allowed(channel) {
+            return;
+        }
+        match channel {
+            0 => regs.write(NR11, param),
+            1 => regs.write(NR21, param),
+            2 => {
+                self.load_wave(param.rotate_left(4), regs);
+                // The channel had to be stopped to reload the wave, so retrigger it.
+                let chan = &self.channels[2];
+                regs.write(NR34, (chan.period >> 8) as u8 | chan.length_bit | 0x80);
+            }
+            _ => {
+                let polynom = regs.read(NR43) & !LFSR_7STEP;
+                regs.write(NR43, polynom | param);
+            }
+        }
+    }
+
+    fn volume_slide(&mut self, channel: usize, param: u8, regs: &mut impl Registers) {
+        if !self.is_allowed(channel) || channel == 2 {
+            return;
+        }
+        let up = param & 0xF0;
+        let down = param << 4;
+        let volume = regs.read(Self::nr(channel, 2)) & 0xF0;
+        let volume = volume.saturating_sub(down).checked_add(up).unwrap_or(0xF0);
+        // Writing $00 would kill the channel.
+        self.apply_volume(channel, volume | ENV_UP, regs);
+    }
+
+    fn set_volume(&mut self, channel: usize, param: u8, regs: &mut impl Registers) {
+        if !self.is_allowed(channel) {
+            return;
+        }
+        if channel == 2 {
+            // Quantise the finer-grained volume control down to one of 4 output levels.
+            let level = match param {
+                0xA0.. => 0x20,
+                0x50.. => 0x40,
+                0x00..=0x0F => 0x00,
+                _ => 0x60,
+            };
+            regs.write(NR32, level);
+            return;
+        }
+        // Preserve the envelope bits if none are specified.
+        let value = if param & 0x0F == 0 {
+            regs.read(Self::nr(channel, 2)) & 0x0F | param
+        } else {
+            param
+        };
+        self.apply_volume(channel, value, regs);
+    }
+
+    /// Writes to NRx2, and retriggers the channel (which the new volume requires).
+    fn apply_volume(&mut self, channel: usize, value: u8, regs: &mut impl Registers) {
+        regs.write(Self::nr(channel, 2), value);
+        let chan = &self.channels[channel];
+        // CH4 has no period, the driver reads its polynom there instead; this is harmless, since
+        // those bits of NR44 are unused.
+        let high = if channel == 3 {
+            chan.polynom
+        } else {
+            (chan.period >> 8) as u8
+        };
+        let control = regs.read(Self::nr(channel, 4)) & 0xC0;
+        regs.write(Self::nr(channel, 4), control | high & 0x3F);
+    }
+
+    fn note_cut(&mut self, channel: usize, param: u8, elapsed: u8, regs: &mut impl Registers) {
+        if elapsed != param {
+            return;
+        }
+        self.channels[channel].subpattern = None;
+        if !self.is_allowed(channel) {
+            return;
+        }
+        // Mute the channel without turning off its DAC, which would "pop".
+        if channel == 2 {
+            regs.write(NR30, 0x1A);
+            regs.write(NR30, 0xFF);
+        } else {
+            regs.write(Self::nr(channel, 2), ENV_UP);
+            regs.write(Self::nr(channel, 4), 0x80);
+        }
+    }
+}
+
+fn effect_id(instr_and_fx: u8) -> EffectId {
+    use EffectId::*;
+    [
+        Arpeggio,
+        PortaUp,
+        PortaDown,
+        TonePorta,
+        Vibrato,
+        SetMasterVol,
+        CallRoutine,
+        NoteDelay,
+        SetPanning,
+        ChangeTimbre,
+        VolSlide,
+        PosJump,
+        SetVol,
+        PatternBreak,
+        NoteCut,
+        SetTempo,
+    ][usize::from(instr_and_fx & 0x0F)]
+}
+
+fn period(note: u8) -> u16 {
+    // The driver would read past the end of its table, which is not worth modelling.
+    PERIODS[usize::from(note.min(LAST_NOTE - 1))]
+}
+
+/// `GetNoisePolynom`, formula by RichardULZ.
+fn noise_polynom(note: u8) -> u8 {
+    let flipped = !note.wrapping_add((256 - 64) as u8);
+    if flipped < 8 {
+        return flipped;
+    }
+    let shift = (flipped >> 2) - 1;
+    ((flipped & 3) + 4) | (shift << 4)
+}
@@ -0,0 +1,802 @@
+use std::{fmt::Display, io::Write};
+
+use clap::{crate_name, crate_version};
+
+use crate::{
+    optimise::{InstrKind, OutputCell, PatternId, PatternKey, SongResults},
+    playback,
+    song::{
+        DutyType, EnvelopeDirection, Instrument, InstrumentKind, LfsrWidth, Location, Routine,
+        RoutineBank, Subpattern, SweepDirection, WaveOutputLevel,
+    },
+    LAST_NOTE, PATTERN_LENGTH,
+};
+
+use super::{AsmOptions, Block, Export, ExportError, PatternLabel};
+
+pub(super) fn export(
+    export: &Export,
+    options: &AsmOptions,
+    output: &mut dyn Write,
+) -> Result<(), ExportError> {
+    let Export {
+        songs,
+        source_names,
+        descriptors,
+        results,
+        blocks,
+        ..
+    } = export;
+    macro_rules! output {
+        ($($arg:tt)*) => {
+            writeln!(output, $($arg)*)?
+        };
+    }
+
+    let batch = songs.len() > 1;
+    if options.routines && batch {
+        return Err(ExportError::RoutinesWithSeveralSongs);
+    }
+    let labels = Labels::new(export);
+
+    output!("; {}", export.origin());
+    for ((song, source_name), descriptor) in songs.iter().zip(*source_names).zip(*descriptors) {
+        if batch {
+            output!(";");
+            output!("; From {source_name}, as {descriptor}:");
+        }
+        for (mut header, string) in [
+            ("Song:", &song.name),
+            ("Artist:", &song.artist),
+            ("Comment:", &song.comment),
+        ] {
+            let header_len = header.len();
+            for line in string.split('\n') {
+                output!("; {header:<header_len$} {line}");
+                header = "";
+            }
+        }
+        if let Some(divider) = song.timer_divider {
+            output!("; Expected playback method: TMA = ${:02x}", divider);
+        } else {
+            output!("; Expected playback method: VBlank");
+        }
+    }
+    output!();
+    output!(
+        "REDEF fortISSimO_VERSION equs /* Generated with {} version: */ \"{}\"",
+        crate_name!(),
+        crate_version!(),
+    );
+    if !options.include_path.is_empty() {
+        output!("INCLUDE \"{}\"", options.include_path);
+        output!();
+    }
+    output!();
+    let event_constants = event_constants(export, options);
+    if options.event_constants && !event_constants.is_empty() {
+        for constant in &event_constants {
+            output!("; `6{:02X}` is used at:", constant.arg);
+            for (song_idx, location) in &constant.uses {
+                if batch {
+                    output!(";   - {}, {location}", descriptors[*song_idx]);
+                } else {
+                    output!(";   - {location}");
+                }
+            }
+            output!("DEF {} equ ${:02X}", constant.name, constant.arg);
+        }
+        output!();
+    }
+    if options.timing_constants {
+        for (song, descriptor) in songs.iter().zip(*descriptors) {
+            let timing = playback::timing(song);
+            output!(
+                "; {:.2} s of intro, then loops to order row {}, row {} every {:.2} s ({:.1} BPM)",
+                timing.seconds(timing.intro_ticks, song.timer_divider),
+                timing.loop_order_idx,
+                timing.loop_row,
+                timing.seconds(timing.loop_ticks, song.timer_divider),
+                timing.bpm(song.timer_divider),
+            );
+            output!("DEF {descriptor}_INTRO_TICKS equ {}", timing.intro_ticks);
+            output!(
+                "DEF {descriptor}_INTRO_FRAMES equ {}",
+                timing.frames(timing.intro_ticks),
+            );
+            output!("DEF {descriptor}_LOOP_TICKS equ {}", timing.loop_ticks);
+            output!(
+                "DEF {descriptor}_LOOP_FRAMES equ {}",
+                timing.frames(timing.loop_ticks),
+            );
+            output!(
+                "DEF {descriptor}_LOOP_ORDER_ROW equ {}",
+                timing.loop_order_idx
+            );
+            output!("DEF {descriptor}_LOOP_ROW equ {}", timing.loop_row);
+            output!();
+        }
+    }
+    if let Some((kind, name)) = &options.section {
+        output!("SECTION \"{name}\", {kind}");
+        output!();
+    }
+
+    for block in blocks {
+        match *block {
+            Block::Header(song_idx) => {
+                let song = &songs[song_idx];
+                output!("{}::", descriptors[song_idx]);
+                output!("\tdb {} ; Tempo (ticks/row)", song.ticks_per_row);
+                output!(
+                    "\tdb ({} - 1) * 2 ; Max index into order \"columns\"",
+                    song.order_matrix.len(),
+                );
+                output!("\tdw .dutyInstrs, .waveInstrs, .noiseInstrs");
+                output!("\tdw {}", labels.routine(song_idx));
+                output!("\tdw .waves");
+                output!(
+                    "\tdb HIGH({}), HIGH({})",
+                    labels.shared(song_idx, "mainCellCatalog"),
+                    labels.shared(song_idx, "subpatCellCatalog"),
+                );
+                output!();
+            }
+
+            Block::OrderColumns(song_idx) => {
+                for i in 0..4 {
+                    let kind = InstrKind::from_channel_id(i);
+                    write!(output, ".ch{}  dw", i + 1)?;
+                    for id in &songs[song_idx].order_matrix {
+                        match export.order_target((song_idx, PatternId::Pattern(kind, id[i]))) {
+                            Some(key) => write!(output, " {:2},", labels.pattern(song_idx, key))?,
+                            // This order row is never played.
+                            None => write!(output, " 0,")?,
+                        }
+                    }
+                    output!();
+                }
+                output!();
+            }
+
+            Block::RowPool(kind) => {
+                let catalog = export.catalog(kind);
+                let row_pool = match kind {
+                    super::CatalogKind::Main => &results.main_row_pool,
+                    super::CatalogKind::Subpattern => &results.subpat_row_pool,
+                };
+                let mut row_idx = 0;
+                for entry in row_pool {
+                    match entry {
+                        OutputCell::Label(whose) => {
+                            write!(output, "\n{}", labels.definition(*whose))?;
+                            row_idx = 0;
+                        }
+                        OutputCell::Cell(id) => {
+                            write!(
+                                output,
+                                "{}{:3}",
+                                if row_idx == 0 { "\n\tdb " } else { "," },
+                                catalog.reverse_lookup[usize::from(*id)]
+                            )?;
+                            row_idx += 1;
+                        }
+                        OutputCell::OverlapMarker {
+                            nb_rows: 1,
+                            pattern_id,
+                        } => {
+                            write!(
+                                output,
+                                "\n\t; {} continues on next row.",
+                                labels.name(*pattern_id)
+                            )?;
+                        }
+                        OutputCell::OverlapMarker {
+                            nb_rows,
+                            pattern_id,
+                        } => {
+                            write!(
+                                output,
+                                "\n\t; {} continues on next {nb_rows} rows.",
+                                labels.name(*pattern_id)
+                            )?;
+                        }
+                    }
+                }
+                output!();
+                output!();
+            }
+
+            Block::CatalogTable(kind, table_idx) => {
+                output!("\tds align[8]");
+                if table_idx == 0 {
+                    output!("{}", labels.shared_definition(kind.label_name()));
+                }
+                write!(output, "\tdb ")?;
+                for cell in &export.catalog(kind).cells {
+                    let byte = match table_idx {
+                        0 => cell.first_byte(),
+                        1 => cell.second_byte(),
+                        2 => cell.third_byte(),
+                        _ => unreachable!(),
+                    };
+                    write!(output, "${byte:02x},")?;
+                }
+                output!();
+            }
+
+            Block::Asserts => {
+                output!();
+                output!("assert LAST_NOTE == {LAST_NOTE}, \"LAST_NOTE == {{LAST_NOTE}}\"");
+                output!("assert PATTERN_LENGTH == {PATTERN_LENGTH}, \"PATTERN_LENGTH == {{PATTERN_LENGTH}}\"");
+                output!();
+            }
+
+            Block::DutyInstrs(song_idx) => {
+                let SongResults {
+                    duty_instr_usage, ..
+                } = &results.songs[song_idx];
+                output!("{}", labels.block(song_idx, *block, "dutyInstrs"));
+                for id in duty_instr_usage.iter() {
+                    let instr = &songs[song_idx].instruments.duty[usize::from(id)];
+                    let id = id + 1;
+                    let &InstrumentKind::Square {
+                        initial_volume,
+                        envelope_dir,
+                        envelope_pace,
+                        sweep_time,
+                        sweep_dir,
+                        sweep_shift,
+                        duty,
+                    } = &instr.kind
+                    else {
+                        panic!("Non-duty instrument in duty instr bank!?");
+                    };
+
+                    output!(":; Duty instrument {}: {}", id, instr.name);
+                    output!(
+                        "\tdb {} << 4 | {} | {} ; Sweep (NR10)",
+                        sweep_time,
+                        sweep_dir,
+                        sweep_shift,
+                    );
+                    output!(
+                        "\tdb {} | {} ; Duty & length (NRx1)",
+                        duty,
+                        decode_len(instr),
+                    );
+                    output!(
+                        "\tdb {} ; Volume & envelope (NRx2)",
+                        NRx2 {
+                            initial_volume,
+                            envelope_dir,
+                            envelope_pace
+                        },
+                    );
+                    output!(
+                        "\tdw {} ; Subpattern pointer",
+                        (SubpatternPtr::new(
+                            &instr.subpattern,
+                            labels.pattern(
+                                song_idx,
+                                (song_idx, PatternId::Subpattern(InstrKind::Duty, id.into()))
+                            )
+                        )),
+                    );
+                    output!(
+                        "\tdb $80 | {} << 6 ; Retrigger bit, and length enable (NRx4)",
+                        instr.length.is_some() as u8,
+                    );
+                    output!(
+                        "assert DUTY_INSTR_SIZE == {size} && @ - :- == {size}",
+                        size = instr.kind.data_size()
+                    );
+                }
+                output!();
+            }
+
+            Block::WaveInstrs(song_idx) => {
+                let SongResults {
+                    wave_instr_usage,
+                    wave_usage,
+                    ..
+                } = &results.songs[song_idx];
+                output!("{}", labels.block(song_idx, *block, "waveInstrs"));
+                for id in wave_instr_usage.iter() {
+                    let instr = &songs[song_idx].instruments.wave[usize::from(id)];
+                    let id = id + 1;
+                    let &InstrumentKind::Wave {
+                        output_level,
+                        wave_id: waveform,
+                    } = &instr.kind
+                    else {
+                        panic!("Non-wave instrument in wave instr bank!?");
+                    };
+
+                    output!(":; Wave instrument {}: {}", id, instr.name);
+                    output!("\tdb {} ; Length (NR31)", decode_len(instr),);
+                    output!("\tdb {output_level} ; Output level (NR32)");
+                    output!(
+                        "\tdw {} ; Subpattern pointer",
+                        (SubpatternPtr::new(
+                            &instr.subpattern,
+                            labels.pattern(
+                                song_idx,
+                                (song_idx, PatternId::Subpattern(InstrKind::Wave, id.into()))
+                            )
+                        )),
+                    );
+                    output!(
+                        "\tdb $80 | {} << 6 ; Retrigger bit, and length enable (NRx4)",
+                        instr.length.is_some() as u8,
+                    );
+                    output!(
+                        "\tdb {} << 4 ; Wave ID",
+                        wave_usage.get_remapped_id(waveform.into())
+                    );
+                    output!(
+                        "assert WAVE_INSTR_SIZE == {size} && @ - :- == {size}",
+                        size = instr.kind.data_size()
+                    );
+                }
+                output!();
+            }
+
+            Block::NoiseInstrs(song_idx) => {
+                let SongResults {
+                    noise_instr_usage, ..
+                } = &results.songs[song_idx];
+                output!("{}", labels.block(song_idx, *block, "noiseInstrs"));
+                for id in noise_instr_usage.iter() {
+                    let instr = &songs[song_idx].instruments.noise[usize::from(id)];
+                    let id = id + 1;
+                    let &InstrumentKind::Noise {
+                        initial_volume,
+                        envelope_dir,
+                        envelope_pace,
+                        lfsr_width,
+                    } = &instr.kind
+                    else {
+                        panic!("Non-noise instrument in noise instr bank!?");
+                    };
+
+                    output!(":; Noise instrument {}: {}", id, instr.name);
+                    output!(
+                        "\tdb {} ; Volume & envelope (NR42)",
+                        NRx2 {
+                            initial_volume,
+                            envelope_dir,
+                            envelope_pace
+                        },
+                    );
+                    output!(
+                        "\tdw {} ; Subpattern pointer",
+                        (SubpatternPtr::new(
+                            &instr.subpattern,
+                            labels.pattern(
+                                song_idx,
+                                (song_idx, PatternId::Subpattern(InstrKind::Noise, id.into()))
+                            )
+                        )),
+                    );
+                    output!(
+                        "\tdb {} | {} << 6 | {} ; LFSR width (NR43), length enable (NR44), and length (NR41)",
+                        lfsr_width,
+                        instr.length.is_some() as u8,
+                        decode_len(instr),
+                    );
+                    output!(
+                        "assert NOISE_INSTR_SIZE == {size} && @ - :- == {size}",
+                        size = instr.kind.data_size()
+                    );
+                }
+                output!();
+            }
+
+            Block::Waves(song_idx) => {
+                output!("{}", labels.block(song_idx, *block, "waves"));
+                for id in results.songs[song_idx].wave_usage.iter() {
+                    write!(output, "\tdb ")?;
+                    for byte in &songs[song_idx].waves[usize::from(id)] {
+                        write!(output, "${byte:02x},")?;
+                    }
+                    output!(" ; Originally #{id}");
+                }
+                output!();
+            }
+
+            Block::SharedSection => {
+                let (kind, name) = options
+                    .shared_section
+                    .as_ref()
+                    .expect("The shared data only gets its own section if one was given");
+                output!("SECTION \"{name}\", {kind}");
+                // The row pool starts with a blank line already.
+            }
+
+            Block::Routine => {
+                output!("{}", labels.routine_definition());
+                if options.routine_skeleton {
+                    routine_skeleton(&event_constants, output)?;
+                }
+                if options.routines {
+                    routine_dispatcher(&songs[0].routines, &descriptors[0], output)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A constant for one of the arguments that the routine may be called with.
+#[derive(Debug)]
+struct EventConstant<'a> {
+    name: String,
+    arg: u8,
+    /// Where the routine is called with that argument, and in which song.
+    uses: Vec<(usize, &'a Location)>,
+}
+
+/// Sorted by argument; songs that use the same argument share its constant if it's been given a
+/// name, but each get their own otherwise.
+fn event_constants<'a>(export: &'a Export, options: &AsmOptions) -> Vec<EventConstant<'a>> {
+    let mut constants: Vec<EventConstant> = vec![];
+    for (song_idx, song_results) in export.results.songs.iter().enumerate() {
+        for (&arg, locations) in &song_results.routine_calls {
+            let name = options
+                .event_names
+                .get(&arg)
+                .cloned()
+                .unwrap_or_else(|| format!("{}_EVENT_{arg:02X}", export.descriptors[song_idx]));
+            let uses = locations.iter().map(|location| (song_idx, location));
+            match constants.iter_mut().find(|constant| constant.name == name) {
+                Some(constant) => constant.uses.extend(uses),
+                None => constants.push(EventConstant {
+                    name,
+                    arg,
+                    uses: uses.collect(),
+                }),
+            }
+        }
+    }
+    constants.sort_by(|a, b| (a.arg, &a.name).cmp(&(b.arg, &b.name)));
+    constants
+}
+
+/// Emits, in comments, a routine that jumps to a different label for each argument it may be
+/// called with, for the user to copy and fill in.
+fn routine_skeleton(constants: &[EventConstant], output: &mut dyn Write) -> std::io::Result<()> {
+    macro_rules! output {
+        ($($arg:tt)*) => {
+            writeln!(output, $($arg)*)?
+        };
+    }
+    let Some(max_arg) = constants.last().map(|constant| constant.arg) else {
+        output!("; This routine is never called, so it can be a single `ret`.");
+        return Ok(());
+    };
+    let names = |arg: u8| {
+        let names: Vec<_> = constants
+            .iter()
+            .filter(|constant| constant.arg == arg)
+            .map(|constant| constant.name.as_str())
+            .collect();
+        names.join(", ")
+    };
+
+    output!("; Skeleton of a routine that jumps to a different label for each argument it may be called with:");
+    output!(";\tld l, b");
+    output!(";\tld h, 0");
+    output!(";\tadd hl, hl");
+    output!(";\tld a, l");
+    output!(";\tadd a, LOW(.events)");
+    output!(";\tld l, a");
+    output!(";\tld a, h");
+    output!(";\tadc a, HIGH(.events)");
+    output!(";\tld h, a");
+    output!(";\tld a, [hli]");
+    output!(";\tld h, [hl]");
+    output!(";\tld l, a");
+    output!(";\tjp hl");
+    output!(";.events");
+    let mut has_gaps = false;
+    for arg in 0..=max_arg {
+        if constants.iter().any(|constant| constant.arg == arg) {
+            output!(";\tdw .event{arg:02X} ; {}", names(arg));
+        } else {
+            output!(";\tdw .noEvent");
+            has_gaps = true;
+        }
+    }
+    if has_gaps {
+        output!(";.noEvent");
+        output!(";\tret");
+    }
+    let mut prev_arg = None;
+    for constant in constants {
+        if prev_arg.replace(constant.arg) != Some(constant.arg) {
+            output!(";");
+            output!(";.event{:02X} ; {}", constant.arg, names(constant.arg));
+            output!(";\tret");
+        }
+    }
+    Ok(())
+}
+
+/// Emits code that jumps to one of the routines depending on the low nibble of the `6xy` effect's
+/// argument, followed by the routines themselves, each of which gets a `ret` appended.
+///
+/// Each routine gets its own global label, so that their local labels can't clash; the registers
+/// that fortISSimO passes to the routine are left intact, except for `hl`, which points to the
+/// routine being called instead.
+fn routine_dispatcher(
+    routines: &RoutineBank,
+    descriptor: &str,
+    output: &mut dyn Write,
+) -> std::io::Result<()> {
+    macro_rules! output {
+        ($($arg:tt)*) => {
+            writeln!(output, $($arg)*)?
+        };
+    }
+    let is_empty = |routine: &Routine| routine.trim().is_empty();
+
+    output!("\tld a, b");
+    output!("\tand $0F");
+    output!("\tadd a, a");
+    output!("\tadd a, LOW(.routines)");
+    output!("\tld l, a");
+    output!("\tadc a, HIGH(.routines)");
+    output!("\tsub l");
+    output!("\tld h, a");
+    output!("\tld a, [hli]");
+    output!("\tld h, [hl]");
+    output!("\tld l, a");
+    output!("\tjp hl");
+    output!(".routines");
+    for (i, routine) in routines.iter().enumerate() {
+        if is_empty(routine) {
+            output!("\tdw .noRoutine");
+        } else {
+            output!("\tdw {descriptor}_routine{i}");
+        }
+    }
+    if routines.iter().any(is_empty) {
+        output!(".noRoutine");
+        output!("\tret");
+    }
+
+    for (i, routine) in routines.iter().enumerate() {
+        if !is_empty(routine) {
+            output!();
+            output!("{descriptor}_routine{i}:");
+            output!("{}", routine.trim_end());
+            output!("\tret");
+        }
+    }
+    Ok(())
+}
+
+fn decode_len(instr: &Instrument) -> u8 {
+    instr.length.unwrap_or(0)
+}
+
+/// Computes how labels must be written, depending on which scope they are referenced from.
+///
+/// Local labels belong to the scope of the last song header emitted before them; this matters when
+/// exporting several songs, since the data they share is defined in only one of their scopes.
+/// A `SECTION` directive ends the current scope, so labels in the shared data's section (if any)
+/// are defined with their full name, as if they were in the first song's scope.
+#[derive(Debug, Clone, Copy)]
+struct Labels<'a> {
+    descriptors: &'a [String],
+    blocks: &'a [Block],
+    batch: bool,
+    /// The song in whose scope the row pools and cell catalogs are defined, if any.
+    shared_scope: Option<usize>,
+    /// The song in whose scope the routine is defined, if any.
+    routine_scope: Option<usize>,
+}
+
+impl<'a> Labels<'a> {
+    fn new(export: &'a Export<'a>) -> Self {
+        Self {
+            descriptors: export.descriptors,
+            blocks: &export.blocks,
+            batch: export.songs.len() > 1,
+            shared_scope: scope_of(&export.blocks, |block| matches!(block, Block::RowPool(_))),
+            routine_scope: scope_of(&export.blocks, |block| matches!(block, Block::Routine)),
+        }
+    }
+
+    /// Labels defined outside of any song's scope are given the first song's.
+    fn owner(scope: Option<usize>) -> usize {
+        scope.unwrap_or(0)
+    }
+
+    fn scope(&self, from_song: usize, defined_in: usize) -> Option<&'a str> {
+        (from_song != defined_in).then(|| self.descriptors[defined_in].as_str())
+    }
+
+    /// How to write the definition of a label owned by a song, in a given scope.
+    fn defined_in<T>(&self, scope: Option<usize>, owner: usize, name: T) -> ScopedLabel<'a, T> {
+        ScopedLabel(
+            (scope != Some(owner)).then(|| self.descriptors[owner].as_str()),
+            name,
+        )
+    }
+
+    /// The label at the beginning of one of a song's blocks, which may have been moved out of the
+    /// song's scope to fill some padding; references to it from the song's header are unaffected.
+    fn block(&self, song_idx: usize, block: Block, name: &'a str) -> ScopedLabel<'a, &'a str> {
+        let scope = scope_of(self.blocks, |candidate| *candidate == block);
+        self.defined_in(scope, song_idx, name)
+    }
+
+    fn shared(&self, from_song: usize, name: &'a str) -> ScopedLabel<'a, &'a str> {
+        ScopedLabel(self.scope(from_song, Self::owner(self.shared_scope)), name)
+    }
+
+    fn shared_definition(&self, name: &'a str) -> ScopedLabel<'a, &'a str> {
+        self.defined_in(self.shared_scope, Self::owner(self.shared_scope), name)
+    }
+
+    fn routine(&self, from_song: usize) -> ScopedLabel<'a, &'a str> {
+        ScopedLabel(
+            self.scope(from_song, Self::owner(self.routine_scope)),
+            "routine",
+        )
+    }
+
+    fn routine_definition(&self) -> ScopedLabel<'a, &'a str> {
+        self.defined_in(
+            self.routine_scope,
+            Self::owner(self.routine_scope),
+            "routine",
+        )
+    }
+
+    fn name(&self, (song_idx, id): PatternKey) -> PatternLabel<'a> {
+        PatternLabel {
+            song_prefix: self.batch.then(|| self.descriptors[song_idx].as_str()),
+            id,
+        }
+    }
+
+    fn pattern(&self, from_song: usize, key: PatternKey) -> ScopedLabel<'a, PatternLabel<'a>> {
+        ScopedLabel(
+            self.scope(from_song, Self::owner(self.shared_scope)),
+            self.name(key),
+        )
+    }
+
+    fn definition(&self, key: PatternKey) -> ScopedLabel<'a, PatternLabel<'a>> {
+        self.defined_in(
+            self.shared_scope,
+            Self::owner(self.shared_scope),
+            self.name(key),
+        )
+    }
+}
+
+/// The song in whose scope the first block matching `target` is, if any.
+fn scope_of(blocks: &[Block], target: impl Fn(&Block) -> bool) -> Option<usize> {
+    let mut scope = None;
+    for block in blocks {
+        match block {
+            Block::Header(song_idx) => scope = Some(*song_idx),
+            Block::SharedSection => scope = None,
+            _ => {}
+        }
+        if target(block) {
+            break;
+        }
+    }
+    scope
+}
+
+/// A local label, qualified with its scope if it's being referenced from outside of it.
+#[derive(Debug, Clone, Copy)]
+struct ScopedLabel<'a, T>(Option<&'a str>, T);
+
+impl<T: Display> Display for ScopedLabel<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(scope) = self.0 {
+            f.write_str(scope)?;
+        }
+        f.write_str(".")?;
+        self.1.fmt(f)
+    }
+}
+
+impl Display for DutyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DutyType::Percent12_5 => write!(f, "%00 << 6"),
+            DutyType::Percent25 => write!(f, "%01 << 6"),
+            DutyType::Percent50 => write!(f, "%10 << 6"),
+            DutyType::Percent75 => write!(f, "%11 << 6"),
+        }
+    }
+}
+
+impl Display for SweepDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} << 3",
+            match self {
+                SweepDirection::Down => '1',
+                SweepDirection::Up => '0',
+            }
+        )
+    }
+}
+
+impl Display for WaveOutputLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "%{} << 5",
+            match self {
+                WaveOutputLevel::Mute => "00",
+                WaveOutputLevel::Full => "01",
+                WaveOutputLevel::Half => "10",
+                WaveOutputLevel::Quarter => "11",
+            }
+        )
+    }
+}
+
+impl Display for LfsrWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} << 7",
+            match self {
+                LfsrWidth::Fifteen => '0',
+                LfsrWidth::Seven => '1',
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NRx2 {
+    initial_volume: u8,
+    envelope_dir: EnvelopeDirection,
+    envelope_pace: u8,
+}
+
+impl Display for NRx2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} << 4 | {} << 3 | {}",
+            self.initial_volume,
+            match self.envelope_dir {
+                EnvelopeDirection::Down => '0',
+                EnvelopeDirection::Up => '1',
+            },
+            self.envelope_pace
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SubpatternPtr<T>(Option<T>);
+
+impl<T> SubpatternPtr<T> {
+    fn new(subpattern: &Option<Subpattern>, label: T) -> Self {
+        Self(subpattern.as_ref().map(|_| label))
+    }
+}
+
+impl<T: Display> Display for SubpatternPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(label) => label.fmt(f),
+            None => write!(f, "0"),
+        }
+    }
+}
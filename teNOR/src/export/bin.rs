@@ -0,0 +1,311 @@
+use std::{collections::HashMap, io::Write};
+
+use clap::{crate_name, crate_version};
+
+use crate::optimise::{InstrKind, OutputCell, PatternId, PatternKey};
+
+use super::{BinOptions, Block, CatalogKind, Export, ExportError, RawInstr, Sizes, SongSizes};
+
+pub(super) fn export(
+    export: &Export,
+    options: &BinOptions,
+    output: &mut dyn Write,
+) -> Result<(), ExportError> {
+    let image = assemble(export, options.base_address);
+    output.write_all(&image.link()?)?;
+    Ok(())
+}
+
+/// Lists where each descriptor (and the routine) ended up.
+pub(super) fn export_symbols(
+    export: &Export,
+    options: &BinOptions,
+    output: &mut dyn Write,
+) -> Result<(), ExportError> {
+    let image = assemble(export, options.base_address);
+    image.link()?; // Only report addresses if they are valid.
+
+    writeln!(
+        output,
+        "; Generated with {} version {}",
+        crate_name!(),
+        crate_version!(),
+    )?;
+    writeln!(output, "; Label, offset into the binary, address")?;
+    let names = export.descriptors.iter().map(String::as_str).enumerate();
+    for (name, label) in names
+        .map(|(song_idx, name)| (name, Label::Descriptor(song_idx)))
+        .chain(std::iter::once(("routine", Label::Routine)))
+    {
+        let offset = image.labels[&label];
+        writeln!(
+            output,
+            "{name} ${offset:04x} ${:04x}",
+            usize::from(options.base_address) + offset,
+        )?;
+    }
+    Ok(())
+}
+
+/// Measures each part of the data, as it would be laid out when loaded at `base_address`.
+pub(super) fn sizes(export: &Export, base_address: u16) -> Sizes {
+    let image = assemble(export, base_address);
+    let mut sizes = Sizes {
+        songs: vec![SongSizes::default(); export.songs.len()],
+        padding: image.padding,
+        reclaimed_padding: export.reclaimed_padding,
+        total: image.bytes.len(),
+        ..Default::default()
+    };
+    for &(block, size) in &image.block_sizes {
+        *match block {
+            Block::Header(song_idx) => &mut sizes.songs[song_idx].header,
+            Block::OrderColumns(song_idx) => &mut sizes.songs[song_idx].order_columns,
+            Block::RowPool(CatalogKind::Main) => &mut sizes.main_row_pool,
+            Block::RowPool(CatalogKind::Subpattern) => &mut sizes.subpat_row_pool,
+            Block::CatalogTable(CatalogKind::Main, _) => &mut sizes.main_catalog,
+            Block::CatalogTable(CatalogKind::Subpattern, _) => &mut sizes.subpat_catalog,
+            Block::DutyInstrs(song_idx)
+            | Block::WaveInstrs(song_idx)
+            | Block::NoiseInstrs(song_idx) => &mut sizes.songs[song_idx].instruments,
+            Block::Waves(song_idx) => &mut sizes.songs[song_idx].waves,
+            Block::Asserts | Block::SharedSection | Block::Routine => continue,
+        } += size;
+    }
+    sizes
+}
+
+/// How many bytes each block takes up, not counting padding.
+pub(super) fn block_sizes(export: &Export, base_address: u16) -> HashMap<Block, usize> {
+    assemble(export, base_address)
+        .block_sizes
+        .into_iter()
+        .collect()
+}
+
+fn assemble(export: &Export, base_address: u16) -> Image {
+    let Export {
+        songs,
+        results,
+        blocks,
+        ..
+    } = export;
+    let mut image = Image::new(base_address);
+
+    for block in blocks {
+        let (start, padding_before) = (image.bytes.len(), image.padding);
+        match *block {
+            Block::Header(song_idx) => {
+                let song = &songs[song_idx];
+                image.define(Label::Descriptor(song_idx));
+                image.byte(song.ticks_per_row);
+                image.byte(((song.order_matrix.len() - 1) * 2) as u8);
+                image.word(Label::DutyInstrs(song_idx));
+                image.word(Label::WaveInstrs(song_idx));
+                image.word(Label::NoiseInstrs(song_idx));
+                image.word(Label::Routine);
+                image.word(Label::Waves(song_idx));
+                image.high(Label::Catalog(CatalogKind::Main));
+                image.high(Label::Catalog(CatalogKind::Subpattern));
+            }
+
+            Block::OrderColumns(song_idx) => {
+                for i in 0..4 {
+                    let kind = InstrKind::from_channel_id(i);
+                    for id in &songs[song_idx].order_matrix {
+                        match export.order_target((song_idx, PatternId::Pattern(kind, id[i]))) {
+                            Some(key) => image.word(Label::Pattern(key)),
+                            // This order row is never played.
+                            None => image.bytes(&[0, 0]),
+                        }
+                    }
+                }
+            }
+
+            Block::RowPool(kind) => {
+                let catalog = export.catalog(kind);
+                let row_pool = match kind {
+                    CatalogKind::Main => &results.main_row_pool,
+                    CatalogKind::Subpattern => &results.subpat_row_pool,
+                };
+                for entry in row_pool {
+                    match entry {
+                        OutputCell::Label(whose) => image.define(Label::Pattern(*whose)),
+                        OutputCell::Cell(id) => {
+                            image.byte(catalog.reverse_lookup[usize::from(*id)])
+                        }
+                        OutputCell::OverlapMarker { .. } => {}
+                    }
+                }
+            }
+
+            Block::CatalogTable(kind, table_idx) => {
+                image.align();
+                if table_idx == 0 {
+                    image.define(Label::Catalog(kind));
+                }
+                for cell in &export.catalog(kind).cells {
+                    image.byte(match table_idx {
+                        0 => cell.first_byte(),
+                        1 => cell.second_byte(),
+                        2 => cell.third_byte(),
+                        _ => unreachable!(),
+                    });
+                }
+            }
+
+            // These only make sense when assembling against the driver's include file.
+            Block::Asserts => {}
+            // Only RGBASM output can be split across sections.
+            Block::SharedSection => {}
+
+            Block::DutyInstrs(song_idx) => image.instrs(export, song_idx, InstrKind::Duty),
+            Block::WaveInstrs(song_idx) => image.instrs(export, song_idx, InstrKind::Wave),
+            Block::NoiseInstrs(song_idx) => image.instrs(export, song_idx, InstrKind::Noise),
+
+            Block::Waves(song_idx) => {
+                image.define(Label::Waves(song_idx));
+                for id in results.songs[song_idx].wave_usage.iter() {
+                    image.bytes(&songs[song_idx].waves[usize::from(id)]);
+                }
+            }
+
+            Block::Routine => image.define(Label::Routine),
+        }
+        let size = image.bytes.len() - start - (image.padding - padding_before);
+        image.block_sizes.push((*block, size));
+    }
+
+    image
+}
+
+/// Everything that can be pointed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Label {
+    Descriptor(usize),
+    DutyInstrs(usize),
+    WaveInstrs(usize),
+    NoiseInstrs(usize),
+    Waves(usize),
+    Routine,
+    Catalog(CatalogKind),
+    Pattern(PatternKey),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    /// A little-endian pointer.
+    Word(Label),
+    /// The high byte of a pointer.
+    High(Label),
+}
+
+/// A bare-bones assembler: since labels can be referenced before being defined, pointers are only
+/// written once everything has been laid out.
+#[derive(Debug)]
+struct Image {
+    base_address: u16,
+    bytes: Vec<u8>,
+    /// Maps labels to their offset into `bytes`.
+    labels: HashMap<Label, usize>,
+    fixups: Vec<(usize, Fixup)>,
+    /// How many bytes were inserted by `align`.
+    padding: usize,
+    /// How many bytes each block takes up, not counting padding.
+    block_sizes: Vec<(Block, usize)>,
+}
+
+impl Image {
+    fn new(base_address: u16) -> Self {
+        Self {
+            base_address,
+            bytes: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            padding: 0,
+            block_sizes: Vec::new(),
+        }
+    }
+
+    fn define(&mut self, label: Label) {
+        let prev = self.labels.insert(label, self.bytes.len());
+        debug_assert!(prev.is_none(), "{label:?} defined twice");
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn word(&mut self, label: Label) {
+        self.fixups.push((self.bytes.len(), Fixup::Word(label)));
+        self.bytes.extend_from_slice(&[0, 0]);
+    }
+
+    fn high(&mut self, label: Label) {
+        self.fixups.push((self.bytes.len(), Fixup::High(label)));
+        self.bytes.push(0);
+    }
+
+    fn instrs(&mut self, export: &Export, song_idx: usize, kind: InstrKind) {
+        self.define(match kind {
+            InstrKind::Duty => Label::DutyInstrs(song_idx),
+            InstrKind::Wave => Label::WaveInstrs(song_idx),
+            InstrKind::Noise => Label::NoiseInstrs(song_idx),
+        });
+        for (id, instr) in export.used_instrs(song_idx, kind) {
+            let raw = RawInstr::new(instr, &export.results.songs[song_idx].wave_usage);
+            self.bytes(&raw.head);
+            match instr.subpattern {
+                Some(_) => self.word(Label::Pattern((
+                    song_idx,
+                    PatternId::Subpattern(kind, (id + 1).into()),
+                ))),
+                None => self.bytes(&[0, 0]),
+            }
+            self.bytes(&raw.tail);
+        }
+    }
+
+    /// Equivalent of `ds align[8]`.
+    fn align(&mut self) {
+        while (usize::from(self.base_address) + self.bytes.len()) % 256 != 0 {
+            self.bytes.push(0);
+            self.padding += 1;
+        }
+    }
+
+    fn address_of(&self, label: Label) -> u16 {
+        let offset = *self
+            .labels
+            .get(&label)
+            .unwrap_or_else(|| panic!("{label:?} was never defined"));
+        // `link` checks that the whole image fits in the address space.
+        (usize::from(self.base_address) + offset) as u16
+    }
+
+    fn link(&self) -> Result<Vec<u8>, ExportError> {
+        let end = usize::from(self.base_address) + self.bytes.len();
+        if end >= 0x1_0000 {
+            return Err(ExportError::AddressOverflow {
+                size: self.bytes.len(),
+                base_address: self.base_address,
+            });
+        }
+
+        let mut bytes = self.bytes.clone();
+        for &(offset, fixup) in &self.fixups {
+            match fixup {
+                Fixup::Word(label) => {
+                    bytes[offset..offset + 2]
+                        .copy_from_slice(&self.address_of(label).to_le_bytes());
+                }
+                Fixup::High(label) => bytes[offset] = (self.address_of(label) >> 8) as u8,
+            }
+        }
+        Ok(bytes)
+    }
+}
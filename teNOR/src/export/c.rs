@@ -0,0 +1,484 @@
+use std::{collections::HashMap, fmt::Write as _, io::Write};
+
+use clap::{crate_name, crate_version};
+
+use crate::{
+    optimise::{InstrKind, OutputCell, PatternId, PatternKey},
+    song::{EffectId, Song},
+};
+
+use super::{Block, COptions, CatalogKind, Export, ExportError, RawInstr};
+
+pub(super) fn export(
+    export: &Export,
+    options: &COptions,
+    output: &mut dyn Write,
+) -> Result<(), ExportError> {
+    let Export {
+        songs,
+        descriptors,
+        results,
+        blocks,
+        ..
+    } = export;
+    if options.catalog_address % 256 != 0 {
+        return Err(ExportError::BadCatalogAddress(options.catalog_address));
+    }
+    let catalog_address = |kind| {
+        usize::from(options.catalog_address)
+            + match kind {
+                CatalogKind::Main => 0,
+                CatalogKind::Subpattern => 0x300,
+            }
+    };
+    // `format!` into a `String` cannot fail, so this spares a lot of `unwrap`s.
+    let mut source = String::new();
+    macro_rules! output {
+        ($($arg:tt)*) => {
+            writeln!(source, $($arg)*).unwrap()
+        };
+    }
+
+    output!("{}", preamble(export));
+    match &options.header_name {
+        Some(header_name) => output!("#include \"{header_name}\""),
+        None => {
+            output!("#include <stdint.h>");
+            output!();
+            source.push_str(&type_definitions(export));
+        }
+    }
+    output!();
+
+    // Where each pattern ended up in its row pool.
+    let mut pattern_offsets = HashMap::new();
+    let pattern_ptr = |pattern_offsets: &HashMap<_, _>, key: PatternKey| {
+        let pool = match key.1 {
+            PatternId::Pattern(..) => "mainRowPool",
+            PatternId::Subpattern(..) => "subpatRowPool",
+        };
+        format!("{pool} + {}", pattern_offsets[&key])
+    };
+
+    // Headers point to everything else, so they must come last.
+    for block in blocks {
+        match *block {
+            Block::Header(_) | Block::OrderColumns(_) | Block::SharedSection | Block::Routine => {}
+
+            Block::RowPool(kind) => {
+                let catalog = export.catalog(kind);
+                let row_pool = match kind {
+                    CatalogKind::Main => &results.main_row_pool,
+                    CatalogKind::Subpattern => &results.subpat_row_pool,
+                };
+                let name = match kind {
+                    CatalogKind::Main => "mainRowPool",
+                    CatalogKind::Subpattern => "subpatRowPool",
+                };
+                if !row_pool
+                    .iter()
+                    .any(|entry| matches!(entry, OutputCell::Cell(_)))
+                {
+                    output!("// No {name}, since it would be empty.");
+                    output!();
+                    continue;
+                }
+
+                write!(source, "static const uint8_t {name}[] = {{").unwrap();
+                let mut offset = 0;
+                let mut row_idx = 0;
+                for entry in row_pool {
+                    match entry {
+                        OutputCell::Label(whose) => {
+                            write!(source, "\n\t// {}", export.pattern_name(*whose)).unwrap();
+                            pattern_offsets.insert(*whose, offset);
+                            row_idx = 0;
+                        }
+                        OutputCell::Cell(id) => {
+                            write!(
+                                source,
+                                "{}{:3},",
+                                if row_idx == 0 { "\n\t" } else { " " },
+                                catalog.reverse_lookup[usize::from(*id)]
+                            )
+                            .unwrap();
+                            offset += 1;
+                            row_idx += 1;
+                        }
+                        OutputCell::OverlapMarker {
+                            nb_rows: 1,
+                            pattern_id,
+                        } => write!(
+                            source,
+                            "\n\t// {} continues on next row.",
+                            export.pattern_name(*pattern_id)
+                        )
+                        .unwrap(),
+                        OutputCell::OverlapMarker {
+                            nb_rows,
+                            pattern_id,
+                        } => write!(
+                            source,
+                            "\n\t// {} continues on next {nb_rows} rows.",
+                            export.pattern_name(*pattern_id)
+                        )
+                        .unwrap(),
+                    }
+                }
+                output!();
+                output!("}};");
+                output!();
+            }
+
+            Block::CatalogTable(kind, table_idx) => {
+                let cells = &export.catalog(kind).cells;
+                let name = kind.label_name();
+                if cells.is_empty() {
+                    if table_idx == 0 {
+                        output!("// No {name}, since it would be empty.");
+                        output!();
+                    }
+                    continue;
+                }
+
+                if table_idx == 0 {
+                    let address = catalog_address(kind);
+                    if address + 512 + cells.len() > 0x1_0000 {
+                        return Err(ExportError::BadCatalogAddress(options.catalog_address));
+                    }
+                    output!("// SDCC cannot align data, so the catalog is placed at a fixed address instead.");
+                    output!("static const uint8_t __at(0x{address:04x}) {name}[] = {{");
+                }
+                // The tables are 256 bytes apart, but there is no need to spell out the padding.
+                write!(source, "\t[{}] =", table_idx * 256).unwrap();
+                for cell in cells {
+                    let byte = match table_idx {
+                        0 => cell.first_byte(),
+                        1 => cell.second_byte(),
+                        2 => cell.third_byte(),
+                        _ => unreachable!(),
+                    };
+                    write!(source, " 0x{byte:02x},").unwrap();
+                }
+                output!();
+                if table_idx == 2 {
+                    output!("}};");
+                    output!();
+                }
+            }
+
+            // The instruments' sizes are checked when they are declared; and the other constants
+            // are not available from C.
+            Block::Asserts => {}
+
+            Block::DutyInstrs(song_idx) => instrs(
+                &mut source,
+                export,
+                &pattern_offsets,
+                song_idx,
+                InstrKind::Duty,
+            ),
+            Block::WaveInstrs(song_idx) => instrs(
+                &mut source,
+                export,
+                &pattern_offsets,
+                song_idx,
+                InstrKind::Wave,
+            ),
+            Block::NoiseInstrs(song_idx) => instrs(
+                &mut source,
+                export,
+                &pattern_offsets,
+                song_idx,
+                InstrKind::Noise,
+            ),
+
+            Block::Waves(song_idx) => {
+                let wave_usage = &results.songs[song_idx].wave_usage;
+                if wave_usage.iter().next().is_none() {
+                    continue;
+                }
+                output!(
+                    "static const uint8_t {}_waves[][16] = {{",
+                    descriptors[song_idx]
+                );
+                for id in wave_usage.iter() {
+                    write!(source, "\t{{").unwrap();
+                    for byte in &songs[song_idx].waves[usize::from(id)] {
+                        write!(source, "0x{byte:02x},").unwrap();
+                    }
+                    output!("}}, // Originally #{id}");
+                }
+                output!("}};");
+                output!();
+            }
+        }
+    }
+
+    for (song_idx, song) in songs.iter().enumerate() {
+        let descriptor = &descriptors[song_idx];
+        let song_results = &results.songs[song_idx];
+        let or_null = |is_present: bool, name: &str| {
+            if is_present {
+                format!("{descriptor}_{name}")
+            } else {
+                "0".into()
+            }
+        };
+
+        output!("const struct {descriptor}_header {descriptor} = {{");
+        output!("\t{}, // Tempo (ticks/row)", song.ticks_per_row);
+        output!(
+            "\t({} - 1) * 2, // Max index into order \"columns\"",
+            song.order_matrix.len(),
+        );
+        output!(
+            "\t{}, {}, {},",
+            or_null(
+                song_results.duty_instr_usage.iter().next().is_some(),
+                "dutyInstrs"
+            ),
+            or_null(
+                song_results.wave_instr_usage.iter().next().is_some(),
+                "waveInstrs"
+            ),
+            or_null(
+                song_results.noise_instr_usage.iter().next().is_some(),
+                "noiseInstrs"
+            ),
+        );
+        output!("\t{},", or_null(uses_routine(song), "routine"));
+        output!(
+            "\t{},",
+            or_null(song_results.wave_usage.iter().next().is_some(), "waves")
+        );
+        output!(
+            "\t0x{:02x}, 0x{:02x}, // HIGH(mainCellCatalog), HIGH(subpatCellCatalog)",
+            catalog_address(CatalogKind::Main) >> 8,
+            catalog_address(CatalogKind::Subpattern) >> 8,
+        );
+        output!("\t{{");
+        for i in 0..4 {
+            let kind = InstrKind::from_channel_id(i);
+            write!(source, "\t\t{{").unwrap();
+            for id in &song.order_matrix {
+                match export.order_target((song_idx, PatternId::Pattern(kind, id[i]))) {
+                    Some(key) => write!(
+                        source,
+                        " {} /* {} */,",
+                        pattern_ptr(&pattern_offsets, key),
+                        export.pattern_name(key)
+                    )
+                    .unwrap(),
+                    // This order row is never played.
+                    None => write!(source, " 0,").unwrap(),
+                }
+            }
+            output!(" }}, // CH{}", i + 1);
+        }
+        output!("\t}},");
+        output!("}};");
+        output!();
+    }
+
+    output.write_all(source.as_bytes())?;
+    Ok(())
+}
+
+/// Declares the song descriptors (with the types that they are made of), and the routines that
+/// must be provided.
+pub(super) fn export_header(export: &Export, output: &mut dyn Write) -> Result<(), ExportError> {
+    let guard = format!("TENOR_{}_H", export.descriptors[0].to_ascii_uppercase());
+
+    writeln!(output, "{}", preamble(export))?;
+    writeln!(output, "#ifndef {guard}")?;
+    writeln!(output, "#define {guard}")?;
+    writeln!(output)?;
+    writeln!(output, "#include <stdint.h>")?;
+    writeln!(output, "#include \"fortISSimO.h\"")?;
+    writeln!(output)?;
+    write!(output, "{}", type_definitions(export))?;
+    for descriptor in export.descriptors {
+        writeln!(
+            output,
+            "// Pass `(struct hUGESong const *)&{descriptor}` to `hUGE_StartSong`."
+        )?;
+        writeln!(
+            output,
+            "extern const struct {descriptor}_header {descriptor};"
+        )?;
+    }
+    writeln!(output)?;
+    writeln!(output, "#endif")?;
+    Ok(())
+}
+
+/// The types that the song descriptors are made of, and the routines that they point to; these go
+/// in the header if there is one, so that the descriptors are declared the same way everywhere.
+fn type_definitions(export: &Export) -> String {
+    let mut types = String::new();
+    macro_rules! output {
+        ($($arg:tt)*) => {
+            writeln!(types, $($arg)*).unwrap()
+        };
+    }
+
+    output!("struct dutyInstr {{");
+    output!("\tuint8_t sweep; // NR10");
+    output!("\tuint8_t duty_length; // NRx1");
+    output!("\tuint8_t volume_envelope; // NRx2");
+    output!("\tuint8_t const * subpattern;");
+    output!("\tuint8_t control; // Retrigger bit, and length enable (NRx4)");
+    output!("}};");
+    output!("_Static_assert(sizeof(struct dutyInstr) == 6, \"DUTY_INSTR_SIZE == 6\");");
+    output!("struct waveInstr {{");
+    output!("\tuint8_t length; // NR31");
+    output!("\tuint8_t output_level; // NR32");
+    output!("\tuint8_t const * subpattern;");
+    output!("\tuint8_t control; // Retrigger bit, and length enable (NRx4)");
+    output!("\tuint8_t wave_id; // Shifted left by 4");
+    output!("}};");
+    output!("_Static_assert(sizeof(struct waveInstr) == 6, \"WAVE_INSTR_SIZE == 6\");");
+    output!("struct noiseInstr {{");
+    output!("\tuint8_t volume_envelope; // NR42");
+    output!("\tuint8_t const * subpattern;");
+    output!("\tuint8_t control; // LFSR width (NR43), length enable (NR44), and length (NR41)");
+    output!("}};");
+    output!("_Static_assert(sizeof(struct noiseInstr) == 4, \"NOISE_INSTR_SIZE == 4\");");
+    output!();
+
+    for (song, descriptor) in export.songs.iter().zip(export.descriptors) {
+        if uses_routine(song) {
+            output!("// This song uses the `6xx` effect, so a routine must be provided (in assembly, as `_{descriptor}_routine`).");
+            output!("void {descriptor}_routine(void);");
+        }
+        output!("struct {descriptor}_header {{");
+        output!("\tuint8_t ticks_per_row;");
+        output!("\tuint8_t max_order_idx;");
+        output!("\tstruct dutyInstr const * duty_instrs;");
+        output!("\tstruct waveInstr const * wave_instrs;");
+        output!("\tstruct noiseInstr const * noise_instrs;");
+        output!("\tvoid (*routine)(void);");
+        output!("\tuint8_t const (* waves)[16];");
+        output!("\tuint8_t main_catalog_high;");
+        output!("\tuint8_t subpat_catalog_high;");
+        output!(
+            "\tuint8_t const * order_columns[4][{}];",
+            song.order_matrix.len()
+        );
+        output!("}};");
+        output!();
+    }
+    types
+}
+
+/// The comment at the top of both generated files.
+fn preamble(export: &Export) -> String {
+    let Export {
+        songs,
+        source_names,
+        descriptors,
+        ..
+    } = export;
+    let mut preamble = String::new();
+    writeln!(preamble, "// {}", export.origin()).unwrap();
+    writeln!(
+        preamble,
+        "// Generated with {} version {}",
+        crate_name!(),
+        crate_version!(),
+    )
+    .unwrap();
+    for ((song, source_name), descriptor) in songs.iter().zip(*source_names).zip(*descriptors) {
+        if songs.len() > 1 {
+            writeln!(preamble, "//").unwrap();
+            writeln!(preamble, "// From {source_name}, as {descriptor}:").unwrap();
+        }
+        for (mut header, string) in [
+            ("Song:", &song.name),
+            ("Artist:", &song.artist),
+            ("Comment:", &song.comment),
+        ] {
+            let header_len = header.len();
+            for line in string.split('\n') {
+                writeln!(preamble, "// {header:<header_len$} {line}").unwrap();
+                header = "";
+            }
+        }
+        if let Some(divider) = song.timer_divider {
+            writeln!(
+                preamble,
+                "// Expected playback method: TMA = 0x{divider:02x}"
+            )
+            .unwrap();
+        } else {
+            writeln!(preamble, "// Expected playback method: VBlank").unwrap();
+        }
+    }
+    preamble
+}
+
+fn instrs(
+    source: &mut String,
+    export: &Export,
+    pattern_offsets: &HashMap<PatternKey, usize>,
+    song_idx: usize,
+    kind: InstrKind,
+) {
+    let mut instrs = export.used_instrs(song_idx, kind).peekable();
+    if instrs.peek().is_none() {
+        return;
+    }
+
+    writeln!(
+        source,
+        "static const struct {kind}Instr {}_{kind}Instrs[] = {{",
+        export.descriptors[song_idx]
+    )
+    .unwrap();
+    for (id, instr) in instrs {
+        let raw = RawInstr::new(instr, &export.results.songs[song_idx].wave_usage);
+        write!(source, "\t{{").unwrap();
+        for byte in &raw.head {
+            write!(source, " 0x{byte:02x},").unwrap();
+        }
+        match instr.subpattern {
+            Some(_) => {
+                let key = (song_idx, PatternId::Subpattern(kind, (id + 1).into()));
+                write!(source, " subpatRowPool + {},", pattern_offsets[&key]).unwrap();
+            }
+            None => write!(source, " 0,").unwrap(),
+        }
+        for byte in &raw.tail {
+            write!(source, " 0x{byte:02x},").unwrap();
+        }
+        writeln!(
+            source,
+            " }}, // {} instrument {}: {}",
+            match kind {
+                InstrKind::Duty => "Duty",
+                InstrKind::Wave => "Wave",
+                InstrKind::Noise => "Noise",
+            },
+            id + 1,
+            instr.name
+        )
+        .unwrap();
+    }
+    writeln!(source, "}};\n").unwrap();
+}
+
+/// Since routines must be provided by the user, only ask for one if the song may need it.
+fn uses_routine(song: &Song) -> bool {
+    let instruments = &song.instruments;
+    song.patterns
+        .iter()
+        .flatten()
+        .any(|cell| cell.effect_code == EffectId::CallRoutine)
+        || [&instruments.duty, &instruments.wave, &instruments.noise]
+            .into_iter()
+            .flatten()
+            .filter_map(|instr| instr.subpattern.as_ref())
+            .flatten()
+            .any(|cell| cell.effect_code == EffectId::CallRoutine)
+}
@@ -0,0 +1,935 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::Write,
+};
+
+use chrono::prelude::*;
+
+use crate::{
+    json,
+    optimise::{
+        Cell, CellCatalog, CompactedMapping, InstrKind, OptimResults, OptimStats, OutputCell,
+        PatternId, PatternKey,
+    },
+    playback::Timing,
+    song::{
+        DutyType, EnvelopeDirection, Instrument, InstrumentKind, LfsrWidth, Song, SweepDirection,
+        WaveOutputLevel,
+    },
+};
+
+mod asm;
+mod bin;
+mod c;
+mod packing;
+
+/// How many bytes a ROM bank can hold.
+const ROM_BANK_SIZE: usize = 0x4000;
+
+/// What to export, and how.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// The name of each song's header (hUGETracker calls this the "song descriptor"), in the same order as the songs.
+    ///
+    /// These must be valid symbol names for the output format.
+    pub descriptors: Vec<String>,
+    /// Where each song came from (typically, its file name), in the same order as the songs.
+    ///
+    /// This is only used in comments.
+    pub source_names: Vec<String>,
+    /// When the songs were exported; this is only used in comments.
+    ///
+    /// If `None`, no date is written at all, so that exporting the same songs always produces the
+    /// same output.
+    pub timestamp: Option<DateTime<Utc>>,
+    pub format: Format,
+}
+
+#[derive(Debug, Clone)]
+pub enum Format {
+    /// RGBASM source code.
+    Asm(AsmOptions),
+    /// Raw binary data.
+    Bin(BinOptions),
+    /// C source code for GBDK/SDCC.
+    C(COptions),
+}
+
+#[derive(Debug, Clone)]
+pub struct AsmOptions {
+    /// Path to the include file to emit an `INCLUDE` directive for; if empty, none will be emitted.
+    pub include_path: String,
+    /// The type and name of the section that the data will be exported to;
+    /// if `None`, no `SECTION` directive will be emitted.
+    pub section: Option<(String, String)>,
+    /// The type and name of the section that the row pools and cell catalogs will be exported to;
+    /// if `None`, they go in the same section as the rest of the data.
+    ///
+    /// fortISSimO cannot switch banks while playing a song, so this is only useful to place half of
+    /// the data in ROM0, or in the same bank as the other half, but at a different address.
+    pub shared_section: Option<(String, String)>,
+    /// Whether to export the routines written in the song, along with a dispatcher that calls one
+    /// of them depending on `6xy`'s `y`, like hUGEDriver does; otherwise, the routine must be
+    /// written by hand after the data.
+    ///
+    /// Only a single song may be exported with this.
+    pub routines: bool,
+    /// Whether to define a constant for each argument that the routine may be called with (see
+    /// [`SongResults::routine_calls`](crate::optimise::SongResults::routine_calls)), so that it can
+    /// refer to them by name.
+    pub event_constants: bool,
+    /// The names of those constants, by argument; the others are named after their song's
+    /// descriptor, e.g. `boss_music_EVENT_01`.
+    pub event_names: HashMap<u8, String>,
+    /// Whether to write, in a comment after the routine's label, a skeleton of a routine that jumps
+    /// to a different label for each argument that it may be called with.
+    pub routine_skeleton: bool,
+    /// Whether to define constants for how long each song plays before looping, how long it then
+    /// takes to loop, and where it loops to (see [`playback::timing`](crate::playback::timing())).
+    pub timing_constants: bool,
+}
+
+impl Default for AsmOptions {
+    fn default() -> Self {
+        Self {
+            include_path: "fortISSimO.inc".into(),
+            section: None,
+            shared_section: None,
+            routines: false,
+            event_constants: false,
+            event_names: HashMap::new(),
+            routine_skeleton: false,
+            timing_constants: false,
+        }
+    }
+}
+
+/// Parses the names to give the routine's arguments (see [`AsmOptions::event_names`]), from lines of
+/// the form `6xx = <name>`. Blank lines and comments (starting with `#`) are ignored.
+pub fn parse_event_names(config: &str) -> Result<HashMap<u8, String>, String> {
+    let mut names = HashMap::new();
+    for (line_no, line) in config.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((effect, name)) = line.split_once('=') else {
+            return Err(format!("line {}: expected `6xx = <name>`", line_no + 1));
+        };
+        let effect = effect.trim();
+        let arg = effect
+            .strip_prefix('6')
+            .filter(|arg| arg.len() == 2 && arg.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|arg| u8::from_str_radix(arg, 16).ok())
+            .ok_or_else(|| {
+                format!(
+                    "line {}: \"{effect}\" is not a `6xx` effect (e.g. `601`)",
+                    line_no + 1
+                )
+            })?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!(
+                "line {}: missing a name for `{effect}`",
+                line_no + 1
+            ));
+        }
+        if names.insert(arg, name.to_string()).is_some() {
+            return Err(format!(
+                "line {}: `{effect}` was already named",
+                line_no + 1
+            ));
+        }
+    }
+    Ok(names)
+}
+
+#[derive(Debug, Clone)]
+pub struct BinOptions {
+    /// Address that the data will be loaded at; all pointers are resolved against it.
+    pub base_address: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct COptions {
+    /// Address that the cell catalogs will be placed at; must be a multiple of 256.
+    pub catalog_address: u16,
+    /// Name of the header written by [`export_companion`], for the source to include; if `None`,
+    /// the source defines the types that it uses itself.
+    pub header_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(std::io::Error),
+    /// A catalog cannot hold more than 256 cells.
+    TooManyCells {
+        nb_unique_cells: usize,
+        subpatterns: bool,
+    },
+    /// The binary data would not fit in the address space.
+    AddressOverflow {
+        size: usize,
+        base_address: u16,
+    },
+    /// The C output's cell catalogs would not fit in the address space, or are misaligned.
+    BadCatalogAddress(u16),
+    /// fortISSimO requires all of a song's data to be accessible at the same time, but some of it
+    /// would not fit in a single ROM bank.
+    TooBigForBank {
+        /// The name of the section that is too big, if there is one.
+        section: Option<String>,
+        size: usize,
+    },
+    /// Two sections (identified by their names) could end up in different ROM banks.
+    SectionsInDifferentBanks(String, String),
+    /// A song's routines can only be exported when it is the only song being exported.
+    RoutinesWithSeveralSongs,
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::TooManyCells {
+                nb_unique_cells,
+                subpatterns,
+            } => write!(
+                f,
+                "There are {nb_unique_cells} unique cells in {}, the max is 256",
+                if *subpatterns {
+                    "subpatterns"
+                } else {
+                    "the main grid"
+                }
+            ),
+            Self::AddressOverflow { size, base_address } => write!(
+                f,
+                "The song data is {size} bytes long, and would overflow {} when loaded at ${base_address:04x}",
+                if *base_address < 0x8000 {
+                    "ROM"
+                } else {
+                    "the address space"
+                }
+            ),
+            Self::BadCatalogAddress(address) if address % 256 != 0 => {
+                write!(f, "The catalog address (${address:04x}) must be a multiple of 256")
+            }
+            Self::BadCatalogAddress(address) => write!(
+                f,
+                "The cell catalogs would overflow the address space when placed at ${address:04x}"
+            ),
+            Self::TooBigForBank { section, size } => {
+                match section {
+                    Some(name) => write!(f, "Section \"{name}\" would be")?,
+                    None => write!(f, "The song data would be")?,
+                }
+                write!(
+                    f,
+                    " {size} bytes long, but a ROM bank can only hold {ROM_BANK_SIZE}"
+                )
+            }
+            Self::SectionsInDifferentBanks(first, second) => write!(
+                f,
+                "Sections \"{first}\" and \"{second}\" may end up in different ROM banks, but fortISSimO cannot switch banks while playing a song (put one of them in ROM0, or both in the same `BANK[...]`)"
+            ),
+            Self::RoutinesWithSeveralSongs => {
+                write!(f, "Routines can only be exported for a single song")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Writes out the songs, which must have been optimised together into `results`.
+pub fn export(
+    songs: &[Song],
+    results: &OptimResults,
+    options: &ExportOptions,
+    mut output: impl Write,
+) -> Result<(), ExportError> {
+    let export = Export::new(songs, results, options)?;
+
+    match &options.format {
+        Format::Asm(asm_options) => asm::export(&export, asm_options, &mut output)?,
+        Format::Bin(bin_options) => bin::export(&export, bin_options, &mut output)?,
+        Format::C(c_options) => c::export(&export, c_options, &mut output)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Measures how many bytes each part of the export takes up, padding included.
+///
+/// For RGBASM output, the padding depends on where the linker places the section;
+/// it is computed as if the section started at a multiple of 256 (e.g. with `ALIGN[8]`).
+/// Otherwise, up to 255 more bytes of padding may be inserted before the first catalog table.
+pub fn sizes(
+    songs: &[Song],
+    results: &OptimResults,
+    options: &ExportOptions,
+) -> Result<Sizes, ExportError> {
+    let export = Export::new(songs, results, options)?;
+    Ok(match &options.format {
+        Format::Bin(bin_options) => bin::sizes(&export, bin_options.base_address),
+        Format::Asm(_) => {
+            // Each section is assumed to start at a multiple of 256.
+            let mut sizes = bin::sizes(&export, 0);
+            let block_sizes = bin::block_sizes(&export, 0);
+            let total = section_sizes(&export.blocks, &block_sizes, 0).iter().sum();
+            sizes.padding = sizes.padding + total - sizes.total;
+            sizes.total = total;
+            sizes
+        }
+        Format::C(_) => {
+            // The catalogs are placed at a fixed address, away from the rest of the data;
+            // so the only padding is between each catalog's tables.
+            let mut sizes = bin::sizes(&export, 0);
+            let padding = [sizes.main_catalog, sizes.subpat_catalog]
+                .into_iter()
+                .filter(|&size| size != 0)
+                .map(|size| (256 - size / 3) * 2)
+                .sum();
+            sizes.total = sizes.total - sizes.padding + padding;
+            sizes.padding = padding;
+            sizes
+        }
+    })
+}
+
+/// Serialises everything that the export takes up and saved, as [measured](sizes) and
+/// [optimised](crate::optimise()), as a JSON object; `timings` are in the same order as the songs.
+pub fn stats_json(
+    options: &ExportOptions,
+    optim_stats: &OptimStats,
+    optim_results: &OptimResults,
+    sizes: &Sizes,
+    timings: &[Timing],
+) -> String {
+    let songs: Vec<String> = sizes
+        .songs
+        .iter()
+        .zip(&options.descriptors)
+        .zip(&options.source_names)
+        .zip(timings)
+        .map(|(((song, descriptor), source_name), timing)| {
+            format!(
+                r#"{{"descriptor":{},"source":{},"header":{},"order_columns":{},"instruments":{},"waves":{},"timing":{}}}"#,
+                json::string(descriptor),
+                json::string(source_name),
+                song.header,
+                song.order_columns,
+                song.instruments,
+                song.waves,
+                timing.to_json(),
+            )
+        })
+        .collect();
+
+    format!(
+        concat!(
+            r#"{{"songs":[{}],"#,
+            r#""row_pools":{{"main":{},"subpattern":{}}},"#,
+            r#""catalogs":{{"main":{{"unique_cells":{},"bytes":{}}},"subpattern":{{"unique_cells":{},"bytes":{}}}}},"#,
+            r#""padding":{},"reclaimed_padding":{},"total":{},"optimisations":{}}}"#,
+        ),
+        songs.join(","),
+        sizes.main_row_pool,
+        sizes.subpat_row_pool,
+        optim_results.main_cell_catalog.len(),
+        sizes.main_catalog,
+        optim_results.subpat_cell_catalog.len(),
+        sizes.subpat_catalog,
+        sizes.padding,
+        sizes.reclaimed_padding,
+        sizes.total,
+        optim_stats.to_json(),
+    )
+}
+
+/// How many bytes each part of an export takes up.
+#[derive(Debug, Clone, Default)]
+pub struct Sizes {
+    /// In the same order as the songs.
+    pub songs: Vec<SongSizes>,
+    pub main_row_pool: usize,
+    pub subpat_row_pool: usize,
+    /// All three tables, not counting the padding between them.
+    pub main_catalog: usize,
+    pub subpat_catalog: usize,
+    /// Bytes inserted to align the catalogs.
+    pub padding: usize,
+    /// Padding that was avoided by moving instruments and waves into it.
+    pub reclaimed_padding: usize,
+    pub total: usize,
+}
+
+/// The parts of an export that belong to a single song.
+#[derive(Debug, Clone, Default)]
+pub struct SongSizes {
+    pub header: usize,
+    pub order_columns: usize,
+    pub instruments: usize,
+    pub waves: usize,
+}
+
+/// Writes out the file that goes along with the main output, if the format has one:
+/// a symbol file listing the descriptors' offsets for binary output, or a header file for C output.
+/// Nothing is written for RGBASM output.
+pub fn export_companion(
+    songs: &[Song],
+    results: &OptimResults,
+    options: &ExportOptions,
+    mut output: impl Write,
+) -> Result<(), ExportError> {
+    let export = Export::new(songs, results, options)?;
+
+    match &options.format {
+        Format::Asm(_) => {}
+        Format::Bin(bin_options) => bin::export_symbols(&export, bin_options, &mut output)?,
+        Format::C(_) => c::export_header(&export, &mut output)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Everything that the backends need to know about what they are exporting.
+struct Export<'a> {
+    songs: &'a [Song<'a>],
+    source_names: &'a [String],
+    descriptors: &'a [String],
+    timestamp: Option<DateTime<Utc>>,
+    results: &'a OptimResults,
+    main_catalog: CatalogView<'a>,
+    subpat_catalog: CatalogView<'a>,
+    blocks: Vec<Block>,
+    /// How many bytes of padding were saved by moving blocks in front of the catalog tables.
+    reclaimed_padding: usize,
+    /// Patterns only referenced by unreachable order rows get pruned, so pointers to them must be
+    /// replaced with something else.
+    emitted_patterns: HashSet<PatternKey>,
+}
+
+impl<'a> Export<'a> {
+    fn new(
+        songs: &'a [Song<'a>],
+        results: &'a OptimResults,
+        options: &'a ExportOptions,
+    ) -> Result<Self, ExportError> {
+        assert_eq!(songs.len(), options.descriptors.len());
+        assert_eq!(songs.len(), options.source_names.len());
+        for (catalog, subpatterns) in [
+            (&results.main_cell_catalog, false),
+            (&results.subpat_cell_catalog, true),
+        ] {
+            if let nb_unique_cells @ 257.. = catalog.len() {
+                return Err(ExportError::TooManyCells {
+                    nb_unique_cells,
+                    subpatterns,
+                });
+            }
+        }
+
+        let mut export = Self {
+            songs,
+            source_names: &options.source_names,
+            descriptors: &options.descriptors,
+            timestamp: options.timestamp,
+            results,
+            main_catalog: CatalogView::new(&results.main_cell_catalog),
+            subpat_catalog: CatalogView::new(&results.subpat_cell_catalog),
+            blocks: layout(
+                songs.len(),
+                matches!(&options.format, Format::Asm(asm_options) if asm_options.shared_section.is_some()),
+            ),
+            reclaimed_padding: 0,
+            emitted_patterns: results
+                .main_row_pool
+                .iter()
+                .filter_map(|entry| match entry {
+                    OutputCell::Label(key) => Some(*key),
+                    _ => None,
+                })
+                .collect(),
+        };
+
+        let start_address = match &options.format {
+            Format::Bin(bin_options) => Some(bin_options.base_address),
+            // The section may be placed anywhere.
+            Format::Asm(_) | Format::C(_) => None,
+        };
+        let base_address = start_address.unwrap_or(0);
+        let block_sizes = bin::block_sizes(&export, base_address);
+        match &options.format {
+            // `bin::export` checks that the data fits where it will be loaded.
+            Format::Bin(_) => {}
+            Format::Asm(asm_options) => {
+                let sections = [&asm_options.section, &asm_options.shared_section];
+                if let [Some((first_kind, first_name)), Some((second_kind, second_name))] = sections
+                {
+                    if !same_bank(first_kind, second_kind) {
+                        return Err(ExportError::SectionsInDifferentBanks(
+                            first_name.clone(),
+                            second_name.clone(),
+                        ));
+                    }
+                }
+            }
+            // The catalogs are placed at a fixed address, away from everything else; so there is
+            // nothing to pack, and they don't count towards the rest of the data's size.
+            Format::C(_) => {
+                let size = block_sizes
+                    .iter()
+                    .filter(|(block, _)| !matches!(block, Block::CatalogTable(..)))
+                    .map(|(_, size)| size)
+                    .sum();
+                if size > ROM_BANK_SIZE {
+                    return Err(ExportError::TooBigForBank {
+                        section: None,
+                        size,
+                    });
+                }
+                return Ok(export);
+            }
+        }
+
+        let packed_blocks = packing::pack(&export.blocks, &block_sizes, start_address);
+        let total_size = |blocks| -> usize {
+            section_sizes(blocks, &block_sizes, base_address.into())
+                .iter()
+                .sum()
+        };
+        export.reclaimed_padding = total_size(&export.blocks) - total_size(&packed_blocks);
+        export.blocks = packed_blocks;
+
+        if let Format::Asm(asm_options) = &options.format {
+            let sections = [&asm_options.section, &asm_options.shared_section];
+            for (size, section) in section_sizes(&export.blocks, &block_sizes, 0)
+                .into_iter()
+                .zip(sections)
+            {
+                if size > ROM_BANK_SIZE {
+                    return Err(ExportError::TooBigForBank {
+                        section: section.as_ref().map(|(_, name)| name.clone()),
+                        size,
+                    });
+                }
+            }
+        }
+        Ok(export)
+    }
+}
+
+/// How many bytes each section takes up (padding included), if the first one starts at
+/// `start_address`, and the others at a multiple of 256.
+fn section_sizes(
+    blocks: &[Block],
+    block_sizes: &HashMap<Block, usize>,
+    start_address: usize,
+) -> Vec<usize> {
+    let mut ends = vec![start_address];
+    for block in blocks {
+        let end = ends.last_mut().unwrap();
+        match block {
+            Block::SharedSection => ends.push(0),
+            Block::CatalogTable(..) => *end += end.wrapping_neg() % 256 + block_sizes[block],
+            _ => *end += block_sizes[block],
+        }
+    }
+    ends[0] -= start_address;
+    ends
+}
+
+/// Whether sections of these types are sure to be accessible at the same time, as far as can be told.
+fn same_bank(first: &str, second: &str) -> bool {
+    // `None` if the section is not in ROMX; otherwise, the bank it is in, if specified.
+    let romx_bank = |kind: &str| {
+        let kind = kind.to_ascii_uppercase();
+        kind.trim_start().starts_with("ROMX").then(|| {
+            let (_, bank) = kind.split_once("BANK")?;
+            let (bank, _) = bank.trim_start().strip_prefix('[')?.split_once(']')?;
+            Some(bank.trim().to_owned())
+        })
+    };
+    match (romx_bank(first), romx_bank(second)) {
+        (Some(first), Some(second)) => first.is_some() && first == second,
+        // ROM0 is always accessible.
+        _ => true,
+    }
+}
+
+impl Export<'_> {
+    /// Where (and possibly when) the output came from, for the comment at its top.
+    fn origin(&self) -> String {
+        let mut origin = if self.songs.len() > 1 {
+            format!("Generated from {} files", self.songs.len())
+        } else {
+            format!("Generated from {}", self.source_names[0])
+        };
+        if let Some(timestamp) = self.timestamp {
+            origin.push_str(&format!(" on {}", timestamp.trunc_subsecs(0)));
+        }
+        origin
+    }
+
+    fn catalog(&self, kind: CatalogKind) -> &CatalogView<'_> {
+        match kind {
+            CatalogKind::Main => &self.main_catalog,
+            CatalogKind::Subpattern => &self.subpat_catalog,
+        }
+    }
+
+    /// The instruments of a given kind that a song uses, in the order they are emitted, along with their original ID.
+    fn used_instrs(
+        &self,
+        song_idx: usize,
+        kind: InstrKind,
+    ) -> impl Iterator<Item = (u8, &Instrument<'_>)> + '_ {
+        let (bank, usage) = match kind {
+            InstrKind::Duty => (
+                &self.songs[song_idx].instruments.duty,
+                &self.results.songs[song_idx].duty_instr_usage,
+            ),
+            InstrKind::Wave => (
+                &self.songs[song_idx].instruments.wave,
+                &self.results.songs[song_idx].wave_instr_usage,
+            ),
+            InstrKind::Noise => (
+                &self.songs[song_idx].instruments.noise,
+                &self.results.songs[song_idx].noise_instr_usage,
+            ),
+        };
+        usage.iter().map(|id| (id, &bank[usize::from(id)]))
+    }
+
+    fn pattern_name(&self, (song_idx, id): PatternKey) -> PatternLabel<'_> {
+        PatternLabel {
+            song_prefix: (self.songs.len() > 1).then(|| self.descriptors[song_idx].as_str()),
+            id,
+        }
+    }
+
+    /// Which pattern an order row should point to, if any.
+    fn order_target(&self, key: PatternKey) -> Option<PatternKey> {
+        self.emitted_patterns.contains(&key).then_some(key)
+    }
+}
+
+/// The pieces of data that make up an export, which every backend emits in the same order,
+/// so that they all agree on the resulting layout.
+/// (Except for the C backend, which leaves the layout up to the compiler.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Block {
+    /// The song descriptor.
+    Header(usize),
+    OrderColumns(usize),
+    RowPool(CatalogKind),
+    /// One of the catalog's three 256-byte-aligned tables.
+    CatalogTable(CatalogKind, usize),
+    /// Sanity checks against the driver's constants.
+    Asserts,
+    DutyInstrs(usize),
+    WaveInstrs(usize),
+    NoiseInstrs(usize),
+    Waves(usize),
+    /// Where the section holding the row pools and cell catalogs starts, if they have their own.
+    SharedSection,
+    /// The end of the export, where the routine goes.
+    Routine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CatalogKind {
+    Main,
+    Subpattern,
+}
+
+impl CatalogKind {
+    fn label_name(&self) -> &'static str {
+        match self {
+            Self::Main => "mainCellCatalog",
+            Self::Subpattern => "subpatCellCatalog",
+        }
+    }
+}
+
+/// The shared data (row pools and cell catalogs) is emitted right after the first song's order columns,
+/// or after all of the songs' data if it goes in its own section; and the routine at the very end.
+fn layout(nb_songs: usize, separate_shared_data: bool) -> Vec<Block> {
+    let shared_data = |blocks: &mut Vec<Block>| {
+        for kind in [CatalogKind::Main, CatalogKind::Subpattern] {
+            blocks.push(Block::RowPool(kind));
+            blocks.extend((0..3).map(|i| Block::CatalogTable(kind, i)));
+        }
+        blocks.push(Block::Asserts);
+    };
+
+    let mut blocks = Vec::new();
+    for song_idx in 0..nb_songs {
+        blocks.push(Block::Header(song_idx));
+        blocks.push(Block::OrderColumns(song_idx));
+        if song_idx == 0 && !separate_shared_data {
+            shared_data(&mut blocks);
+        }
+        blocks.push(Block::DutyInstrs(song_idx));
+        blocks.push(Block::WaveInstrs(song_idx));
+        blocks.push(Block::NoiseInstrs(song_idx));
+        blocks.push(Block::Waves(song_idx));
+    }
+    if separate_shared_data {
+        blocks.push(Block::SharedSection);
+        shared_data(&mut blocks);
+    }
+    blocks.push(Block::Routine);
+    blocks
+}
+
+/// A cell catalog, in the order its entries are emitted.
+#[derive(Debug)]
+struct CatalogView<'a> {
+    cells: Vec<&'a Cell>,
+    /// Maps the IDs in the row pool to the index of the corresponding cell in `cells`.
+    reverse_lookup: [u8; 256],
+}
+
+impl<'a> CatalogView<'a> {
+    fn new(cell_catalog: &'a CellCatalog) -> Self {
+        // The catalog is a hashmap, whose iteration order is arbitrary; emit the cells by ID instead,
+        // so that the output doesn't change from one run to the next.
+        let mut entries: Vec<(&Cell, &u8)> = cell_catalog.iter().collect();
+        entries.sort_unstable_by_key(|(_, id)| **id);
+
+        let mut reverse_lookup = [0; 256];
+        for (i, (_, id)) in entries.iter().enumerate() {
+            reverse_lookup[usize::from(**id)] = i as u8;
+        }
+        Self {
+            cells: entries.into_iter().map(|(cell, _)| cell).collect(),
+            reverse_lookup,
+        }
+    }
+}
+
+/// An instrument's bytes, for backends that can't rely on RGBASM to compute them.
+/// The subpattern pointer goes between the two halves.
+#[derive(Debug)]
+pub(crate) struct RawInstr {
+    pub(crate) head: Vec<u8>,
+    pub(crate) tail: Vec<u8>,
+}
+
+impl RawInstr {
+    pub(crate) fn new(instr: &Instrument, wave_usage: &CompactedMapping<16>) -> Self {
+        let length = instr.length.unwrap_or(0);
+        let length_enable = (instr.length.is_some() as u8) << 6;
+        let nrx2 = |initial_volume: u8, envelope_dir, envelope_pace: u8| {
+            let envelope_dir = match envelope_dir {
+                EnvelopeDirection::Down => 0,
+                EnvelopeDirection::Up => 1,
+            };
+            initial_volume << 4 | envelope_dir << 3 | envelope_pace
+        };
+
+        match instr.kind {
+            InstrumentKind::Square {
+                initial_volume,
+                envelope_dir,
+                envelope_pace,
+                sweep_time,
+                sweep_dir,
+                sweep_shift,
+                duty,
+            } => {
+                let sweep_dir = match sweep_dir {
+                    SweepDirection::Down => 1,
+                    SweepDirection::Up => 0,
+                };
+                let duty = match duty {
+                    DutyType::Percent12_5 => 0b00,
+                    DutyType::Percent25 => 0b01,
+                    DutyType::Percent50 => 0b10,
+                    DutyType::Percent75 => 0b11,
+                };
+                Self {
+                    head: vec![
+                        sweep_time << 4 | sweep_dir << 3 | sweep_shift,
+                        duty << 6 | length,
+                        nrx2(initial_volume, envelope_dir, envelope_pace),
+                    ],
+                    tail: vec![0x80 | length_enable],
+                }
+            }
+            InstrumentKind::Wave {
+                output_level,
+                wave_id,
+            } => {
+                let output_level = match output_level {
+                    WaveOutputLevel::Mute => 0b00,
+                    WaveOutputLevel::Full => 0b01,
+                    WaveOutputLevel::Half => 0b10,
+                    WaveOutputLevel::Quarter => 0b11,
+                };
+                Self {
+                    head: vec![length, output_level << 5],
+                    tail: vec![
+                        0x80 | length_enable,
+                        wave_usage.get_remapped_id(wave_id.into()) << 4,
+                    ],
+                }
+            }
+            InstrumentKind::Noise {
+                initial_volume,
+                envelope_dir,
+                envelope_pace,
+                lfsr_width,
+            } => {
+                let lfsr_width = match lfsr_width {
+                    LfsrWidth::Fifteen => 0,
+                    LfsrWidth::Seven => 1,
+                };
+                Self {
+                    head: vec![nrx2(initial_volume, envelope_dir, envelope_pace)],
+                    tail: vec![lfsr_width << 7 | length_enable | length],
+                }
+            }
+        }
+    }
+}
+
+/// In batch mode, patterns of different songs are disambiguated by their song's descriptor.
+#[derive(Debug, Clone, Copy)]
+struct PatternLabel<'a> {
+    song_prefix: Option<&'a str>,
+    id: PatternId,
+}
+
+impl Display for PatternLabel<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(prefix) = self.song_prefix {
+            write!(f, "{prefix}_")?;
+        }
+        self.id.fmt(f)
+    }
+}
+
+impl Display for PatternId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = f.width().unwrap_or(0);
+        match self {
+            Self::Pattern(kind, id) => write!(f, "{kind}Ptrn{id:<width$}"),
+            Self::Subpattern(kind, id) => write!(f, "{kind}Inst{:<width$}Subpattern", id),
+        }
+    }
+}
+
+impl Display for InstrKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Duty => write!(f, "duty"),
+            Self::Wave => write!(f, "wave"),
+            Self::Noise => write!(f, "noise"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        optimise::{optimise, OptimOptions},
+        song::Note,
+        uge::test_song,
+    };
+
+    /// Optimises and exports the songs from scratch, returning the output and the companion file.
+    fn export_all(songs: &[Song], format: Format) -> (Vec<u8>, Vec<u8>) {
+        let options = ExportOptions {
+            descriptors: (0..songs.len()).map(|i| format!("song_{i}")).collect(),
+            source_names: (0..songs.len()).map(|i| format!("song_{i}.uge")).collect(),
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0),
+            format,
+        };
+        let (results, _stats) = optimise(songs, &OptimOptions::default());
+        let (mut output, mut companion) = (vec![], vec![]);
+        export(songs, &results, &options, &mut output).expect("Export should succeed");
+        export_companion(songs, &results, &options, &mut companion)
+            .expect("Companion export should succeed");
+        (output, companion)
+    }
+
+    #[test]
+    fn reproducible() {
+        let song = test_song();
+        let mut other_song = test_song();
+        other_song.patterns[0][10].note = Note::C_5;
+        other_song.order_matrix.push([2, 2, 0, 0]);
+
+        let formats = [
+            Format::Asm(AsmOptions::default()),
+            Format::Bin(BinOptions {
+                base_address: 0x4000,
+            }),
+            Format::C(COptions {
+                catalog_address: 0x4000,
+                header_name: Some("songs.h".into()),
+            }),
+        ];
+        // Both a single song, and several exported together.
+        for songs in [&[song.clone()][..], &[song, other_song][..]] {
+            for format in &formats {
+                let first = export_all(songs, format.clone());
+                let second = export_all(songs, format.clone());
+                assert!(
+                    first == second,
+                    "{format:?} export of {} songs differs",
+                    songs.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn routines() {
+        let mut song = test_song();
+        song.routines[4] = "\tld a, 42".into();
+        let options = |nb_songs| ExportOptions {
+            descriptors: (0..nb_songs).map(|i| format!("song_{i}")).collect(),
+            source_names: (0..nb_songs).map(|i| format!("song_{i}.uge")).collect(),
+            timestamp: None,
+            format: Format::Asm(AsmOptions {
+                routines: true,
+                ..Default::default()
+            }),
+        };
+
+        let songs = [song.clone()];
+        let (results, _stats) = optimise(&songs, &OptimOptions::default());
+        let mut output = vec![];
+        export(&songs, &results, &options(1), &mut output).expect("Export should succeed");
+        let output = String::from_utf8(output).unwrap();
+        // Routines are exported as-is, so they must be returned from.
+        assert!(output.contains("song_0_routine4:\n\tld a, 42\n\tret\n"));
+
+        let songs = [song.clone(), song];
+        let (results, _stats) = optimise(&songs, &OptimOptions::default());
+        assert!(matches!(
+            export(&songs, &results, &options(2), &mut vec![]),
+            Err(ExportError::RoutinesWithSeveralSongs)
+        ));
+    }
+}
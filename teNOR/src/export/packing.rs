@@ -0,0 +1,110 @@
+//! Fills the padding before the catalogs' 256-byte-aligned tables with other data.
+//!
+//! The tables are aligned, but the catalogs rarely contain 256 cells; so, without this, the gap
+//! between one table's end and the next one's start is wasted.
+//! Instruments and waves are only ever accessed through pointers in the song's header, so they can
+//! go anywhere; so, going through the gaps in order, each is filled with as many of them as will fit.
+//! (The order columns cannot move, since the driver expects them right after the header; neither
+//! can the patterns, since they are interleaved in the row pools.)
+
+use std::collections::{HashMap, HashSet};
+
+use super::Block;
+
+/// Moves blocks into the padding before the catalog tables, returning the new layout.
+///
+/// `start_address` is where the data will be loaded at, if known; otherwise, the gap before the
+/// first table cannot be known, and is left alone. (The ones after it only depend on the tables'
+/// sizes, since those are aligned.) The same goes for the shared data's section, if it has one.
+pub(super) fn pack(
+    blocks: &[Block],
+    block_sizes: &HashMap<Block, usize>,
+    start_address: Option<u16>,
+) -> Vec<Block> {
+    let is_movable = |block: &Block| {
+        matches!(
+            block,
+            Block::DutyInstrs(_) | Block::WaveInstrs(_) | Block::NoiseInstrs(_) | Block::Waves(_)
+        ) && block_sizes[block] != 0
+    };
+
+    // First, pick which blocks go in front of each table.
+    // Blocks can be moved forwards, but also backwards if their size doesn't affect any gap's,
+    // which is the case if they are somewhere whose address isn't known.
+    let mut deferred = Vec::new();
+    let mut moved = HashSet::new();
+    let mut fillings = HashMap::new();
+    // Where the next block would be placed, if that is known; only its value modulo 256 matters.
+    let mut address = start_address.map(usize::from);
+    for (i, block) in blocks.iter().enumerate() {
+        if moved.contains(block) {
+            continue;
+        }
+        match block {
+            Block::CatalogTable(..) => {
+                if let Some(addr) = address {
+                    let candidates: Vec<(Block, usize)> = deferred
+                        .iter()
+                        .chain(&blocks[i + 1..])
+                        .filter(|candidate| is_movable(candidate) && !moved.contains(*candidate))
+                        .map(|candidate| (*candidate, block_sizes[candidate]))
+                        .collect();
+                    let mut filling: Vec<Block> =
+                        fullest_fit(&candidates, addr.wrapping_neg() % 256)
+                            .into_iter()
+                            .map(|idx| candidates[idx].0)
+                            .collect();
+                    // Keep the blocks in their original order, which is nicer to read.
+                    filling.reverse();
+                    moved.extend(filling.iter().copied());
+                    fillings.insert(*block, filling);
+                }
+                address = Some(0); // The table is aligned.
+            }
+            Block::SharedSection => address = None,
+            block if address.is_none() => deferred.push(*block),
+            _ => {}
+        }
+        address = address.map(|addr| addr + block_sizes[block]);
+    }
+
+    // Then, lay everything out accordingly.
+    let mut packed = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        if moved.contains(block) {
+            continue;
+        }
+        if let Some(filling) = fillings.get(block) {
+            packed.extend_from_slice(filling);
+        }
+        packed.push(*block);
+    }
+    packed
+}
+
+/// Picks the blocks that fill as much of a gap as possible, returning their indices in decreasing order
+/// (so that they can be removed one by one).
+fn fullest_fit(blocks: &[(Block, usize)], gap: usize) -> Vec<usize> {
+    // Classic 0/1 knapsack, where each block's value is its size: `best[i][n]` is whether the first
+    // `i` blocks can fill exactly `n` bytes.
+    let mut best = vec![vec![false; gap + 1]; blocks.len() + 1];
+    best[0][0] = true;
+    for (i, &(_, size)) in blocks.iter().enumerate() {
+        for n in 0..=gap {
+            best[i + 1][n] = best[i][n] || (n >= size && best[i][n - size]);
+        }
+    }
+
+    let Some(mut n) = (0..=gap).rev().find(|&n| best[blocks.len()][n]) else {
+        unreachable!("Filling nothing is always possible");
+    };
+    let mut picked = Vec::new();
+    for i in (0..blocks.len()).rev() {
+        if !best[i][n] {
+            // The `i`th block is required to fill `n` bytes.
+            picked.push(i);
+            n -= blocks[i].1;
+        }
+    }
+    picked
+}
@@ -0,0 +1,246 @@
+//! Serialises diagnostics and stats as JSON, for tools (editors, CI) that consume them.
+//!
+//! Each kind of report has its own `to_json` method, next to its type; this module only holds
+//! what they share.
+
+use crate::{
+    song::EffectId,
+    validate::{Lint, Severity},
+};
+
+/// Quotes and escapes a string.
+pub fn string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// The object every diagnostic is reported as, whether it comes from [`validate`](crate::validate())
+/// or from [`convert_tempo`](crate::convert_tempo()).
+///
+/// `location` must already be serialised; it is written as `null` if absent.
+pub(crate) fn diagnostic(
+    file: &str,
+    severity: Severity,
+    lint: Option<Lint>,
+    message: &str,
+    effect: Option<(EffectId, u8)>,
+    location: Option<&str>,
+) -> String {
+    format!(
+        r#"{{"file":{},"severity":"{severity}","lint":{},"message":{},"effect":{},"location":{}}}"#,
+        string(file),
+        lint.map_or("null".into(), |lint| string(lint.name())),
+        string(message),
+        effect.map_or("null".into(), |(effect_code, effect_param)| format!(
+            r#""{:X}{effect_param:02X}""#,
+            effect_code as u8
+        )),
+        location.unwrap_or("null"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        export::{self, ExportOptions, Format},
+        optimise::{self, InstrKind},
+        playback,
+        song::Location,
+        tempo::{TempoChange, ZeroedEffect},
+        uge,
+        validate::{Diagnostic, Problem},
+    };
+
+    /// Checks that `json` is a single, well-formed JSON value.
+    fn assert_parses(json: &str) {
+        let mut rest = json.as_bytes();
+        let parsed = value(&mut rest).is_some() && skip_whitespace(&mut rest).is_empty();
+        assert!(parsed, "Not valid JSON: {json}");
+    }
+
+    fn skip_whitespace<'a>(input: &mut &'a [u8]) -> &'a [u8] {
+        while let [b' ' | b'\t' | b'\n' | b'\r', rest @ ..] = input {
+            *input = rest;
+        }
+        input
+    }
+
+    fn eat(input: &mut &[u8], token: &[u8]) -> Option<()> {
+        let rest = skip_whitespace(input).strip_prefix(token)?;
+        *input = rest;
+        Some(())
+    }
+
+    fn value(input: &mut &[u8]) -> Option<()> {
+        match skip_whitespace(input).first()? {
+            b'{' => {
+                eat(input, b"{")?;
+                if eat(input, b"}").is_some() {
+                    return Some(());
+                }
+                loop {
+                    string_value(input)?;
+                    eat(input, b":")?;
+                    value(input)?;
+                    if eat(input, b",").is_none() {
+                        return eat(input, b"}");
+                    }
+                }
+            }
+            b'[' => {
+                eat(input, b"[")?;
+                if eat(input, b"]").is_some() {
+                    return Some(());
+                }
+                loop {
+                    value(input)?;
+                    if eat(input, b",").is_none() {
+                        return eat(input, b"]");
+                    }
+                }
+            }
+            b'"' => string_value(input),
+            b't' => eat(input, b"true"),
+            b'f' => eat(input, b"false"),
+            b'n' => eat(input, b"null"),
+            _ => number(input),
+        }
+    }
+
+    fn string_value(input: &mut &[u8]) -> Option<()> {
+        eat(input, b"\"")?;
+        loop {
+            match input {
+                [b'"', rest @ ..] => {
+                    *input = rest;
+                    return Some(());
+                }
+                [b'\\', b'u', a, b, c, d, rest @ ..]
+                    if [a, b, c, d].iter().all(|digit| digit.is_ascii_hexdigit()) =>
+                {
+                    *input = rest
+                }
+                [b'\\', b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't', rest @ ..] => {
+                    *input = rest
+                }
+                [c, rest @ ..] if *c >= 0x20 && *c != b'\\' => *input = rest,
+                _ => return None,
+            }
+        }
+    }
+
+    fn number(input: &mut &[u8]) -> Option<()> {
+        let digits = |input: &mut &[u8]| {
+            let len = input.iter().take_while(|c| c.is_ascii_digit()).count();
+            *input = &input[len..];
+            (len != 0).then_some(())
+        };
+
+        if let [b'-', rest @ ..] = input {
+            *input = rest;
+        }
+        match input {
+            [b'0', rest @ ..] => *input = rest,
+            _ => digits(input)?,
+        }
+        if let [b'.', rest @ ..] = input {
+            *input = rest;
+            digits(input)?;
+        }
+        if let [b'e' | b'E', rest @ ..] = input {
+            *input = rest;
+            if let [b'+' | b'-', rest @ ..] = input {
+                *input = rest;
+            }
+            digits(input)?;
+        }
+        Some(())
+    }
+
+    #[test]
+    fn diagnostics() {
+        let file = "songs/\"boss\"\\\tmusic\u{7f}é.uge";
+        let locations = [
+            Location::Row {
+                order_idx: 1,
+                channel: 2,
+                pattern_id: 3,
+                row: 4,
+            },
+            Location::SubpatternRow {
+                kind: InstrKind::Wave,
+                instrument: 5,
+                row: 6,
+            },
+            Location::Pattern { pattern_id: 7 },
+            Location::OrderRow { order_idx: 8 },
+            Location::Instrument {
+                kind: InstrKind::Noise,
+                instrument: 9,
+            },
+            Location::Routine { routine_id: 10 },
+        ];
+        for location in locations {
+            let diagnostic = Diagnostic {
+                severity: Severity::Error,
+                location,
+                effect: Some((EffectId::PosJump, 0x42)),
+                problem: Problem::PosJumpOutOfBounds { nb_orders: 3 },
+            };
+            assert_parses(&diagnostic.to_json(file));
+        }
+        let diagnostic = Diagnostic {
+            severity: Severity::Warning,
+            location: Location::Pattern { pattern_id: 1 },
+            effect: None,
+            problem: Problem::UnreachablePattern,
+        };
+        assert_parses(&diagnostic.to_json(file));
+
+        let change = TempoChange {
+            old_ticks: 7,
+            new_ticks: 5,
+            initial: true,
+            nb_effects: 2,
+        };
+        assert_parses(&change.to_json(file, (None, Some(0x80))));
+        let zeroed_effect = ZeroedEffect {
+            pattern_id: 2,
+            row: 3,
+            effect_code: EffectId::NoteCut,
+            old_param: 1,
+        };
+        assert_parses(&zeroed_effect.to_json(file));
+    }
+
+    #[test]
+    fn stats() {
+        let mut timer_song = uge::test_song();
+        timer_song.timer_divider = Some(0xC0);
+        let songs = [uge::test_song(), timer_song];
+        let (results, stats) = optimise::optimise(&songs, &Default::default());
+        let options = ExportOptions {
+            descriptors: vec!["song_0".into(), "song_\"1\"".into()],
+            source_names: vec!["0.uge".into(), "C:\\1.uge".into()],
+            timestamp: None,
+            format: Format::Asm(Default::default()),
+        };
+        let sizes = export::sizes(&songs, &results, &options).unwrap();
+        let timings: Vec<_> = songs.iter().map(playback::timing).collect();
+
+        assert_parses(&export::stats_json(
+            &options, &stats, &results, &sizes, &timings,
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+//! teNOR converts hUGETracker's `.uge` files into data for fortISSimO.
+//!
+//! This is the library behind the `teNOR` command-line program, for use e.g. from build scripts.
+//! Exporting goes through three steps (plus checking that the song is valid):
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use teNOR::validate::Severity;
+//!
+//! let data = std::fs::read("title.uge")?;
+//! let song = teNOR::parse_song(&data).map_err(|err| err.to_string())?;
+//! for diagnostic in teNOR::validate(&song, &Default::default()) {
+//!     eprintln!("{}: {diagnostic}", diagnostic.severity);
+//!     if diagnostic.severity == Severity::Error {
+//!         return Err("the song is invalid".into());
+//!     }
+//! }
+//! let songs = [song];
+//! let (results, _stats) = teNOR::optimise(&songs, &Default::default());
+//!
+//! let options = teNOR::ExportOptions {
+//!     descriptors: vec!["Title".into()],
+//!     source_names: vec!["title.uge".into()],
+//!     timestamp: None,
+//!     format: teNOR::export::Format::Asm(Default::default()),
+//! };
+//! let output = std::fs::File::create("title.asm")?;
+//! teNOR::export(&songs, &results, &options, output)?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![allow(non_snake_case)] // The name is stylised on purpose.
+
+pub mod driver;
+pub mod export;
+pub mod json;
+pub mod merge;
+pub mod midi;
+pub mod optimise;
+pub mod playback;
+pub mod render;
+pub mod song;
+pub mod tempo;
+pub mod trace;
+pub mod uge;
+pub mod validate;
+
+pub use export::{export, ExportError, ExportOptions};
+pub use merge::merge;
+pub use midi::write_midi;
+pub use optimise::{optimise, OptimOptions, OptimResults, OptimStats};
+pub use render::{render, RenderOptions};
+pub use song::Song;
+pub use tempo::convert_tempo;
+pub use trace::{trace, TraceOptions};
+pub use uge::{parse_song, write_song, ParseError};
+pub use validate::validate;
+
+const LAST_NOTE: u8 = 72;
+const PATTERN_LENGTH: u8 = 64;
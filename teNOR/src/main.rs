@@ -1,31 +1,70 @@
 use std::{
-    ffi::OsString,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
     fmt::Display,
-    io::{IsTerminal, Write},
-    path::Path,
+    fs::File,
+    io::{BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
     process::ExitCode,
+    time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
-use termcolor::{Color, ColorSpec, StandardStream, StandardStreamLock, WriteColor};
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use termcolor::{Color, ColorSpec, NoColor, StandardStream, StandardStreamLock, WriteColor};
 
-mod export;
-mod optimise;
-mod song;
-mod uge;
-
-const LAST_NOTE: u8 = 72;
-const PATTERN_LENGTH: u8 = 64;
+use teNOR::{
+    export::{
+        self, AsmOptions, BinOptions, COptions, ExportError, ExportOptions, Format, Sizes,
+        SongSizes,
+    },
+    merge::{self, Conflict},
+    midi,
+    optimise::{self, OptimOptions, SearchBudget},
+    playback::{self, Timing},
+    render::{self, RenderOptions},
+    tempo::{self, TempoChange, ZeroedEffect},
+    trace::{self, TraceOptions},
+    uge,
+    validate::{self, Diagnostic, Level, Lint, LintLevels, Severity},
+};
 
 #[derive(Debug, Clone, Parser)]
-#[command(version, about, arg_required_else_help = true)]
+#[command(
+    version,
+    about,
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct CliArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the `.uge` file to be exported.
-    input_path: OsString,
-    /// Path to the `.asm` file to write to.
+    #[arg(required = true)]
+    input_path: Option<OsString>,
+    /// Path to the file to write to.
     ///
     /// If omitted, the file will be written to standard output.
     output_path: Option<OsString>,
+    /// Path to another `.uge` file to be exported alongside the first one.
+    ///
+    /// All songs are written to the same file, each with its own descriptor, but share a single row pool and cell catalogs;
+    /// this saves space when songs have patterns or cells in common.
+    /// Can be specified several times.
+    #[arg(short, long = "add", value_name = "PATH")]
+    additional_inputs: Vec<OsString>,
+
+    /// Format of the output file.
+    #[arg(
+        help_heading = "Output modifiers",
+        short,
+        long,
+        default_value_t,
+        value_name = "FORMAT"
+    )]
+    format: OutputFormat,
 
     /// Path to include file to emit.
     ///
@@ -63,10 +102,61 @@ struct CliArgs {
         requires = "section_type"
     )]
     section_name: String,
+    /// Type of a separate section that the row pools and cell catalogs will be exported to.
+    ///
+    /// This is useful if the data does not fit in a single ROM bank: since fortISSimO cannot switch banks while playing a song,
+    /// either this or `--section-type` should be `ROM0`, or both should specify the same `BANK[...]`.
+    #[arg(help_heading = "Output modifiers", long, value_name = "TYPE")]
+    shared_section_type: Option<String>,
+    /// Name of the section that the row pools and cell catalogs will be exported to.
+    ///
+    /// This has no effect if its type is omitted.
+    #[arg(
+        help_heading = "Output modifiers",
+        long,
+        default_value = "Shared Song Data",
+        value_name = "NAME",
+        requires = "shared_section_type"
+    )]
+    shared_section_name: String,
+
+    /// Export the routines written in the `.uge` file, and call them like hUGEDriver does.
+    ///
+    /// A dispatcher is emitted as the song's routine, which calls routine `y` for effect `6xy`.
+    /// Only supported with assembly output, for a single song.
+    #[arg(help_heading = "Output modifiers", long)]
+    routines: bool,
+    /// Define a constant for each argument that the routine may be called with (by `6xx`), and list where each is used.
+    ///
+    /// Unless named by `--event-names`, they are named after the song's descriptor, e.g. `boss_music_EVENT_01`.
+    #[arg(help_heading = "Output modifiers", long)]
+    events: bool,
+    /// Path to a file naming the constants defined by `--events`, with lines like `601 = MUSIC_EVT_BOSS_VULNERABLE`.
+    #[arg(
+        help_heading = "Output modifiers",
+        long,
+        value_name = "PATH",
+        requires = "events"
+    )]
+    event_names: Option<OsString>,
+    /// Write, in a comment after the routine's label, a skeleton of a routine that jumps to a different label for each argument it may be called with.
+    #[arg(help_heading = "Output modifiers", long, conflicts_with = "routines")]
+    routine_skeleton: bool,
+    /// Define constants for how long each song plays before looping, how long it then takes to loop (both in ticks and in frames), and where it loops to.
+    #[arg(help_heading = "Output modifiers", long)]
+    timing: bool,
+
+    /// Do not write the date of the export at the top of the output file.
+    ///
+    /// Otherwise, the date written is the current one, unless the `SOURCE_DATE_EPOCH` environment
+    /// variable is set; either way, exporting the same files always produces the same output.
+    #[arg(help_heading = "Output modifiers", long)]
+    no_timestamp: bool,
 
     /// Name of the label that will point to the track's header (hUGETracker calls this the "song descriptor").
     ///
     /// If omitted, this will be deduced from the input file name.
+    /// When exporting several songs, this can be specified once per song, in the same order as the input files.
     // The alias is for back-compat only.
     #[arg(
         help_heading = "Output modifiers",
@@ -75,7 +165,49 @@ struct CliArgs {
         alias = "song-descriptor",
         value_name = "LABEL"
     )]
-    descriptor: Option<String>,
+    descriptor: Vec<String>,
+
+    /// Address that the binary output will be loaded at; all pointers are resolved against it.
+    ///
+    /// Can be written in decimal, or in hexadecimal with a `$` or `0x` prefix.
+    #[arg(
+        help_heading = "Binary output",
+        short = 'b',
+        long,
+        value_name = "ADDRESS",
+        value_parser = parse_address,
+        required_if_eq("format", "bin")
+    )]
+    base_address: Option<u16>,
+    /// Path to a file that will list the offset and address of each song descriptor in the binary output.
+    #[arg(help_heading = "Binary output", long, value_name = "PATH")]
+    symbol_file: Option<OsString>,
+
+    /// Address that the cell catalogs will be placed at; must be a multiple of 256.
+    ///
+    /// SDCC cannot align data, so the catalogs must be placed at a fixed address instead.
+    /// They take up to 1536 bytes.
+    #[arg(
+        help_heading = "C output",
+        long,
+        value_name = "ADDRESS",
+        value_parser = parse_address,
+        required_if_eq("format", "c")
+    )]
+    catalog_address: Option<u16>,
+
+    /// Spend more time looking for ways to overlap patterns, which can make the output smaller.
+    ///
+    /// Either a duration (e.g. `30s`, `2m`, `500ms`), or a number of iterations (e.g. `1000000`);
+    /// the latter always gives the same results, whereas a duration depends on the machine.
+    /// Results are never worse than without this option.
+    #[arg(
+        help_heading = "Optimisation",
+        long,
+        value_name = "BUDGET",
+        value_parser = parse_search_budget
+    )]
+    search_budget: Option<SearchBudget>,
 
     /// Require the track being converted to have the `Enable timer-based tempo` checkbox unchecked.
     #[arg(
@@ -97,14 +229,114 @@ struct CliArgs {
         value_name = "DIVIDER"
     )]
     timer: Option<u8>,
+    /// Instead of requiring songs to use the playback method given by `--vblank` or `--timer`,
+    /// convert the tempo of those that don't.
+    #[arg(help_heading = "Playback method", long)]
+    convert_tempo: bool,
+
+    /// Report a lint as an error, and refuse to export songs that trigger it.
+    ///
+    /// `warnings` denies every lint that would otherwise warn, and `hugedriver-differences` every
+    /// lint about cells that play differently under hUGEDriver.
+    /// Can be specified several times; `--deny` takes precedence over `--warn`, which takes
+    /// precedence over `--allow`, all of which take precedence over `--lint-config`.
+    #[arg(help_heading = "Lints", short = 'D', long, value_name = "LINT")]
+    deny: Vec<String>,
+    /// Report a lint as a warning.
+    #[arg(help_heading = "Lints", short = 'W', long, value_name = "LINT")]
+    warn: Vec<String>,
+    /// Do not report a lint.
+    #[arg(help_heading = "Lints", short = 'A', long, value_name = "LINT")]
+    allow: Vec<String>,
+    /// Path to a file setting lints' levels, one per line, e.g. `unused-instruments = deny`.
+    #[arg(help_heading = "Lints", long, value_name = "PATH")]
+    lint_config: Option<OsString>,
 
     /// Do not emit stats at the end.
     #[arg(short = 'q', long)]
     quiet: bool,
+    /// Format of the stats.
+    ///
+    /// The JSON output also includes how many bytes each part of the data takes up.
+    #[arg(long, default_value_t, value_name = "FORMAT")]
+    stats_format: StatsFormat,
+    /// Write the stats to this file instead of standard error (even with `--quiet`).
+    #[arg(long, value_name = "PATH")]
+    stats_file: Option<OsString>,
 
     /// Use colours when writing to standard error (errors, stats, etc.)
-    #[arg(long, default_value_t, value_name = "WHEN")]
+    #[arg(long, global = true, default_value_t, value_name = "WHEN")]
     color: CliColorChoice,
+    /// How to report problems found in the songs.
+    #[arg(long, default_value_t, value_name = "FORMAT")]
+    diagnostic_format: DiagnosticFormat,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Merge two versions of a `.uge` file edited from a common one; meant to be used as a git merge driver.
+    ///
+    /// Header fields, instruments, waves, and routines are merged as a whole, patterns cell by cell, and the order row by row.
+    /// If both versions changed the same thing differently, ours is kept, the conflict is reported, and the exit status is non-zero.
+    Merge(MergeArgs),
+    /// Render a `.uge` file to a WAV file, emulating how fortISSimO would play it on a Game Boy.
+    ///
+    /// The song is played until it loops, plus as many extra loops as requested.
+    Render(RenderArgs),
+    /// Log the writes that fortISSimO would make to the APU's registers while playing a `.uge` file.
+    ///
+    /// The log can be compared against a trace of the driver running in an emulator.
+    Trace(TraceArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+struct MergeArgs {
+    /// Path to the common ancestor (git's `%O`).
+    base: OsString,
+    /// Path to our version (git's `%A`); the result is written there, unless `--output` is given.
+    ours: OsString,
+    /// Path to their version (git's `%B`).
+    theirs: OsString,
+    /// Path to write the merged file to, instead of overwriting ours.
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<OsString>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct RenderArgs {
+    /// Path to the `.uge` file to be rendered.
+    input_path: OsString,
+    /// Path to the WAV file to write to.
+    ///
+    /// If omitted, the file will be written to standard output.
+    output_path: Option<OsString>,
+    /// How many times to play the part of the song that loops.
+    #[arg(short, long, default_value_t = 1, value_name = "N")]
+    loops: u32,
+    /// The WAV file's sample rate, in Hz.
+    #[arg(short = 'r', long, default_value_t = 44100, value_name = "HZ")]
+    sample_rate: u32,
+}
+
+#[derive(Debug, Clone, Args)]
+struct TraceArgs {
+    /// Path to the `.uge` file to be played.
+    input_path: OsString,
+    /// Path to the text file to write the log to.
+    ///
+    /// If omitted, the log will be written to standard output.
+    output_path: Option<OsString>,
+    /// How many times to play the part of the song that loops.
+    #[arg(short, long, default_value_t = 1, value_name = "N")]
+    loops: u32,
+    /// Before the given tick, set `hUGE_MutedChannels` to mute the given channels (e.g. `120:1,3`; or `300:` to unmute them all).
+    ///
+    /// Channels muted at tick 0 are already muted when the song is selected.
+    #[arg(long, value_name = "TICK:CHANNELS", value_parser = parse_mute)]
+    mute: Vec<(u64, u8)>,
+    /// Before the given tick, set `hUGE_LoadedWaveID` to `hUGE_NO_WAVE`, like games must do after modifying wave RAM.
+    #[arg(long, value_name = "TICK")]
+    reload_wave: Vec<u64>,
 }
 
 fn main() -> ExitCode {
@@ -117,7 +349,6 @@ fn main() -> ExitCode {
     };
     let stderr = StandardStream::stderr(color_choice);
     let mut stderr = stderr.lock();
-    let input_path: &Path = args.input_path.as_ref();
 
     macro_rules! write_error {
         ($descr:literal $(, $($descr_args:expr),+)? ; $(,)? $inner:literal $(, $($inner_args:expr),+)? $(,)?) => {
@@ -134,66 +365,568 @@ fn main() -> ExitCode {
         };
     }
 
-    let data = match std::fs::read(input_path) {
-        Ok(data) => data,
-        Err(err) => {
-            write_error!("Failed to read file \"{}\": ", input_path.display();
-                "{err}");
+    let input_path = match &args.command {
+        Some(Command::Merge(merge_args)) => {
+            let paths: Vec<&Path> = [&merge_args.base, &merge_args.ours, &merge_args.theirs]
+                .into_iter()
+                .map(AsRef::as_ref)
+                .collect();
+            let mut datas = Vec::with_capacity(paths.len());
+            for path in &paths {
+                match std::fs::read(path) {
+                    Ok(data) => datas.push(data),
+                    Err(err) => {
+                        write_error!("Failed to read file \"{}\": ", path.display();
+                            "{err}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            let mut songs = Vec::with_capacity(paths.len());
+            for (data, path) in datas.iter().zip(&paths) {
+                match uge::parse_song(data) {
+                    Ok(song) => songs.push(song),
+                    Err(err) => {
+                        write_error!("Unable to parse a UGE song from \"{}\": ", path.display();
+                            "{err}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            let (merged, conflicts) = merge::merge(&songs[0], &songs[1], &songs[2]);
+            let output_path: &Path = merge_args
+                .output
+                .as_ref()
+                .unwrap_or(&merge_args.ours)
+                .as_ref();
+            // If the merge picked one side entirely, that file is copied as-is, so that anything
+            // the parser doesn't understand survives as well.
+            let unchanged = (1..=2).find(|&i| merged == songs[i]);
+            let result = match (File::create(output_path), unchanged) {
+                (Ok(mut file), Some(i)) => file.write_all(&datas[i]),
+                (Ok(file), None) => uge::write_song(&merged, BufWriter::new(file)),
+                (Err(err), _) => Err(err),
+            };
+            if let Err(err) = result {
+                write_error!("Failed to write \"{}\": ", output_path.display(); "{err}");
+                return ExitCode::FAILURE;
+            }
+
+            if conflicts.is_empty() {
+                return ExitCode::SUCCESS;
+            }
+            for conflict in &conflicts {
+                print_conflict(&mut stderr, conflict);
+            }
+            write_error!("Found {} conflict{}\n", conflicts.len(), if conflicts.len() == 1 { "" } else { "s" };
+                "Our version was kept for each of them; please check them in hUGETracker");
             return ExitCode::FAILURE;
         }
+        Some(
+            Command::Render(RenderArgs {
+                input_path,
+                output_path,
+                ..
+            })
+            | Command::Trace(TraceArgs {
+                input_path,
+                output_path,
+                ..
+            }),
+        ) => {
+            let input_path: &Path = input_path.as_ref();
+            let data = match std::fs::read(input_path) {
+                Ok(data) => data,
+                Err(err) => {
+                    write_error!("Failed to read file \"{}\": ", input_path.display();
+                        "{err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let song = match uge::parse_song(&data) {
+                Ok(song) => song,
+                Err(err) => {
+                    write_error!("Unable to parse a UGE song from \"{}\": ", input_path.display();
+                        "{err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            // Only errors are reported, since they prevent the song from being played at all.
+            let mut nb_errors = 0;
+            for diagnostic in validate::validate(&song, &LintLevels::default()) {
+                if diagnostic.severity == Severity::Error {
+                    nb_errors += 1;
+                    print_diagnostic(
+                        &mut stderr,
+                        DiagnosticFormat::Human,
+                        input_path,
+                        &diagnostic,
+                    );
+                }
+            }
+            if nb_errors != 0 {
+                write_error!("Found {nb_errors} error{} in the input\n", if nb_errors == 1 { "" } else { "s" };
+                    "The song cannot be played until they are fixed");
+                return ExitCode::FAILURE;
+            }
+
+            let output = match open_output(output_path.as_deref()) {
+                Ok(output) => output,
+                Err(err) => {
+                    write_error!("Failed to open file \"{}\" for writing: ", Path::new(output_path.as_ref().unwrap()).display();
+                        "{err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let res = match &args.command {
+                Some(Command::Render(render_args)) => {
+                    let options = RenderOptions {
+                        sample_rate: render_args.sample_rate,
+                        nb_loops: render_args.loops,
+                    };
+                    render::render(&song, &options, output)
+                }
+                Some(Command::Trace(trace_args)) => {
+                    let mut events: Vec<_> = trace_args
+                        .mute
+                        .iter()
+                        .map(|&(tick, mask)| (tick, trace::Event::SetMutedChannels(mask)))
+                        .chain(
+                            trace_args
+                                .reload_wave
+                                .iter()
+                                .map(|&tick| (tick, trace::Event::InvalidateWave)),
+                        )
+                        .collect();
+                    events.sort_by_key(|(tick, _)| *tick);
+                    let options = TraceOptions {
+                        nb_loops: trace_args.loops,
+                        events,
+                    };
+                    trace::trace(&song, &options).write_text(output)
+                }
+                _ => unreachable!(),
+            };
+            if let Err(err) = res {
+                write_error!("Failed to write the output: "; "{err}");
+                return ExitCode::FAILURE;
+            }
+            return ExitCode::SUCCESS;
+        }
+        None => args
+            .input_path
+            .as_ref()
+            .expect("An input path is required without a subcommand"),
     };
-    let song = match uge::parse_song(&data) {
-        Ok(song) => song,
-        Err(err) => {
-            write_error!("Unable to parse a UGE song from \"{}\": ", input_path.display();
-                "{err}");
+    let input_paths: Vec<&Path> = std::iter::once(input_path)
+        .chain(&args.additional_inputs)
+        .map(AsRef::as_ref)
+        .collect();
+
+    if args.descriptor.len() > input_paths.len() {
+        write_error!("{} descriptors were specified, but only {} input files\n", args.descriptor.len(), input_paths.len();
+            "Please specify at most one descriptor per input file");
+        return ExitCode::FAILURE;
+    }
+    let descriptors: Vec<String> = input_paths
+        .iter()
+        .enumerate()
+        .map(|(i, input_path)| match args.descriptor.get(i) {
+            Some(label) => label.clone(),
+            None => {
+                let stem: &Path = input_path
+                    .file_stem()
+                    .expect("Input file path has no stem?")
+                    .as_ref();
+                stem.display().to_string()
+            }
+        })
+        .collect();
+    for (i, descriptor) in descriptors.iter().enumerate() {
+        if let Some(j) = descriptors[..i]
+            .iter()
+            .position(|other| other == descriptor)
+        {
+            write_error!("\"{}\" and \"{}\" would both use the descriptor \"{descriptor}\"\n", input_paths[j].display(), input_paths[i].display();
+                "Please specify unique descriptors for them using `-d`");
             return ExitCode::FAILURE;
         }
-    };
-    if args.vblank {
-        if song.timer_divider.is_some() {
-            write_error!("Expected \"{}\" to specify VBlank-based playback!\n", input_path.display();
-                "Please uncheck the `Enable timer-based playback` checkbox in the `General` tab, and alter your `F` effects as necessary");
+    }
+
+    if !matches!(args.format, OutputFormat::Bin) {
+        for (is_present, flag) in [
+            (args.base_address.is_some(), "--base-address"),
+            (args.symbol_file.is_some(), "--symbol-file"),
+        ] {
+            if is_present {
+                write_error!("`{flag}` only makes sense with binary output\n";
+                    "Please either remove it, or add `--format bin`");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if matches!(args.format, OutputFormat::Midi) && !args.additional_inputs.is_empty() {
+        write_error!("MIDI output only supports a single song\n";
+            "Please export each song to its own file");
+        return ExitCode::FAILURE;
+    }
+
+    if !matches!(args.format, OutputFormat::Asm) {
+        for (is_present, flag) in [
+            (args.routines, "--routines"),
+            (args.events, "--events"),
+            (args.routine_skeleton, "--routine-skeleton"),
+            (args.timing, "--timing"),
+        ] {
+            if is_present {
+                write_error!("`{flag}` only makes sense with assembly output\n";
+                    "Please either remove it, or use `--format asm`");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if args.routines && !args.additional_inputs.is_empty() {
+        write_error!("`--routines` only supports a single song\n";
+            "Please export each song to its own file");
+        return ExitCode::FAILURE;
+    }
+
+    if !matches!(args.format, OutputFormat::C) && args.catalog_address.is_some() {
+        write_error!("`--catalog-address` only makes sense with C output\n";
+            "Please either remove it, or add `--format c`");
+        return ExitCode::FAILURE;
+    }
+    if args.convert_tempo && !args.vblank && args.timer.is_none() {
+        write_error!("`--convert-tempo` only makes sense with `--vblank` or `--timer`\n";
+            "Please add either of them, to pick the playback method to convert songs to");
+        return ExitCode::FAILURE;
+    }
+    if matches!(args.format, OutputFormat::C) {
+        if args.output_path.is_none() {
+            write_error!("C output cannot be written to standard output\n";
+                "Please specify an output path; the header file will be written next to it");
             return ExitCode::FAILURE;
         }
-    } else if let Some(divider) = args.timer {
-        match song.timer_divider {
+        if let Some(address @ 1..) = args.catalog_address.map(|address| address % 256) {
+            write_error!("The catalog address must be a multiple of 256\n";
+                "Please specify e.g. ${:04x} instead", args.catalog_address.unwrap() - address);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // See https://reproducible-builds.org/specs/source-date-epoch/
+    let timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+        _ if args.no_timestamp => None,
+        Err(_) => Some(Utc::now()),
+        Ok(epoch) => match epoch
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        {
+            Some(timestamp) => Some(timestamp),
             None => {
-                write_error!("Expected \"{}\" to specify timer-based playback!\n", input_path.display();
-                    "Please check the `Enable timer-based playback` checkbox in the `General` tab, set the `Tempo (timer divider)` field to {divider}, and alter your `F` effects as necessary");
+                write_error!("`SOURCE_DATE_EPOCH` is set to \"{epoch}\", which is not a valid timestamp\n";
+                    "Please set it to a number of seconds since the Unix epoch, or unset it");
                 return ExitCode::FAILURE;
             }
-            Some(song_div) => {
-                if song_div != divider {
-                    write_error!("\"{}\" has the wrong timer divider\n", input_path.display();
-                        "Please set the `Tempo (timer divider)` field in the `General` tab to {divider}");
+        },
+    };
+
+    let event_names = match &args.event_names {
+        Some(path) => {
+            let path: &Path = path.as_ref();
+            let names = match std::fs::read_to_string(path) {
+                Ok(names) => names,
+                Err(err) => {
+                    write_error!("Failed to read event names \"{}\": ", path.display(); "{err}");
                     return ExitCode::FAILURE;
                 }
+            };
+            match export::parse_event_names(&names) {
+                Ok(names) => names,
+                Err(err) => {
+                    write_error!("Invalid event names \"{}\": ", path.display(); "{err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => HashMap::new(),
+    };
+
+    let mut lint_levels = LintLevels::default();
+    if let Some(path) = &args.lint_config {
+        let path: &Path = path.as_ref();
+        let config = match std::fs::read_to_string(path) {
+            Ok(config) => config,
+            Err(err) => {
+                write_error!("Failed to read lint config \"{}\": ", path.display(); "{err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if let Err(err) = lint_levels.apply_config(&config) {
+            write_error!("Invalid lint config \"{}\": ", path.display(); "{err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if args.routines {
+        // The routines are exported, and called like under hUGEDriver.
+        lint_levels.set(Lint::IgnoredRoutines, Level::Allow);
+        lint_levels.set(Lint::RoutineNumbers, Level::Allow);
+    }
+    for (names, level) in [
+        (&args.allow, Level::Allow),
+        (&args.warn, Level::Warn),
+        (&args.deny, Level::Deny),
+    ] {
+        for name in names {
+            if let Err(err) = lint_levels.set_by_name(name, level) {
+                write_error!("Invalid lint: "; "{err}");
+                return ExitCode::FAILURE;
             }
         }
     }
 
-    let (optim_results, optim_stats) = optimise::optimise(&song);
+    let mut datas = Vec::with_capacity(input_paths.len());
+    for input_path in &input_paths {
+        match std::fs::read(input_path) {
+            Ok(data) => datas.push(data),
+            Err(err) => {
+                write_error!("Failed to read file \"{}\": ", input_path.display();
+                    "{err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let mut songs = Vec::with_capacity(input_paths.len());
+    let mut nb_errors = 0;
+    for (data, input_path) in datas.iter().zip(&input_paths) {
+        let mut song = match uge::parse_song(data) {
+            Ok(song) => song,
+            Err(err) => {
+                write_error!("Unable to parse a UGE song from \"{}\": ", input_path.display();
+                    "{err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let timer_divider = if args.vblank {
+            Some(None)
+        } else {
+            args.timer.map(Some)
+        };
+        match (timer_divider, song.timer_divider) {
+            (Some(timer_divider), old_divider)
+                if args.convert_tempo && old_divider != timer_divider =>
+            {
+                let (changes, zeroed_effects) = tempo::convert_tempo(&mut song, timer_divider);
+                for change in changes {
+                    let error = change.error(old_divider, timer_divider);
+                    // Only report errors that would show up in the message.
+                    if (error * 1000.0).round() != 0.0 {
+                        print_tempo_change(
+                            &mut stderr,
+                            args.diagnostic_format,
+                            input_path,
+                            &change,
+                            (old_divider, timer_divider),
+                        );
+                    }
+                }
+                for zeroed_effect in &zeroed_effects {
+                    print_zeroed_effect(
+                        &mut stderr,
+                        args.diagnostic_format,
+                        input_path,
+                        zeroed_effect,
+                    );
+                }
+            }
+            (Some(None), Some(_)) => {
+                write_error!("Expected \"{}\" to specify VBlank-based playback!\n", input_path.display();
+                    "Please uncheck the `Enable timer-based playback` checkbox in the `General` tab, and alter your `F` effects as necessary; or pass `--convert-tempo` to have teNOR do it");
+                return ExitCode::FAILURE;
+            }
+            (Some(Some(divider)), None) => {
+                write_error!("Expected \"{}\" to specify timer-based playback!\n", input_path.display();
+                    "Please check the `Enable timer-based playback` checkbox in the `General` tab, set the `Tempo (timer divider)` field to {divider}, and alter your `F` effects as necessary; or pass `--convert-tempo` to have teNOR do it");
+                return ExitCode::FAILURE;
+            }
+            (Some(Some(divider)), Some(song_div)) if song_div != divider => {
+                write_error!("\"{}\" has the wrong timer divider\n", input_path.display();
+                    "Please set the `Tempo (timer divider)` field in the `General` tab to {divider}, or pass `--convert-tempo` to have teNOR convert the song's tempo");
+                return ExitCode::FAILURE;
+            }
+            _ => {}
+        }
+        for diagnostic in validate::validate(&song, &lint_levels) {
+            if diagnostic.severity == Severity::Error {
+                nb_errors += 1;
+            }
+            print_diagnostic(&mut stderr, args.diagnostic_format, input_path, &diagnostic);
+        }
+        songs.push(song);
+    }
+    if nb_errors != 0 {
+        if let DiagnosticFormat::Human = args.diagnostic_format {
+            write_error!("Found {nb_errors} error{} in the input\n", if nb_errors == 1 { "" } else { "s" };
+                "The songs cannot be exported until they are fixed");
+        }
+        return ExitCode::FAILURE;
+    }
+
+    // MIDI files are not meant to be played by the driver, so there is nothing to optimise.
+    if let OutputFormat::Midi = args.format {
+        let output = match open_output(args.output_path.as_deref()) {
+            Ok(output) => output,
+            Err(err) => {
+                write_error!("Failed to open file \"{}\" for writing: ", Path::new(args.output_path.as_ref().unwrap()).display();
+                    "{err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if let Err(err) = midi::write_midi(&songs[0], output) {
+            write_error!("Failed to export: "; "{err}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let (optim_results, optim_stats) = optimise::optimise(
+        &songs,
+        &OptimOptions {
+            search_budget: args.search_budget,
+        },
+    );
 
     for (catalog, name) in [
         (&optim_results.main_cell_catalog, "the main grid"),
         (&optim_results.subpat_cell_catalog, "subpatterns"),
     ] {
         if let nb_unique_cells @ 257.. = catalog.len() {
-            write_error!("The song has {nb_unique_cells} unique cells in {name}, the max is 256!\n" ; "There is not much that can be done, sorry. Try simplifying it?");
+            let subject = if songs.len() > 1 {
+                "The songs have"
+            } else {
+                "The song has"
+            };
+            write_error!("{subject} {nb_unique_cells} unique cells in {name}, the max is 256!\n" ; "There is not much that can be done, sorry. Try simplifying it?");
             return ExitCode::FAILURE;
         }
     }
 
-    export::export(&args, &song, input_path, &optim_results);
+    let options = ExportOptions {
+        descriptors,
+        source_names: input_paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+        timestamp,
+        format: match args.format {
+            OutputFormat::Asm => Format::Asm(AsmOptions {
+                include_path: args.include_path.clone(),
+                section: args
+                    .section_type
+                    .clone()
+                    .map(|kind| (kind, args.section_name.clone())),
+                shared_section: args
+                    .shared_section_type
+                    .clone()
+                    .map(|kind| (kind, args.shared_section_name.clone())),
+                routines: args.routines,
+                event_constants: args.events,
+                event_names,
+                routine_skeleton: args.routine_skeleton,
+                timing_constants: args.timing,
+            }),
+            OutputFormat::Bin => Format::Bin(BinOptions {
+                base_address: args
+                    .base_address
+                    .expect("Binary output requires a base address"),
+            }),
+            OutputFormat::C => Format::C(COptions {
+                catalog_address: args
+                    .catalog_address
+                    .expect("C output requires a catalog address"),
+                // The header is written next to the source (see below).
+                header_name: args.output_path.as_ref().and_then(|path| {
+                    let header_path = Path::new(path).with_extension("h");
+                    Some(header_path.file_name()?.to_string_lossy().into_owned())
+                }),
+            }),
+            OutputFormat::Midi => unreachable!("MIDI output doesn't go through the exporter"),
+        },
+    };
+    let companion_path = match args.format {
+        OutputFormat::Asm | OutputFormat::Midi => None,
+        OutputFormat::Bin => args.symbol_file.as_ref().map(PathBuf::from),
+        OutputFormat::C => args
+            .output_path
+            .as_ref()
+            .map(|path| Path::new(path).with_extension("h")),
+    };
 
-    if !args.quiet {
-        print_stats(
-            &mut stderr,
-            &optim_stats,
-            optim_results.main_cell_catalog.len(),
-            optim_results.subpat_cell_catalog.len(),
-        );
+    let output = match open_output(args.output_path.as_deref()) {
+        Ok(output) => output,
+        Err(err) => {
+            write_error!("Failed to open file \"{}\" for writing: ", Path::new(args.output_path.as_ref().unwrap()).display();
+                "{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = export::export(&songs, &optim_results, &options, output) {
+        write_error!("Failed to export: "; "{err}");
+        return ExitCode::FAILURE;
+    }
+    if let Some(path) = companion_path {
+        let result = match File::create(&path) {
+            Ok(file) => {
+                export::export_companion(&songs, &optim_results, &options, BufWriter::new(file))
+            }
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            write_error!("Failed to write \"{}\": ", path.display();
+                "{err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let timings: Vec<Timing> = songs.iter().map(playback::timing).collect();
+    let write_stats = |output: &mut dyn WriteColor| {
+        let sizes = export::sizes(&songs, &optim_results, &options)?;
+        match args.stats_format {
+            StatsFormat::Human => print_stats(
+                output,
+                &optim_stats,
+                optim_results.main_cell_catalog.len(),
+                optim_results.subpat_cell_catalog.len(),
+                &sizes,
+                &options.descriptors,
+                &timings,
+            ),
+            StatsFormat::Json => writeln!(
+                output,
+                "{}",
+                export::stats_json(&options, &optim_stats, &optim_results, &sizes, &timings)
+            )?,
+        }
+        Ok::<_, ExportError>(())
+    };
+    if let Some(path) = &args.stats_file {
+        let path: &Path = path.as_ref();
+        let result = match File::create(path) {
+            Ok(file) => write_stats(&mut NoColor::new(BufWriter::new(file))),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            write_error!("Failed to write stats to \"{}\": ", path.display(); "{err}");
+            return ExitCode::FAILURE;
+        }
+    } else if !args.quiet {
+        if let Err(err) = write_stats(&mut stderr) {
+            write_error!("Failed to compute stats: "; "{err}");
+            return ExitCode::FAILURE;
+        }
     }
 
     ExitCode::SUCCESS
@@ -220,11 +953,279 @@ impl Display for CliColorChoice {
     }
 }
 
-fn print_stats(
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum StatsFormat {
+    /// Human-readable text.
+    #[default]
+    Human,
+    /// A JSON object, meant for other programs.
+    Json,
+}
+
+impl Display for StatsFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Human => write!(f, "human"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum DiagnosticFormat {
+    /// Human-readable messages.
+    #[default]
+    Human,
+    /// One JSON object per line, meant for other programs.
+    Json,
+}
+
+impl Display for DiagnosticFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Human => write!(f, "human"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum OutputFormat {
+    /// RGBASM source code.
+    #[default]
+    Asm,
+    /// Raw binary data, ready to be `INCBIN`'d or loaded from elsewhere.
+    Bin,
+    /// C source code for GBDK/SDCC; a header file is written alongside it.
+    C,
+    /// Standard MIDI File, to preview the song without hUGETracker.
+    Midi,
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Asm => write!(f, "asm"),
+            Self::Bin => write!(f, "bin"),
+            Self::C => write!(f, "c"),
+            Self::Midi => write!(f, "midi"),
+        }
+    }
+}
+
+/// Opens the file at `path` for writing, or standard output if there is none.
+fn open_output(path: Option<&OsStr>) -> std::io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    })
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    let (digits, radix) = if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        (hex, 16)
+    } else {
+        (s, 10)
+    };
+    u16::from_str_radix(digits, radix)
+        .map_err(|err| format!("{err} (expected an address between $0000 and $FFFF)"))
+}
+
+fn parse_mute(s: &str) -> Result<(u64, u8), String> {
+    let (tick, channels) = s
+        .split_once(':')
+        .ok_or("expected a tick and channels separated by a colon (e.g. `120:1,3`)")?;
+    let tick = tick.parse().map_err(|err| format!("{err} (bad tick)"))?;
+    let mut mask = 0;
+    for channel in channels.split(',').filter(|channel| !channel.is_empty()) {
+        match channel.parse::<u8>() {
+            Ok(channel @ 1..=4) => mask |= 1 << (channel - 1),
+            _ => return Err(format!("\"{channel}\" is not a channel (expected 1 to 4)")),
+        }
+    }
+    Ok((tick, mask))
+}
+
+fn parse_search_budget(s: &str) -> Result<SearchBudget, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, ""),
+    };
+    let amount: u64 = digits
+        .parse()
+        .map_err(|err| format!("{err} (expected e.g. `30s` or `1000000`)"))?;
+    match unit {
+        "" => Ok(SearchBudget::Iterations(amount)),
+        "ms" => Ok(SearchBudget::Time(Duration::from_millis(amount))),
+        "s" => Ok(SearchBudget::Time(Duration::from_secs(amount))),
+        "m" => Ok(SearchBudget::Time(Duration::from_secs(amount * 60))),
+        unit => Err(format!(
+            "unknown unit \"{unit}\" (expected `ms`, `s`, `m`, or none for a number of iterations)"
+        )),
+    }
+}
+
+fn print_conflict(stderr: &mut StandardStreamLock<'_>, conflict: &Conflict) {
+    stderr
+        .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Yellow)))
+        .unwrap();
+    write!(stderr, "conflict: ").unwrap();
+    stderr
+        .set_color(ColorSpec::new().set_bold(true).set_fg(None))
+        .unwrap();
+    write!(stderr, "Both versions changed ").unwrap();
+    stderr.set_color(ColorSpec::new().set_bold(false)).unwrap();
+    writeln!(stderr, "{conflict}").unwrap();
+}
+
+fn print_tempo_change(
     stderr: &mut StandardStreamLock<'_>,
+    format: DiagnosticFormat,
+    input_path: &Path,
+    change: &TempoChange,
+    (old_divider, new_divider): (Option<u8>, Option<u8>),
+) {
+    let message = change.message(old_divider, new_divider);
+    match format {
+        DiagnosticFormat::Human => {
+            stderr
+                .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Yellow)))
+                .unwrap();
+            write!(stderr, "warning: ").unwrap();
+            stderr
+                .set_color(ColorSpec::new().set_bold(true).set_fg(None))
+                .unwrap();
+            writeln!(stderr, "{message}").unwrap();
+            stderr.set_color(ColorSpec::new().set_bold(false)).unwrap();
+            write!(
+                stderr,
+                "    in \"{}\", {} tick{} per row ({:.1} BPM) became {} ({:.1} BPM), used by",
+                input_path.display(),
+                change.old_ticks,
+                if change.old_ticks == 1 { "" } else { "s" },
+                playback::bpm(change.old_ticks, old_divider),
+                change.new_ticks,
+                playback::bpm(change.new_ticks, new_divider),
+            )
+            .unwrap();
+            if change.initial {
+                write!(stderr, " the initial tempo").unwrap();
+                if change.nb_effects != 0 {
+                    write!(stderr, " and").unwrap();
+                }
+            }
+            match change.nb_effects {
+                0 => writeln!(stderr).unwrap(),
+                1 => writeln!(stderr, " 1 `Fxx`").unwrap(),
+                n => writeln!(stderr, " {n} `Fxx`s").unwrap(),
+            }
+        }
+        DiagnosticFormat::Json => writeln!(
+            stderr,
+            "{}",
+            change.to_json(
+                &input_path.display().to_string(),
+                (old_divider, new_divider)
+            ),
+        )
+        .unwrap(),
+    }
+}
+
+fn print_zeroed_effect(
+    stderr: &mut StandardStreamLock<'_>,
+    format: DiagnosticFormat,
+    input_path: &Path,
+    zeroed_effect: &ZeroedEffect,
+) {
+    let message = zeroed_effect.message();
+    match format {
+        DiagnosticFormat::Human => {
+            stderr
+                .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Yellow)))
+                .unwrap();
+            write!(stderr, "warning: ").unwrap();
+            stderr
+                .set_color(ColorSpec::new().set_bold(true).set_fg(None))
+                .unwrap();
+            writeln!(stderr, "{message}").unwrap();
+            stderr.set_color(ColorSpec::new().set_bold(false)).unwrap();
+            writeln!(
+                stderr,
+                "    in \"{}\", at pattern {}, row {}",
+                input_path.display(),
+                zeroed_effect.pattern_id,
+                zeroed_effect.row,
+            )
+            .unwrap();
+        }
+        DiagnosticFormat::Json => writeln!(
+            stderr,
+            "{}",
+            zeroed_effect.to_json(&input_path.display().to_string()),
+        )
+        .unwrap(),
+    }
+}
+
+fn print_diagnostic(
+    stderr: &mut StandardStreamLock<'_>,
+    format: DiagnosticFormat,
+    input_path: &Path,
+    diagnostic: &Diagnostic,
+) {
+    let effect = diagnostic
+        .effect
+        .map(|(effect_code, effect_param)| format!("{:X}{effect_param:02X}", effect_code as u8));
+    let lint = diagnostic.problem.lint();
+    match format {
+        DiagnosticFormat::Human => {
+            let color = match diagnostic.severity {
+                Severity::Warning => Color::Yellow,
+                Severity::Error => Color::Red,
+            };
+            stderr
+                .set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)))
+                .unwrap();
+            write!(stderr, "{}: ", diagnostic.severity).unwrap();
+            stderr
+                .set_color(ColorSpec::new().set_bold(true).set_fg(None))
+                .unwrap();
+            write!(stderr, "{}", diagnostic.problem).unwrap();
+            if let Some(effect) = effect {
+                write!(stderr, " (effect {effect})").unwrap();
+            }
+            writeln!(stderr).unwrap();
+            stderr.set_color(ColorSpec::new().set_bold(false)).unwrap();
+            write!(
+                stderr,
+                "    in \"{}\", at {}",
+                input_path.display(),
+                diagnostic.location,
+            )
+            .unwrap();
+            if let Some(lint) = lint {
+                write!(stderr, " [{lint}]").unwrap();
+            }
+            writeln!(stderr).unwrap();
+        }
+        DiagnosticFormat::Json => writeln!(
+            stderr,
+            "{}",
+            diagnostic.to_json(&input_path.display().to_string()),
+        )
+        .unwrap(),
+    }
+}
+
+fn print_stats(
+    stderr: &mut dyn WriteColor,
     optim_stats: &optimise::OptimStats,
     nb_unique_main_cells: usize,
     nb_unique_sub_cells: usize,
+    sizes: &Sizes,
+    descriptors: &[String],
+    timings: &[Timing],
 ) {
     stderr
         .set_color(ColorSpec::new().set_underline(true))
@@ -246,7 +1247,7 @@ fn print_stats(
         .unwrap();
     writeln!(stderr, "teNOR optimisation stats:").unwrap();
 
-    let report = |stderr: &mut StandardStreamLock<'_>, verb, how_many, what, bytes_saved| {
+    let report = |stderr: &mut dyn WriteColor, verb, how_many, what, bytes_saved| {
         let mut color_spec = ColorSpec::new();
         if bytes_saved == 0 {
             color_spec.set_dimmed(true).set_italic(true);
@@ -273,6 +1274,13 @@ fn print_stats(
         "unreachable rows",
         optim_stats.saved_bytes_trimmed_rows(),
     );
+    report(
+        stderr,
+        "Deduplicating",
+        optim_stats.deduplicated_patterns,
+        "patterns",
+        optim_stats.saved_bytes_deduplicated_patterns(),
+    );
     report(
         stderr,
         "Overlapping",
@@ -329,12 +1337,95 @@ fn print_stats(
         stderr.set_color(ColorSpec::new().set_bold(true)).unwrap();
         writeln!(stderr, "{} bytes", -optim_stats.saved_bytes_catalog).unwrap();
     }
-    write!(
+    if optim_stats.canonicalised_cells != 0 {
+        stderr.set_color(&ColorSpec::new()).unwrap();
+        write!(
+            stderr,
+            "\t...merging {} equivalent cells beforehand having saved ",
+            optim_stats.canonicalised_cells
+        )
+        .unwrap();
+        stderr.set_color(ColorSpec::new().set_bold(true)).unwrap();
+        write!(
+            stderr,
+            "{} bytes",
+            optim_stats.saved_bytes_canonicalised_cells()
+        )
+        .unwrap();
+        stderr.set_color(&ColorSpec::new()).unwrap();
+        writeln!(stderr, " of that").unwrap();
+    }
+    writeln!(
         stderr,
         "Total: {} bytes saved",
         optim_stats.total_saved_bytes()
     )
     .unwrap();
+
+    stderr
+        .set_color(ColorSpec::new().set_underline(true))
+        .unwrap();
+    writeln!(stderr, "Output size:").unwrap();
+    let sum = |size_of: fn(&SongSizes) -> usize| sizes.songs.iter().map(size_of).sum();
+    for (what, size) in [
+        ("Headers", sum(|song| song.header)),
+        ("Order \"columns\"", sum(|song| song.order_columns)),
+        ("\"Main\" row pool", sizes.main_row_pool),
+        ("Subpattern row pool", sizes.subpat_row_pool),
+        ("\"Main\" cell catalog", sizes.main_catalog),
+        ("Subpattern cell catalog", sizes.subpat_catalog),
+        ("Instruments", sum(|song| song.instruments)),
+        ("Waves", sum(|song| song.waves)),
+    ] {
+        stderr.set_color(&ColorSpec::new()).unwrap();
+        writeln!(stderr, "\t{what}: {size} bytes").unwrap();
+    }
+    write!(stderr, "\tAlignment padding: {} bytes", sizes.padding).unwrap();
+    if sizes.reclaimed_padding != 0 {
+        write!(
+            stderr,
+            " ({} more were avoided by moving instruments and waves into it)",
+            sizes.reclaimed_padding
+        )
+        .unwrap();
+    }
+    writeln!(stderr).unwrap();
+    stderr.set_color(ColorSpec::new().set_bold(true)).unwrap();
+    writeln!(stderr, "Total: {} bytes", sizes.total).unwrap();
+
+    stderr
+        .set_color(ColorSpec::new().set_underline(true))
+        .unwrap();
+    writeln!(stderr, "Timing:").unwrap();
     stderr.set_color(&ColorSpec::new()).unwrap();
-    writeln!(stderr, " (give or take a few.)").unwrap();
+    for (descriptor, timing) in descriptors.iter().zip(timings) {
+        // Timer-based songs' durations are also given in frames, for games that count those, and
+        // as they would be with VBlank-based playback.
+        let duration = |nb_ticks| match timing.timer_divider {
+            None => format!("{nb_ticks} ticks ({:.2} s)", timing.seconds(nb_ticks, None)),
+            Some(divider) => format!(
+                "{nb_ticks} ticks ({:.2} s, {} frames; {:.2} s on VBlank)",
+                timing.seconds(nb_ticks, Some(divider)),
+                timing.frames(nb_ticks),
+                timing.seconds(nb_ticks, None),
+            ),
+        };
+        let tempo = match timing.timer_divider {
+            None => format!("{:.1} BPM", timing.bpm(None)),
+            Some(divider) => format!(
+                "{:.1} BPM ({:.1} BPM on VBlank)",
+                timing.bpm(Some(divider)),
+                timing.bpm(None),
+            ),
+        };
+        writeln!(
+            stderr,
+            "\t{descriptor}: {} of intro, then {} looping from order row {}, row {} at {tempo}",
+            duration(timing.intro_ticks),
+            duration(timing.loop_ticks),
+            timing.loop_order_idx,
+            timing.loop_row,
+        )
+        .unwrap();
+    }
 }
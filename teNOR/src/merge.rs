@@ -0,0 +1,290 @@
+//! Merges two versions of a song that were both edited from a common ancestor ("three-way merge").
+//!
+//! `.uge` files are binary, so version control systems can't merge them on their own; this merges
+//! them piece by piece instead: each header field, instrument, wave, and routine as a whole, but
+//! patterns cell by cell, and the order row by row.
+//! A piece that only one side changed gets that side's version; if both sides changed it
+//! differently, that's a conflict, and ours is kept.
+
+use std::fmt::Display;
+
+use crate::{
+    optimise::InstrKind,
+    song::{Pattern, PatternCell, Song},
+};
+
+/// Merges `ours` and `theirs`, which were both derived from `base`.
+///
+/// Returns the merged song, and the conflicts, in the order in which the song stores them.
+pub fn merge<'input>(
+    base: &Song<'input>,
+    ours: &Song<'input>,
+    theirs: &Song<'input>,
+) -> (Song<'input>, Vec<Conflict>) {
+    let mut conflicts = vec![];
+    let mut merged = ours.clone();
+
+    macro_rules! merge_field {
+        ($field:ident, $name:literal) => {
+            match merge_one(&base.$field, &ours.$field, &theirs.$field) {
+                Some(value) => merged.$field = value.clone(),
+                None => conflicts.push(Conflict::Field($name)),
+            }
+        };
+    }
+    merge_field!(name, "name");
+    merge_field!(artist, "artist");
+    merge_field!(comment, "comment");
+    merge_field!(ticks_per_row, "ticks per row");
+    // hUGETracker keeps the divider even while it's disabled, so both go together.
+    match merge_one(
+        &(base.timer_divider, base.leftovers.timer_divider),
+        &(ours.timer_divider, ours.leftovers.timer_divider),
+        &(theirs.timer_divider, theirs.leftovers.timer_divider),
+    ) {
+        Some(&(timer_divider, leftover)) => {
+            merged.timer_divider = timer_divider;
+            merged.leftovers.timer_divider = leftover;
+        }
+        None => conflicts.push(Conflict::Field("timer divider")),
+    }
+
+    for (kind, instruments, base_instrs, our_instrs, their_instrs) in [
+        (
+            InstrKind::Duty,
+            &mut merged.instruments.duty,
+            &base.instruments.duty,
+            &ours.instruments.duty,
+            &theirs.instruments.duty,
+        ),
+        (
+            InstrKind::Wave,
+            &mut merged.instruments.wave,
+            &base.instruments.wave,
+            &ours.instruments.wave,
+            &theirs.instruments.wave,
+        ),
+        (
+            InstrKind::Noise,
+            &mut merged.instruments.noise,
+            &base.instruments.noise,
+            &ours.instruments.noise,
+            &theirs.instruments.noise,
+        ),
+    ] {
+        for (i, instrument) in instruments.iter_mut().enumerate() {
+            match merge_one(&base_instrs[i], &our_instrs[i], &their_instrs[i]) {
+                Some(instr) => *instrument = instr.clone(),
+                None => conflicts.push(Conflict::Instrument {
+                    kind,
+                    instrument: i as u8 + 1,
+                }),
+            }
+        }
+    }
+
+    for (wave_id, wave) in merged.waves.iter_mut().enumerate() {
+        match merge_one(
+            &base.waves[wave_id],
+            &ours.waves[wave_id],
+            &theirs.waves[wave_id],
+        ) {
+            Some(merged_wave) => *wave = *merged_wave,
+            None => conflicts.push(Conflict::Wave { wave_id }),
+        }
+    }
+
+    // hUGETracker creates patterns as needed, so one side may have more than the other; missing
+    // patterns are treated as blank, which is also what the parser fills gaps with.
+    let blank: Pattern = [Default::default(); 64];
+    let nb_patterns = [base, ours, theirs]
+        .iter()
+        .map(|song| song.patterns.len())
+        .max()
+        .unwrap();
+    merged.patterns.resize(nb_patterns, blank);
+    // Whether each pattern is in the file can't conflict, since there are only two possibilities.
+    let is_present = |song: &Song, pattern_id| {
+        pattern_id < song.patterns.len() && !song.leftovers.missing_patterns.contains(&pattern_id)
+    };
+    merged.leftovers.missing_patterns = (0..nb_patterns)
+        .filter(|&pattern_id| {
+            let [base, ours, theirs] =
+                [base, ours, theirs].map(|song| is_present(song, pattern_id));
+            !*merge_one(&base, &ours, &theirs).unwrap()
+        })
+        .collect();
+    for (pattern_id, pattern) in merged.patterns.iter_mut().enumerate() {
+        let [base_pattern, our_pattern, their_pattern] =
+            [base, ours, theirs].map(|song| song.patterns.get(pattern_id).unwrap_or(&blank));
+        for (row, cell) in pattern.iter_mut().enumerate() {
+            let versions = [base_pattern[row], our_pattern[row], their_pattern[row]];
+            match merge_one(&versions[0], &versions[1], &versions[2]) {
+                Some(merged_cell) => *cell = *merged_cell,
+                None => conflicts.push(Conflict::Cell {
+                    pattern_id,
+                    row,
+                    versions,
+                }),
+            }
+        }
+    }
+
+    // Order rows are compared by index; rows that end up missing (because a side deleted them)
+    // are dropped.
+    let nb_orders = [base, ours, theirs]
+        .iter()
+        .map(|song| song.order_matrix.len())
+        .max()
+        .unwrap();
+    merged.order_matrix = (0..nb_orders)
+        .filter_map(|order_idx| {
+            let versions =
+                [base, ours, theirs].map(|song| song.order_matrix.get(order_idx).copied());
+            match merge_one(&versions[0], &versions[1], &versions[2]) {
+                Some(row) => *row,
+                None => {
+                    conflicts.push(Conflict::OrderRow {
+                        order_idx,
+                        versions,
+                    });
+                    versions[1]
+                }
+            }
+        })
+        .collect();
+
+    for (routine_id, routine) in merged.routines.iter_mut().enumerate() {
+        match merge_one(
+            &base.routines[routine_id],
+            &ours.routines[routine_id],
+            &theirs.routines[routine_id],
+        ) {
+            Some(merged_routine) => *routine = merged_routine.clone(),
+            None => conflicts.push(Conflict::Routine { routine_id }),
+        }
+    }
+
+    (merged, conflicts)
+}
+
+/// Returns the merged value, or `None` if both sides changed it differently.
+fn merge_one<'a, T: PartialEq>(base: &'a T, ours: &'a T, theirs: &'a T) -> Option<&'a T> {
+    if ours == theirs || theirs == base {
+        Some(ours)
+    } else if ours == base {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+/// Something that both sides changed differently; all indices are 0-based, except instruments'
+/// (like in hUGETracker).
+#[derive(Debug, Clone)]
+pub enum Conflict {
+    /// One of the song's general settings.
+    Field(&'static str),
+    Instrument {
+        kind: InstrKind,
+        instrument: u8,
+    },
+    Wave {
+        wave_id: usize,
+    },
+    Cell {
+        pattern_id: usize,
+        row: usize,
+        /// Base, ours, theirs.
+        versions: [PatternCell; 3],
+    },
+    OrderRow {
+        order_idx: usize,
+        /// Base, ours, theirs; `None` if that version doesn't have the row.
+        versions: [Option<[usize; 4]>; 3],
+    },
+    Routine {
+        routine_id: usize,
+    },
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Field(name) => write!(f, "the song's {name}"),
+            Self::Instrument { kind, instrument } => {
+                let kind = match kind {
+                    InstrKind::Duty => "duty",
+                    InstrKind::Wave => "wave",
+                    InstrKind::Noise => "noise",
+                };
+                write!(f, "{kind} instrument {instrument}")
+            }
+            Self::Wave { wave_id } => write!(f, "wave {wave_id}"),
+            Self::Cell {
+                pattern_id,
+                row,
+                versions: [base, ours, theirs],
+            } => write!(
+                f,
+                "pattern {pattern_id}, row {row}: `{ours}` in ours, `{theirs}` in theirs (was `{base}`)"
+            ),
+            Self::OrderRow {
+                order_idx,
+                versions: [base, ours, theirs],
+            } => {
+                let row = |row: &Option<[usize; 4]>| match row {
+                    Some([ch1, ch2, ch3, ch4]) => format!("`{ch1} {ch2} {ch3} {ch4}`"),
+                    None => "absent".into(),
+                };
+                write!(
+                    f,
+                    "order row {order_idx}: {} in ours, {} in theirs (was {})",
+                    row(ours),
+                    row(theirs),
+                    row(base),
+                )
+            }
+            Self::Routine { routine_id } => write!(f, "routine {routine_id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uge::{parse_song, test_song, write_song};
+
+    fn write(song: &Song) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_song(song, &mut bytes).expect("Writing to a `Vec` shouldn't fail");
+        bytes
+    }
+
+    #[test]
+    fn unchanged() {
+        let bytes = write(&test_song());
+        let base = parse_song(&bytes).expect("Test song should parse");
+
+        let (merged, conflicts) = merge(&base, &base, &base);
+        assert!(conflicts.is_empty());
+        assert_eq!(write(&merged), bytes);
+    }
+
+    #[test]
+    fn changed_on_one_side() {
+        let bytes = write(&test_song());
+        let base = parse_song(&bytes).expect("Test song should parse");
+        let mut theirs = base.clone();
+        theirs.patterns[0][0].note = crate::song::Note::C_5;
+        theirs.instruments.duty[1].subpattern = Some(Default::default());
+        theirs.timer_divider = Some(theirs.leftovers.timer_divider);
+
+        let (merged, conflicts) = merge(&base, &base, &theirs);
+        assert!(conflicts.is_empty());
+        assert_eq!(merged, theirs);
+        let (merged, conflicts) = merge(&base, &theirs, &base);
+        assert!(conflicts.is_empty());
+        assert_eq!(write(&merged), write(&theirs));
+    }
+}
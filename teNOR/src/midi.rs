@@ -0,0 +1,160 @@
+//! Writes songs as Standard MIDI Files, to listen to them without hUGETracker.
+//!
+//! This is only meant as a preview: only the notes and their timing are exported, and instruments
+//! are only told apart by their program number, since General MIDI has nothing resembling them.
+//! Effects other than those affecting timing (`Bxx`, `Dxx`, `Fxx`, note delays and cuts) are ignored.
+
+use std::io::{self, Write};
+
+use crate::{
+    playback::{self, PlayedRow},
+    song::{EffectId, Note, Song},
+};
+
+/// The MIDI note that hUGETracker's `C-3` corresponds to.
+const MIDI_C_3: u8 = 36;
+const VELOCITY: u8 = 100;
+
+/// Writes a format 1 MIDI file, with a "conductor" track followed by one track per channel.
+///
+/// One MIDI tick is one driver tick, and one quarter note is 4 rows at the song's initial tempo.
+/// The part that loops is delimited by `loopStart` and `loopEnd` markers.
+pub fn write_midi(song: &Song, mut output: impl Write) -> io::Result<()> {
+    let playthrough = playback::play_through(song);
+    let end = playthrough.ticks_before(playthrough.rows.len());
+    let ticks_per_quarter = 4 * playthrough.rows[0].nb_ticks;
+    let us_per_quarter = (f64::from(ticks_per_quarter) * 1_000_000.0
+        / playback::tick_rate(song.timer_divider))
+    .round()
+    .min(f64::from(0xFF_FFFF)) as u32;
+
+    output.write_all(b"MThd")?;
+    output.write_all(&6u32.to_be_bytes())?;
+    output.write_all(&1u16.to_be_bytes())?; // Format 1: several tracks, played simultaneously.
+    output.write_all(&5u16.to_be_bytes())?;
+    output.write_all(&ticks_per_quarter.to_be_bytes())?;
+
+    let mut conductor = Track::default();
+    conductor.meta(0, 0x03, song.name.as_bytes());
+    conductor.meta(0, 0x51, &us_per_quarter.to_be_bytes()[1..]);
+    conductor.meta(
+        playthrough.ticks_before(playthrough.loop_start),
+        0x06,
+        b"loopStart",
+    );
+    conductor.meta(end, 0x06, b"loopEnd");
+    conductor.write(&mut output, end)?;
+
+    for channel in 0..4 {
+        channel_track(song, &playthrough.rows, channel).write(&mut output, end)?;
+    }
+    output.flush()
+}
+
+fn channel_track(song: &Song, rows: &[PlayedRow], channel: usize) -> Track {
+    let mut track = Track::default();
+    let name = format!("CH{}", channel + 1);
+    track.meta(0, 0x03, name.as_bytes());
+    let status = |kind: u8| kind | channel as u8;
+
+    let mut time = 0;
+    let mut program = None;
+    // The note that the channel is currently playing, if any; and the last note it was given,
+    // which a note delay on an empty row plays again.
+    let mut playing = None;
+    let mut last_note = None;
+    for row in rows {
+        let cell = &song.patterns[song.order_matrix[row.order_idx][channel]][row.row];
+        let delay = match cell.effect_code {
+            EffectId::NoteDelay => Some(u64::from(cell.effect_param)),
+            _ => None,
+        };
+
+        let note = match cell.note {
+            Note::None if delay.is_some() => last_note,
+            Note::None => None,
+            note => Some(MIDI_C_3 + note as u8),
+        };
+        // A delay longer than the row means that the note is never played.
+        if let Some(note) = note.filter(|_| delay.unwrap_or(0) < u64::from(row.nb_ticks)) {
+            let start = time + delay.unwrap_or(0);
+            if let Some(prev) = playing.take() {
+                track.event(start, &[status(0x80), prev, 0]);
+            }
+            if cell.instrument != 0 && program != Some(cell.instrument) {
+                track.event(start, &[status(0xC0), cell.instrument - 1]);
+                program = Some(cell.instrument);
+            }
+            track.event(start, &[status(0x90), note, VELOCITY]);
+            playing = Some(note);
+            last_note = Some(note);
+        }
+
+        if cell.effect_code == EffectId::NoteCut && u16::from(cell.effect_param) < row.nb_ticks {
+            if let Some(prev) = playing.take() {
+                track.event(
+                    time + u64::from(cell.effect_param),
+                    &[status(0x80), prev, 0],
+                );
+            }
+        }
+
+        time += u64::from(row.nb_ticks);
+    }
+    if let Some(prev) = playing {
+        track.event(time, &[status(0x80), prev, 0]);
+    }
+
+    track
+}
+
+/// A MIDI track being built; events are kept sorted by time, those at the same time being kept in
+/// the order they were added.
+#[derive(Debug, Default)]
+struct Track(Vec<(u64, Vec<u8>)>);
+
+impl Track {
+    fn event(&mut self, time: u64, bytes: &[u8]) {
+        let idx = self.0.partition_point(|(other, _)| *other <= time);
+        self.0.insert(idx, (time, bytes.to_vec()));
+    }
+
+    fn meta(&mut self, time: u64, kind: u8, data: &[u8]) {
+        let mut bytes = vec![0xFF, kind];
+        write_var_len(&mut bytes, data.len() as u64);
+        bytes.extend_from_slice(data);
+        self.event(time, &bytes);
+    }
+
+    fn write(mut self, output: &mut dyn Write, end: u64) -> io::Result<()> {
+        self.meta(end, 0x2F, &[]); // End of track.
+
+        let mut data = vec![];
+        let mut time = 0;
+        for (event_time, bytes) in &self.0 {
+            write_var_len(&mut data, event_time - time);
+            data.extend_from_slice(bytes);
+            time = *event_time;
+        }
+
+        output.write_all(b"MTrk")?;
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MIDI track is too long"))?;
+        output.write_all(&len.to_be_bytes())?;
+        output.write_all(&data)
+    }
+}
+
+/// Appends a MIDI "variable-length quantity": 7 bits per byte, most significant first, with bit 7
+/// set on all bytes but the last.
+fn write_var_len(bytes: &mut Vec<u8>, value: u64) {
+    let mut shift = 63 / 7 * 7;
+    while shift != 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift != 0 {
+        bytes.push((value >> shift) as u8 & 0x7F | 0x80);
+        shift -= 7;
+    }
+    bytes.push(value as u8 & 0x7F);
+}
@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+
+use crate::{song::EffectId, LAST_NOTE};
+
+use super::{Cell, CellFirstHalf, Effect, InstrKind, Note, OptimStats, PatternId, PatternPool};
+
+/// Rewrites cells that the driver plays identically into a single form, so that they share a
+/// catalog entry (and so that more patterns can be deduplicated or overlapped).
+///
+/// Only rewrites that cannot change playback are performed; notably, effects whose outcome depends
+/// on the channel's state (e.g. a note cut on a channel that may or may not be playing anything)
+/// are left alone, since that state can't be known without playing the song.
+pub(super) fn canonicalise_cells(patterns: &mut PatternPool, stats: &mut OptimStats) {
+    let nb_unique_cells_before = count_unique_cells(patterns);
+
+    for ((_, id), pattern) in patterns.iter_mut() {
+        let (PatternId::Pattern(kind, _) | PatternId::Subpattern(kind, _)) = *id;
+        for row in &mut pattern.0 {
+            row.cell = canonical(row.cell, kind);
+        }
+    }
+
+    let nb_unique_cells_after = count_unique_cells(patterns);
+    stats.canonicalised_cells = (0..2)
+        .map(|catalog| nb_unique_cells_before[catalog] - nb_unique_cells_after[catalog])
+        .sum();
+}
+
+fn canonical(Cell(first_half, effect): Cell, kind: InstrKind) -> Cell {
+    let blank = Effect {
+        id: EffectId::Arpeggio,
+        param: 0,
+    };
+
+    match first_half {
+        CellFirstHalf::Pattern { note, instrument } => {
+            let effect = match effect {
+                // The note is (re)played on tick 0, so the channel is already at its period, and
+                // "sliding" by 0 changes nothing. (Unlike on a row without a note, where this would
+                // e.g. undo a vibrato's offset.) CH4 is excluded, since it would glitch out anyway.
+                Effect {
+                    id: EffectId::PortaUp | EffectId::PortaDown,
+                    param: 0,
+                } if note != Note::None && kind != InstrKind::Noise => blank,
+                // All of these encode to the same byte (see `Cell::first_byte`).
+                Effect {
+                    id: EffectId::SetVol,
+                    param: 0x10..=0x70,
+                } if effect.param & 0x0F == 0 => Effect {
+                    id: EffectId::SetVol,
+                    param: 0x80,
+                },
+                _ => effect,
+            };
+            // The instrument is only applied when a note is played; on a row without a note, only
+            // a note delay plays one (the channel's previous note).
+            let instrument = if note == Note::None && effect.id != EffectId::NoteDelay {
+                0
+            } else {
+                instrument
+            };
+            Cell(CellFirstHalf::Pattern { note, instrument }, effect)
+        }
+
+        CellFirstHalf::Subpattern {
+            offset,
+            next_row_idx,
+        } => {
+            // Offsets that are too high are ignored altogether.
+            let offset = if offset >= LAST_NOTE {
+                Note::None as u8
+            } else {
+                offset
+            };
+            let effect = match effect.id {
+                // These aren't supported in subpatterns, and do nothing.
+                EffectId::TonePorta
+                | EffectId::Vibrato
+                | EffectId::PosJump
+                | EffectId::PatternBreak
+                | EffectId::NoteCut
+                | EffectId::SetTempo => blank,
+                _ => effect,
+            };
+            Cell(
+                CellFirstHalf::Subpattern {
+                    offset,
+                    next_row_idx,
+                },
+                effect,
+            )
+        }
+    }
+}
+
+/// Counts the cells that would end up in the catalogs; unreachable ones don't.
+/// Patterns' and subpatterns' cells go into separate catalogs, so each is counted on its own.
+fn count_unique_cells(patterns: &PatternPool) -> [usize; 2] {
+    let mut catalogs: [HashSet<Cell>; 2] = Default::default();
+    for row in patterns
+        .values()
+        .flat_map(|pattern| &pattern.0)
+        .filter(|row| row.reachable)
+    {
+        let catalog = usize::from(matches!(row.cell.0, CellFirstHalf::Subpattern { .. }));
+        catalogs[catalog].insert(row.cell);
+    }
+    catalogs.map(|cells| cells.len())
+}
@@ -4,10 +4,7 @@ use super::*;
 pub struct Cell(pub CellFirstHalf, pub Effect);
 
 impl Cell {
-    // TODO: add a "validation" function, and report invalid cells during export.
-    //       toneporta requires a note
-    //       pos_jump's arg must be non-zero and in-bounds
-    //       pattern break: same
+    // These assume that the cell's params are valid (see `crate::validate`).
 
     pub fn first_byte(&self) -> u8 {
         match self.1 {
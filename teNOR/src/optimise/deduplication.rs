@@ -0,0 +1,205 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use super::{OptimStats, OptimisedPattern, PatternId, PatternKey, PatternPool};
+
+/// Maps patterns that have been merged into another one, to that pattern and the row they begin at.
+/// Only "root" patterns (i.e. that haven't been merged into any other) are ever pointed to.
+pub(super) type Aliases = HashMap<PatternKey, (PatternKey, usize)>;
+
+/// Finds patterns that can be stored entirely within another (either because they are identical,
+/// or because they fit somewhere in the middle of it), and merges them into that other pattern.
+///
+/// Merged patterns are kept in the pool, since their reachability info is still useful to check
+/// the output's validity, but they must not be given to the overlapping pass.
+pub(super) fn deduplicate_patterns(patterns: &mut PatternPool, stats: &mut OptimStats) -> Aliases {
+    let mut merged_into: Aliases = HashMap::new();
+
+    // Identical patterns are common (starting with the copies made for each instrument kind), and
+    // can be found without comparing every pair; only the first of each needs to be searched for.
+    let mut first_copies = HashMap::new();
+    for (&key, pattern) in patterns.iter() {
+        // Unreachable rows are left out, since they can't make patterns differ.
+        let rows: Vec<_> = pattern
+            .0
+            .iter()
+            .map(|row| row.reachable.then_some(row.cell))
+            .collect();
+        match first_copies.entry((is_subpattern(&key), rows)) {
+            Entry::Vacant(entry) => {
+                entry.insert(key);
+            }
+            Entry::Occupied(entry) => {
+                count_merge(stats, key, *entry.get(), 0, pattern.0.len());
+                merged_into.insert(key, (*entry.get(), 0));
+            }
+        }
+    }
+
+    // Shorter patterns are more likely to fit inside longer ones, so try them first; but longer
+    // patterns may then be merged as well, carrying the shorter ones along.
+    let mut keys: Vec<PatternKey> = patterns
+        .keys()
+        .filter(|key| !merged_into.contains_key(key))
+        .cloned()
+        .collect();
+    keys.sort_by_key(|key| std::cmp::Reverse(patterns[key].0.len())); // Stable, so ties stay in key order.
+
+    for (i, &key) in keys.iter().enumerate().rev() {
+        let pattern = &patterns[&key];
+        let Some((container_key, start_row_idx)) = keys
+            .iter()
+            .enumerate()
+            .filter(|&(j, other_key)| j != i && !merged_into.contains_key(other_key))
+            // Subpatterns go into a separate row pool, so they can't be merged with patterns.
+            .filter(|(_, other_key)| is_subpattern(other_key) == is_subpattern(&key))
+            .find_map(|(_, &other_key)| {
+                find_in(&patterns[&other_key], pattern).map(|start| (other_key, start))
+            })
+        else {
+            continue;
+        };
+
+        // Rows that aren't reachable in the container may be in the merged pattern.
+        let pattern = pattern.clone();
+        let container = patterns.get_mut(&container_key).unwrap();
+        for (row, merged_row) in container.0[start_row_idx..].iter_mut().zip(&pattern.0) {
+            if merged_row.reachable {
+                *row = *merged_row;
+            }
+        }
+
+        count_merge(stats, key, container_key, start_row_idx, pattern.0.len());
+        merged_into.insert(key, (container_key, start_row_idx));
+    }
+
+    // Patterns that received others may have been merged themselves afterwards; follow those chains.
+    merged_into
+        .keys()
+        .map(|&key| {
+            let (mut root, mut start_row_idx) = merged_into[&key];
+            while let Some(&(container_key, ofs)) = merged_into.get(&root) {
+                root = container_key;
+                start_row_idx += ofs;
+            }
+            (key, (root, start_row_idx))
+        })
+        .collect()
+}
+
+fn count_merge(
+    stats: &mut OptimStats,
+    key: PatternKey,
+    container_key: PatternKey,
+    start_row_idx: usize,
+    nb_rows: usize,
+) {
+    // `collect_patterns` duplicates patterns across instrument kinds, and counts those copies as
+    // wasted; merging such a copy back into its original means it isn't wasted anymore.
+    match (key, container_key) {
+        (
+            (song_idx, PatternId::Pattern(_, id)),
+            (container_song_idx, PatternId::Pattern(_, container_id)),
+        ) if song_idx == container_song_idx && id == container_id && start_row_idx == 0 => {
+            stats.duplicated_patterns -= 1;
+        }
+        _ => {
+            stats.deduplicated_patterns += 1;
+            stats.deduplicated_rows += nb_rows;
+        }
+    }
+}
+
+fn is_subpattern(key: &PatternKey) -> bool {
+    matches!(key.1, PatternId::Subpattern(..))
+}
+
+/// Returns the index of the first row in `container` at which `pattern` could begin.
+fn find_in(container: &OptimisedPattern, pattern: &OptimisedPattern) -> Option<usize> {
+    let nb_candidates = (container.0.len() + 1).checked_sub(pattern.0.len())?;
+    (0..nb_candidates).find(|&start_row_idx| {
+        container.0[start_row_idx..]
+            .iter()
+            .zip(&pattern.0)
+            .all(|(row, other)| row.can_overlap_with(other))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        optimise::{AnnotatedCell, Cell, CellFirstHalf, Effect, InstrKind},
+        song::{EffectId, Note},
+    };
+
+    fn pattern(rows: &[(Note, bool)]) -> OptimisedPattern {
+        OptimisedPattern(
+            rows.iter()
+                .map(|&(note, reachable)| AnnotatedCell {
+                    reachable,
+                    cell: Cell(
+                        CellFirstHalf::Pattern {
+                            note,
+                            instrument: 1,
+                        },
+                        Effect {
+                            id: EffectId::Arpeggio,
+                            param: 0,
+                        },
+                    ),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn duplicates_and_contained_patterns() {
+        let rows = [(Note::C_5, true), (Note::D_5, true), (Note::E_5, true)];
+        let container = (0, PatternId::Pattern(InstrKind::Duty, 1));
+        let original = (0, PatternId::Pattern(InstrKind::Duty, 0));
+        let kind_copy = (0, PatternId::Pattern(InstrKind::Wave, 0));
+        let other_song_copy = (1, PatternId::Pattern(InstrKind::Duty, 2));
+        // Only differs from the others in a row that is never played.
+        let partly_reachable = (1, PatternId::Pattern(InstrKind::Noise, 5));
+        let subpattern = (0, PatternId::Subpattern(InstrKind::Duty, 1));
+        let mut patterns = PatternPool::from([
+            (original, pattern(&rows)),
+            (
+                container,
+                pattern(&[
+                    (Note::B_4, true),
+                    rows[0],
+                    rows[1],
+                    rows[2],
+                    (Note::F_5, true),
+                ]),
+            ),
+            (kind_copy, pattern(&rows)),
+            (other_song_copy, pattern(&rows)),
+            (
+                partly_reachable,
+                pattern(&[rows[0], (Note::G_5, false), rows[2]]),
+            ),
+            (subpattern, pattern(&rows)),
+        ]);
+        let mut stats = OptimStats {
+            duplicated_patterns: 1,
+            ..Default::default()
+        };
+
+        let aliases = deduplicate_patterns(&mut patterns, &mut stats);
+        assert_eq!(
+            aliases,
+            Aliases::from([
+                (original, (container, 1)),
+                (kind_copy, (container, 1)),
+                (other_song_copy, (container, 1)),
+                (partly_reachable, (container, 1)),
+            ]),
+        );
+        // Merging the copy back into its original isn't counted as deduplication.
+        assert_eq!(stats.duplicated_patterns, 0);
+        assert_eq!(stats.deduplicated_patterns, 3);
+        assert_eq!(stats.deduplicated_rows, 3 * 3);
+    }
+}
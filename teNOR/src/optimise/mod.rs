@@ -1,38 +1,107 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
     hash::{BuildHasherDefault, Hash},
+    time::Duration,
 };
 
 use crate::{
-    song::{EffectId, Instrument, InstrumentKind, Note, PatternCell, Song, SubpatternCell},
+    song::{
+        EffectId, Instrument, InstrumentKind, Location, Note, PatternCell, Song, SubpatternCell,
+    },
     PATTERN_LENGTH,
 };
 
+mod canonicalisation;
+use canonicalisation::*;
 mod cell;
 pub use cell::*;
+mod deduplication;
+use deduplication::*;
 mod overlapping;
 use overlapping::*;
 mod reachability;
 use reachability::*;
 mod remapping;
+pub use remapping::CompactedMapping;
 use remapping::*;
+mod search;
+use search::*;
+
+#[derive(Debug, Clone, Default)]
+pub struct OptimOptions {
+    /// If set, more time is spent looking for better ways to overlap patterns than the default,
+    /// greedy approach; the result is never worse than the latter's.
+    pub search_budget: Option<SearchBudget>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchBudget {
+    Iterations(u64),
+    Time(Duration),
+}
+
+/// The songs are assumed to be valid, i.e. that [`validate`](crate::validate()) reports no errors
+/// for any of them; otherwise, this may panic, or produce garbage.
+pub fn optimise(songs: &[Song], options: &OptimOptions) -> (OptimResults, OptimStats) {
+    let mut stats = OptimStats::default();
+
+    // Each song goes through the "local" passes on its own, since instruments and waves are per-song;
+    // but all of their patterns then go into the same pool, so that they can share rows and cells.
+    let mut pattern_pool = PatternPool::default();
+    let mut song_results = Vec::with_capacity(songs.len());
+    for (song_idx, song) in songs.iter().enumerate() {
+        let (patterns, results) = optimise_song(song, &mut stats);
+        pattern_pool.extend(
+            patterns
+                .into_iter()
+                .map(|(id, pattern)| ((song_idx, id), pattern)),
+        );
+        song_results.push(results);
+    }
+
+    // Doing this first lets the passes below consider equivalent cells as identical.
+    canonicalise_cells(&mut pattern_pool, &mut stats);
+
+    // Removing patterns that fit inside of others cuts down on the number of patterns that the
+    // (much more expensive) overlapping pass has to deal with.
+    let aliases = deduplicate_patterns(&mut pattern_pool, &mut stats);
+    let (main_row_pool_builder, subpat_row_pool_builder, overlapped_rows) =
+        find_pattern_overlap(&pattern_pool, &aliases, options.search_budget);
+    let (main_row_pool, main_cell_map, main_saved_bytes_catalog) =
+        generate_row_pool(main_row_pool_builder);
+    let (subpat_row_pool, subpat_cell_map, subpat_saved_bytes_catalog) =
+        generate_row_pool(subpat_row_pool_builder);
+
+    stats.overlapped_rows = overlapped_rows;
+    stats.saved_bytes_catalog = main_saved_bytes_catalog + subpat_saved_bytes_catalog;
 
-pub fn optimise(song: &Song) -> (OptimResults, OptimStats) {
+    (
+        OptimResults {
+            main_row_pool,
+            main_cell_catalog: main_cell_map,
+            subpat_row_pool,
+            subpat_cell_catalog: subpat_cell_map,
+            songs: song_results,
+        },
+        stats,
+    )
+}
+
+/// Runs all the passes that only concern a single song, and accumulates their stats.
+fn optimise_song(song: &Song, stats: &mut OptimStats) -> (PatternStore, SongResults) {
     let mut patterns = collect_patterns(song);
 
+    let mut routine_calls = RoutineCalls::new();
     let (used_duty_instrs, used_wave_instrs, used_noise_instrs, mut used_waves) =
-        mark_reachable_pattern_rows(song, &mut patterns);
+        mark_reachable_pattern_rows(song, &mut patterns, &mut routine_calls);
 
-    let mut pruned_patterns = 0;
-    let mut pruned_pattern_rows = 0;
-    let mut trimmed_rows = 0;
     // Eliminating patterns now means `remove` will move less data since the subpatterns aren't in yet,
     // and iterating over fewer rows when remapping instruments.
     trim_trailing_unreachable_rows(
         &mut patterns,
-        &mut pruned_patterns,
-        &mut pruned_pattern_rows,
-        &mut trimmed_rows,
+        &mut stats.pruned_patterns,
+        &mut stats.pruned_pattern_rows,
+        &mut stats.trimmed_rows,
     );
 
     collect_subpatterns(
@@ -58,16 +127,16 @@ pub fn optimise(song: &Song) -> (OptimResults, OptimStats) {
         let PatternId::Subpattern(..) = id else {
             continue;
         };
-        mark_reachable_subpattern_rows(*id, subpattern, &mut used_waves);
+        mark_reachable_subpattern_rows(*id, subpattern, &mut used_waves, &mut routine_calls);
     }
 
     // FIXME: this is not ideal, since it will iterate on the regular patterns again.
     //        This might be fixable by doing the trimming in the collection phase instead.
     trim_trailing_unreachable_rows(
         &mut patterns,
-        &mut pruned_patterns,
-        &mut pruned_pattern_rows,
-        &mut trimmed_rows,
+        &mut stats.pruned_patterns,
+        &mut stats.pruned_pattern_rows,
+        &mut stats.trimmed_rows,
     );
 
     // Eliminate "dead" instruments and reorder remaining ones.
@@ -103,25 +172,16 @@ pub fn optimise(song: &Song) -> (OptimResults, OptimStats) {
         else {
             unreachable!();
         };
-        used_waves |= 1 << wave_id;
+        // Out-of-bounds wave IDs are reported by validation.
+        used_waves |= 1u16.checked_shl(wave_id.into()).unwrap_or(0);
     }
     let wave_usage = compacted_mapping_from_mask(used_waves);
     remap_waves(&mut patterns, &wave_usage.0);
     // Instruments' waves are remapped during export.
 
-    // TODO: pattern deduplication (including finding patterns "in the middle of" of others) would
-    //       cut down on the number of patterns, and potentially speed up following steps.
-    let (main_row_pool_builder, subpat_row_pool_builder, overlapped_rows) =
-        find_pattern_overlap(&patterns);
-    let (main_row_pool, main_cell_map, main_saved_bytes_catalog) =
-        generate_row_pool(main_row_pool_builder);
-    let (subpat_row_pool, subpat_cell_map, subpat_saved_bytes_catalog) =
-        generate_row_pool(subpat_row_pool_builder);
-
-    // We're done! Time to compute some stats for reporting, and return our hard work!
+    // We're done with this song! Time to compute some stats for reporting.
 
     let mut pattern_usage = vec![0u8; (song.patterns.len() + 7) / 8];
-    let mut duplicated_patterns = 0; // Innocent until proven guilty.
     for id in patterns.keys() {
         let PatternId::Pattern(_, index) = id else {
             continue;
@@ -131,7 +191,7 @@ pub fn optimise(song: &Song) -> (OptimResults, OptimStats) {
         if *byte & mask == 0 {
             *byte |= mask;
         } else {
-            duplicated_patterns += 1;
+            stats.duplicated_patterns += 1;
         }
     }
 
@@ -144,41 +204,29 @@ pub fn optimise(song: &Song) -> (OptimResults, OptimStats) {
                     .map_or(0, |subpattern| subpattern.len() * 3)
         })
     };
-    let stats = OptimStats {
-        duplicated_patterns,
-        overlapped_rows,
-        pruned_patterns,
-        pruned_pattern_rows,
-        trimmed_rows,
-        pruned_instrs: duty_instr_usage.nb_saved()
-            + wave_instr_usage.nb_saved()
-            + noise_instr_usage.nb_saved(),
-        pruned_instrs_bytes: saved_bytes_instrs(
-            &song.instruments.duty,
-            &duty_instr_usage.0[duty_instr_usage.1..],
-        ) + saved_bytes_instrs(
-            &song.instruments.wave,
-            &wave_instr_usage.0[wave_instr_usage.1..],
-        ) + saved_bytes_instrs(
-            &song.instruments.noise,
-            &noise_instr_usage.0[noise_instr_usage.1..],
-        ),
-        trimmed_waves: wave_usage.nb_saved(),
-        saved_bytes_catalog: main_saved_bytes_catalog + subpat_saved_bytes_catalog,
-    };
+    stats.pruned_instrs +=
+        duty_instr_usage.nb_saved() + wave_instr_usage.nb_saved() + noise_instr_usage.nb_saved();
+    stats.pruned_instrs_bytes += saved_bytes_instrs(
+        &song.instruments.duty,
+        &duty_instr_usage.0[duty_instr_usage.1..],
+    ) + saved_bytes_instrs(
+        &song.instruments.wave,
+        &wave_instr_usage.0[wave_instr_usage.1..],
+    ) + saved_bytes_instrs(
+        &song.instruments.noise,
+        &noise_instr_usage.0[noise_instr_usage.1..],
+    );
+    stats.trimmed_waves += wave_usage.nb_saved();
 
     (
-        OptimResults {
-            main_row_pool,
-            main_cell_catalog: main_cell_map,
-            subpat_row_pool,
-            subpat_cell_catalog: subpat_cell_map,
+        patterns,
+        SongResults {
             duty_instr_usage,
             wave_instr_usage,
             noise_instr_usage,
             wave_usage,
+            routine_calls,
         },
-        stats,
     )
 }
 
@@ -188,15 +236,29 @@ pub struct OptimResults {
     pub main_cell_catalog: CellCatalog,
     pub subpat_row_pool: Vec<OutputCell>,
     pub subpat_cell_catalog: CellCatalog,
+    /// One per input song, in the same order.
+    pub songs: Vec<SongResults>,
+}
+
+/// The results that are specific to each song, even when several are optimised together.
+#[derive(Debug)]
+pub struct SongResults {
     pub duty_instr_usage: CompactedMapping<15>,
     pub wave_instr_usage: CompactedMapping<15>,
     pub noise_instr_usage: CompactedMapping<15>,
     pub wave_usage: CompactedMapping<16>,
+    /// Every argument that the routine may be called with, and where (in the order that playback
+    /// first reaches them, subpatterns coming last).
+    pub routine_calls: RoutineCalls,
 }
 
-#[derive(Debug, Clone)]
+pub type RoutineCalls = BTreeMap<u8, Vec<Location>>;
+
+#[derive(Debug, Clone, Default)]
 pub struct OptimStats {
     pub duplicated_patterns: usize,
+    pub deduplicated_patterns: usize,
+    pub deduplicated_rows: usize,
     pub overlapped_rows: usize,
     pub pruned_patterns: usize,
     pub pruned_pattern_rows: usize,
@@ -204,6 +266,7 @@ pub struct OptimStats {
     pub pruned_instrs: usize,
     pub pruned_instrs_bytes: usize,
     pub trimmed_waves: usize,
+    pub canonicalised_cells: usize,
     pub saved_bytes_catalog: isize,
 }
 
@@ -212,6 +275,10 @@ impl OptimStats {
         self.duplicated_patterns * 64 * 3
     }
 
+    pub fn saved_bytes_deduplicated_patterns(&self) -> usize {
+        self.deduplicated_rows * 3
+    }
+
     pub fn saved_bytes_overlapped_rows(&self) -> usize {
         self.overlapped_rows * 3
     }
@@ -228,8 +295,15 @@ impl OptimStats {
         self.trimmed_waves * 16
     }
 
+    /// This is already part of [`Self::saved_bytes_catalog`], since canonicalisation happens before
+    /// the catalogs are generated; so, it's not counted in [`Self::total_saved_bytes`].
+    pub fn saved_bytes_canonicalised_cells(&self) -> usize {
+        self.canonicalised_cells * 3
+    }
+
     pub fn total_saved_bytes(&self) -> isize {
-        (self.saved_bytes_overlapped_rows()
+        (self.saved_bytes_deduplicated_patterns()
+            + self.saved_bytes_overlapped_rows()
             + self.saved_bytes_pruned_patterns()
             + self.saved_bytes_trimmed_rows()
             + self.pruned_instrs_bytes
@@ -237,20 +311,75 @@ impl OptimStats {
         .wrapping_sub(self.wasted_bytes_duplicated_patterns()) as isize // I doubt the savings will ever grow that large...
         + self.saved_bytes_catalog
     }
+
+    /// Serialises the stats as a JSON object, with each optimisation's bytes next to its count.
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("pruned_patterns", self.pruned_patterns),
+            ("pruned_pattern_rows", self.pruned_pattern_rows),
+            (
+                "saved_bytes_pruned_patterns",
+                self.saved_bytes_pruned_patterns(),
+            ),
+            ("trimmed_rows", self.trimmed_rows),
+            ("saved_bytes_trimmed_rows", self.saved_bytes_trimmed_rows()),
+            ("deduplicated_patterns", self.deduplicated_patterns),
+            ("deduplicated_rows", self.deduplicated_rows),
+            (
+                "saved_bytes_deduplicated_patterns",
+                self.saved_bytes_deduplicated_patterns(),
+            ),
+            ("overlapped_rows", self.overlapped_rows),
+            (
+                "saved_bytes_overlapped_rows",
+                self.saved_bytes_overlapped_rows(),
+            ),
+            ("pruned_instrs", self.pruned_instrs),
+            ("saved_bytes_pruned_instrs", self.pruned_instrs_bytes),
+            ("trimmed_waves", self.trimmed_waves),
+            (
+                "saved_bytes_trimmed_waves",
+                self.saved_bytes_trimmed_waves(),
+            ),
+            ("canonicalised_cells", self.canonicalised_cells),
+            (
+                "saved_bytes_canonicalised_cells",
+                self.saved_bytes_canonicalised_cells(),
+            ),
+            ("duplicated_patterns", self.duplicated_patterns),
+            (
+                "wasted_bytes_duplicated_patterns",
+                self.wasted_bytes_duplicated_patterns(),
+            ),
+        ]
+        .map(|(name, value)| format!(r#""{name}":{value}"#));
+        format!(
+            r#"{{{},"saved_bytes_catalog":{},"total_saved_bytes":{}}}"#,
+            fields.join(","),
+            self.saved_bytes_catalog,
+            self.total_saved_bytes(),
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum OutputCell {
-    Label(PatternId),
+    Label(PatternKey),
     Cell(u8),
     OverlapMarker {
         nb_rows: usize,
-        pattern_id: PatternId,
+        pattern_id: PatternKey,
     },
 }
 
 /// Convenience shortcuts.
-pub type PatternStore = HashMap<PatternId, OptimisedPattern, BuildHasherDefault<DefaultHasher>>;
+///
+/// Patterns are kept in ordered maps, so that every pass iterates over them in the same order,
+/// and thus that identical input always produces identical output.
+pub type PatternStore = BTreeMap<PatternId, OptimisedPattern>;
+/// Patterns from all songs being optimised together, keyed by the song's index and their ID within it.
+pub type PatternPool = BTreeMap<PatternKey, OptimisedPattern>;
+pub type PatternKey = (usize, PatternId);
 pub type CellCatalog = HashMap<Cell, u8, BuildHasherDefault<DefaultHasher>>;
 
 fn collect_patterns(song: &Song) -> PatternStore {
@@ -295,13 +424,13 @@ fn collect_subpatterns(
     );
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum PatternId {
     Pattern(InstrKind, usize),
     Subpattern(InstrKind, usize),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum InstrKind {
     Duty,
     Wave,
@@ -327,3 +456,79 @@ pub struct AnnotatedCell {
     reachable: bool,
     cell: Cell,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{optimise, OptimOptions};
+    use crate::{
+        song::{EffectId, Note, PatternCell},
+        uge::test_song,
+    };
+
+    #[test]
+    fn canonicalisation() {
+        let note = PatternCell {
+            note: Note::C_5,
+            instrument: 1,
+            effect_code: EffectId::Arpeggio,
+            effect_param: 0,
+        };
+        let set_vol = |effect_param| PatternCell {
+            effect_code: EffectId::SetVol,
+            effect_param,
+            ..Default::default()
+        };
+        let mut song = test_song();
+        // Only CH1 plays anything.
+        song.order_matrix = vec![[1, 0, 0, 0]];
+        song.patterns[1][..5].copy_from_slice(&[
+            note,
+            note,
+            set_vol(0x80),
+            Default::default(),
+            set_vol(0x80),
+        ]);
+        // The same, but with cells that play identically to those.
+        let mut equivalent_song = song.clone();
+        equivalent_song.patterns[1][..5].copy_from_slice(&[
+            note,
+            // Sliding by 0 on tick 0 does nothing, since the note has just been played.
+            PatternCell {
+                effect_code: EffectId::PortaUp,
+                ..note
+            },
+            // This encodes to the same byte as `C80`...
+            set_vol(0x30),
+            // Without a note, the instrument isn't applied.
+            PatternCell {
+                instrument: 1,
+                ..Default::default()
+            },
+            // ...and so does this.
+            set_vol(0x50),
+        ]);
+
+        let (results, stats) = optimise(&[song], &OptimOptions::default());
+        let (equivalent_results, equivalent_stats) =
+            optimise(&[equivalent_song], &OptimOptions::default());
+        assert_eq!(stats.canonicalised_cells, 0);
+        // Six distinct cells become three: the note with `100` merges into the plain note, the lone
+        // instrument into the blank cell, and both `Cx0` into `C80`.
+        assert_eq!(equivalent_stats.canonicalised_cells, 3);
+        assert_eq!(equivalent_stats.saved_bytes_canonicalised_cells(), 3 * 3);
+        // Once merged, the cells are catalogued like the canonical ones.
+        assert_eq!(
+            equivalent_results.main_cell_catalog.len(),
+            results.main_cell_catalog.len(),
+        );
+        assert_eq!(
+            equivalent_stats.saved_bytes_catalog,
+            stats.saved_bytes_catalog
+        );
+        // The savings are part of the catalog's, and not counted again.
+        assert_eq!(
+            equivalent_stats.total_saved_bytes(),
+            stats.total_saved_bytes()
+        );
+    }
+}
@@ -2,27 +2,43 @@ use std::{iter::FusedIterator, num::Wrapping};
 
 use crate::optimise::CellCatalog;
 
-use super::{AnnotatedCell, OutputCell, PatternId, PatternStore};
+use super::{
+    improve_ordering, Aliases, AnnotatedCell, OutputCell, PatternId, PatternKey, PatternPool,
+    SearchBudget,
+};
 
 // This algorithm is described in the README.
-pub(super) fn find_pattern_overlap(
-    patterns: &PatternStore,
-) -> (RowPoolBuilder<'_>, RowPoolBuilder<'_>, usize) {
-    // A hashmap's keys are not guaranteed to be returned in a consistent order, so collect them to ensure that.
+pub(super) fn find_pattern_overlap<'patterns>(
+    patterns: &'patterns PatternPool,
+    aliases: &Aliases,
+    search_budget: Option<SearchBudget>,
+) -> (RowPoolBuilder<'patterns>, RowPoolBuilder<'patterns>, usize) {
+    // Sort the patterns into their groups; the keys are iterated in order, so this is consistent.
     let mut main_pattern_ids = Vec::with_capacity(patterns.len());
     let mut sub_pattern_ids = Vec::with_capacity(patterns.len());
-    for &id in patterns.keys() {
-        match id {
-            PatternId::Pattern(_, _) => main_pattern_ids.push(id),
-            PatternId::Subpattern(_, _) => sub_pattern_ids.push(id),
+    for &key in patterns.keys() {
+        if aliases.contains_key(&key) {
+            continue; // These are placed inside of the pattern they were merged into, afterwards.
+        }
+        match key {
+            (_, PatternId::Pattern(_, _)) => main_pattern_ids.push(key),
+            (_, PatternId::Subpattern(_, _)) => sub_pattern_ids.push(key),
         }
     }
 
     fn find_overlap_in_group<'patterns>(
-        patterns: &'patterns PatternStore,
-        pattern_ids: &[PatternId],
-    ) -> (RowPoolBuilder<'patterns>, usize) {
+        patterns: &'patterns PatternPool,
+        pattern_ids: &[PatternKey],
+    ) -> RowPoolBuilder<'patterns> {
         let nb_patterns = pattern_ids.len();
+        if nb_patterns == 0 {
+            // This happens when no instrument with a subpattern is used, for example.
+            return RowPoolBuilder {
+                patterns,
+                ordering: vec![],
+                score: 0,
+            };
+        }
 
         // The first iteration is really simple: just shove every pattern, and there can be no overlap.
         // This also ensures that no ordering will ever be empty.
@@ -55,21 +71,50 @@ pub(super) fn find_pattern_overlap(
             }
         }
 
-        let best_builder = new_row
+        new_row
             .into_iter()
             .flatten() // Skip over empty cells.
             .max_by_key(|builder| builder.score)
-            .expect("How come no ordering survived!?");
-        let score = best_builder.score;
-        (best_builder, score)
+            .expect("How come no ordering survived!?")
+    }
+    let (mut main_builder, mut sub_builder) = (
+        find_overlap_in_group(patterns, &main_pattern_ids),
+        find_overlap_in_group(patterns, &sub_pattern_ids),
+    );
+    if let Some(budget) = search_budget {
+        let nb_patterns = main_pattern_ids.len() + sub_pattern_ids.len();
+        main_builder = improve_ordering(
+            main_builder,
+            budget.share(main_pattern_ids.len(), nb_patterns),
+        );
+        sub_builder = improve_ordering(
+            sub_builder,
+            budget.share(sub_pattern_ids.len(), nb_patterns),
+        );
+    }
+    let (main_score, sub_score) = (main_builder.score, sub_builder.score);
+
+    // Sort the aliases, so that patterns beginning on the same row always get emitted in the same order.
+    let mut aliases: Vec<_> = aliases.iter().collect();
+    aliases.sort_unstable_by_key(|(&key, _)| key);
+    for (&key, &(container_key, start_row_idx)) in aliases {
+        let builder = match key {
+            (_, PatternId::Pattern(_, _)) => &mut main_builder,
+            (_, PatternId::Subpattern(_, _)) => &mut sub_builder,
+        };
+        let &(_, container_start_idx) = builder
+            .ordering
+            .iter()
+            .find(|(id, _)| *id == container_key)
+            .expect("Pattern was merged into a pattern that wasn't placed");
+        builder.add(key, container_start_idx + start_row_idx, builder.score);
     }
-    let (main_builder, main_score) = find_overlap_in_group(patterns, &main_pattern_ids);
-    let (sub_builder, sub_score) = find_overlap_in_group(patterns, &sub_pattern_ids);
+
     (main_builder, sub_builder, main_score + sub_score)
 }
 
 impl AnnotatedCell {
-    fn can_overlap_with(&self, other: &Self) -> bool {
+    pub(super) fn can_overlap_with(&self, other: &Self) -> bool {
         // Checking for reachability like this is fine, because we always try hard to find an overlapping reachable row.
         !self.reachable || !other.reachable || self.cell == other.cell
     }
@@ -77,14 +122,14 @@ impl AnnotatedCell {
 
 #[derive(Debug, Clone)]
 pub(super) struct RowPoolBuilder<'patterns> {
-    patterns: &'patterns PatternStore,
+    pub(super) patterns: &'patterns PatternPool,
     // Vector of (pattern id, how many rows into pool before its start)
-    ordering: Vec<(PatternId, usize)>,
-    score: usize,
+    pub(super) ordering: Vec<(PatternKey, usize)>,
+    pub(super) score: usize,
 }
 
 impl<'patterns> RowPoolBuilder<'patterns> {
-    fn new(patterns: &'patterns PatternStore, initial_pattern_id: PatternId) -> Self {
+    pub(super) fn new(patterns: &'patterns PatternPool, initial_pattern_id: PatternKey) -> Self {
         let mut ordering = Vec::with_capacity(patterns.len());
         ordering.push((initial_pattern_id, 0));
         Self {
@@ -94,11 +139,11 @@ impl<'patterns> RowPoolBuilder<'patterns> {
         }
     }
 
-    fn contains(&self, pattern_id: PatternId) -> bool {
+    fn contains(&self, pattern_id: PatternKey) -> bool {
         self.ordering.iter().any(|&(id, _)| id == pattern_id)
     }
 
-    fn score_with(&self, pattern_id: PatternId) -> (usize, usize) {
+    pub(super) fn score_with(&self, pattern_id: PatternKey) -> (usize, usize) {
         let pattern = &self.patterns[&pattern_id];
         let first_row = &pattern.0[0];
 
@@ -131,7 +176,7 @@ impl<'patterns> RowPoolBuilder<'patterns> {
         (self.score, start_row_idx)
     }
 
-    fn add(&mut self, pattern_id: PatternId, start_row_idx: usize, new_score: usize) {
+    pub(super) fn add(&mut self, pattern_id: PatternKey, start_row_idx: usize, new_score: usize) {
         // Keep the array sorted by `start_row_idx`.
         let insert_idx = self
             .ordering
@@ -186,8 +231,13 @@ impl<'builder, 'patterns: 'builder> Iterator for RowsIter<'builder, 'patterns> {
                     break;
                 };
 
-                let overlapping_row =
-                    &self.builder.patterns[&overlapping_pattern_id].0[overlapping_pattern_ofs];
+                // Patterns nested inside of the current one may end before this row.
+                let Some(overlapping_row) = self.builder.patterns[&overlapping_pattern_id]
+                    .0
+                    .get(overlapping_pattern_ofs)
+                else {
+                    continue;
+                };
                 // I found you, faker!
                 if overlapping_row.reachable {
                     // Faker? You're not even good enough to be my fake.
@@ -231,6 +281,9 @@ pub(super) fn generate_row_pool(
     let mut next_id = Wrapping(0); // An ID overflow will trigger an error in a later stage; for now, just ensure that we get there without panicking.
     let mut nb_saved_bytes = 0;
 
+    if ordering.is_empty() {
+        return (output, cell_catalog, nb_saved_bytes);
+    }
     debug_assert_eq!(ordering[0].1, 0); // The first pattern should be starting at the first row.
     let mut nb_rows_emitted = 0; // For sanity checking.
     let mut idx = 0;
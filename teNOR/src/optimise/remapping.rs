@@ -39,6 +39,11 @@ pub(super) fn compacted_mapping_from_mask<const N: usize>(mut mask: u16) -> Comp
 }
 
 impl<const N: usize> CompactedMapping<N> {
+    /// A mapping that leaves all IDs as they are.
+    pub(crate) fn identity() -> Self {
+        Self(std::array::from_fn(|i| i as u8), N)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
         self.0[..self.1].iter().cloned()
     }
@@ -0,0 +1,173 @@
+use std::time::Instant;
+
+use super::{OptimisedPattern, PatternKey, PatternPool, RowPoolBuilder, SearchBudget};
+
+/// Tries to find a better ordering than the greedy one, within the given budget.
+///
+/// This approximates the problem as an asymmetric travelling salesman one, where going from
+/// pattern A to pattern B "earns" as many rows as A's end and B's beginning can overlap by;
+/// the orderings found this way are then laid out for real, and only kept if they beat `greedy`.
+pub(super) fn improve_ordering(
+    greedy: RowPoolBuilder<'_>,
+    budget: SearchBudget,
+) -> RowPoolBuilder<'_> {
+    let start_time = Instant::now();
+    let patterns = greedy.patterns;
+    let keys: Vec<PatternKey> = greedy.ordering.iter().map(|&(key, _)| key).collect();
+    let nb_patterns = keys.len();
+    if nb_patterns < 3 {
+        return greedy; // There aren't any other orderings worth trying.
+    }
+
+    let overlaps: Vec<usize> = keys
+        .iter()
+        .flat_map(|a| keys.iter().map(move |b| (a, b)))
+        .map(|(a, b)| {
+            if a == b {
+                0
+            } else {
+                overlap_between(&patterns[a], &patterns[b])
+            }
+        })
+        .collect();
+    let gain = |from: Option<usize>, to: Option<usize>| match (from, to) {
+        (Some(from), Some(to)) => overlaps[from * nb_patterns + to],
+        _ => 0,
+    };
+
+    // Start from the greedy ordering, so that the search has a good head start.
+    let mut ordering: Vec<usize> = (0..nb_patterns).collect();
+    let mut score: usize = ordering
+        .windows(2)
+        .map(|w| gain(Some(w[0]), Some(w[1])))
+        .sum();
+    let mut best_ordering = ordering.clone();
+    let mut best_score = score;
+    // The approximation isn't perfect (e.g. it ignores patterns nested inside of others), so any
+    // improvement has to be checked for real.
+    let mut best_builder = greedy;
+
+    let mut rng = XorShift(0x7e40_7e40_7e40_7e40); // Fixed seed, so that iteration budgets give reproducible results.
+    let mut nb_stale_iterations = 0;
+    for iteration in 0u64.. {
+        let out_of_budget = match budget {
+            SearchBudget::Iterations(max) => iteration >= max,
+            // Checking the time isn't free, so don't do it every time.
+            SearchBudget::Time(duration) => {
+                iteration % 256 == 0 && start_time.elapsed() >= duration
+            }
+        };
+        if out_of_budget {
+            break;
+        }
+
+        // Try moving a few consecutive patterns somewhere else ("or-opt").
+        let len = 1 + rng.below(3);
+        let from = rng.below(nb_patterns - len + 1);
+        let to = rng.below(nb_patterns - len + 1); // Index into the ordering once the moved patterns are removed.
+        if to == from {
+            continue;
+        }
+        let (first, last) = (ordering[from], ordering[from + len - 1]);
+        let remaining = |idx: usize| {
+            if idx < from {
+                ordering.get(idx)
+            } else {
+                ordering.get(idx + len)
+            }
+            .copied()
+        };
+        let (before, after) = (from.checked_sub(1).and_then(remaining), remaining(from));
+        let (new_before, new_after) = (to.checked_sub(1).and_then(remaining), remaining(to));
+        let removed =
+            gain(before, Some(first)) + gain(Some(last), after) + gain(new_before, new_after);
+        let added =
+            gain(before, after) + gain(new_before, Some(first)) + gain(Some(last), new_after);
+
+        // Accepting "neutral" moves lets the search wander around plateaus.
+        if added >= removed {
+            let moved: Vec<usize> = ordering.drain(from..from + len).collect();
+            ordering.splice(to..to, moved);
+            score = score + added - removed;
+            if score > best_score {
+                best_ordering.clone_from(&ordering);
+                best_score = score;
+                nb_stale_iterations = 0;
+
+                let builder = lay_out(patterns, &keys, &ordering);
+                if builder.score > best_builder.score {
+                    best_builder = builder;
+                }
+                continue;
+            }
+        }
+
+        nb_stale_iterations += 1;
+        if nb_stale_iterations > nb_patterns * nb_patterns {
+            // We're likely stuck in a local optimum; shake the best ordering up a bit, and go again.
+            ordering.clone_from(&best_ordering);
+            for _ in 0..3 {
+                let from = rng.below(nb_patterns);
+                let idx = ordering.remove(from);
+                ordering.insert(rng.below(nb_patterns), idx);
+            }
+            score = ordering
+                .windows(2)
+                .map(|w| gain(Some(w[0]), Some(w[1])))
+                .sum();
+            nb_stale_iterations = 0;
+        }
+    }
+
+    best_builder
+}
+
+fn lay_out<'patterns>(
+    patterns: &'patterns PatternPool,
+    keys: &[PatternKey],
+    ordering: &[usize],
+) -> RowPoolBuilder<'patterns> {
+    let mut builder = RowPoolBuilder::new(patterns, keys[ordering[0]]);
+    for &idx in &ordering[1..] {
+        let (new_score, start_row_idx) = builder.score_with(keys[idx]);
+        builder.add(keys[idx], start_row_idx, new_score);
+    }
+    builder
+}
+
+/// How many of `a`'s last rows can overlap with `b`'s first rows.
+fn overlap_between(a: &OptimisedPattern, b: &OptimisedPattern) -> usize {
+    (1..=a.0.len().min(b.0.len()))
+        .rev()
+        .find(|&nb_rows| {
+            a.0[a.0.len() - nb_rows..]
+                .iter()
+                .zip(&b.0)
+                .all(|(row, other)| row.can_overlap_with(other))
+        })
+        .unwrap_or(0)
+}
+
+/// A tiny PRNG; the search doesn't need anything fancier.
+struct XorShift(u64);
+
+impl XorShift {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+impl SearchBudget {
+    /// The share of the budget that a group of `part` patterns out of `total` should get.
+    pub(super) fn share(self, part: usize, total: usize) -> Self {
+        match self {
+            Self::Iterations(n) => {
+                Self::Iterations((u128::from(n) * part as u128 / total.max(1) as u128) as u64)
+            }
+            Self::Time(duration) => Self::Time(duration.mul_f64(part as f64 / total.max(1) as f64)),
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Follows a song's order the way the driver plays it, for anything that needs to know what gets
+//! played when (as opposed to what merely gets exported).
+
+use crate::{
+    song::{EffectId, Song},
+    PATTERN_LENGTH,
+};
+
+/// How many times per second the Game Boy's CPU is clocked (in single-speed mode).
+pub const CPU_CLOCK: u32 = 4_194_304;
+
+/// How many CPU cycles there are between two calls to `hUGE_TickSound`.
+///
+/// Without a timer divider, that's once per frame; otherwise, the timer is assumed to be clocked at
+/// 4096 Hz, with `TMA` set to the divider.
+pub fn cycles_per_tick(timer_divider: Option<u8>) -> u32 {
+    match timer_divider {
+        None => 70_224,
+        Some(divider) => (256 - u32::from(divider)) * (CPU_CLOCK / 4096),
+    }
+}
+
+/// How many times per second the driver is ticked (see [`cycles_per_tick`]).
+pub fn tick_rate(timer_divider: Option<u8>) -> f64 {
+    f64::from(CPU_CLOCK) / f64::from(cycles_per_tick(timer_divider))
+}
+
+/// How many beats per minute a song plays at with a given tempo, assuming 4 rows per beat (as
+/// hUGETracker does).
+pub fn bpm(ticks_per_row: u16, timer_divider: Option<u8>) -> f64 {
+    tick_rate(timer_divider) * 60.0 / (4.0 * f64::from(ticks_per_row))
+}
+
+/// Every row that gets played, in order, until the song starts looping.
+#[derive(Debug, Clone)]
+pub struct Playthrough {
+    pub rows: Vec<PlayedRow>,
+    /// Index (into `rows`) of the row that playback loops back to after the last one.
+    pub loop_start: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlayedRow {
+    pub order_idx: usize,
+    pub row: usize,
+    /// How long the row lasts, taking `Fxx` into account.
+    pub nb_ticks: u16,
+}
+
+impl Playthrough {
+    /// How many ticks it takes to play the rows before `idx`.
+    pub fn ticks_before(&self, idx: usize) -> u64 {
+        self.rows[..idx]
+            .iter()
+            .map(|row| u64::from(row.nb_ticks))
+            .sum()
+    }
+
+    /// How many ticks it takes to play the song until it loops, and then its looping part
+    /// `nb_loops` times.
+    pub fn nb_ticks(&self, nb_loops: u32) -> u64 {
+        let intro_len = self.ticks_before(self.loop_start);
+        let loop_len = self.ticks_before(self.rows.len()) - intro_len;
+        intro_len + loop_len * u64::from(nb_loops)
+    }
+}
+
+/// How long a song takes to play, as followed by [`play_through`].
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    /// How many ticks are played before reaching the part that loops.
+    pub intro_ticks: u64,
+    /// How many ticks the part that loops lasts.
+    pub loop_ticks: u64,
+    /// How many rows the part that loops lasts.
+    pub loop_rows: usize,
+    /// The order row that playback loops back to.
+    pub loop_order_idx: usize,
+    /// The row (within the patterns of that order row) that playback loops back to.
+    pub loop_row: usize,
+    pub timer_divider: Option<u8>,
+}
+
+impl Timing {
+    /// How many seconds that many ticks last, if played with the given timer divider (`None` for
+    /// VBlank-based playback); usually, the song's own.
+    pub fn seconds(&self, nb_ticks: u64, timer_divider: Option<u8>) -> f64 {
+        nb_ticks as f64 / tick_rate(timer_divider)
+    }
+
+    /// How many frames that many ticks last (rounded to the nearest); this is the same as the
+    /// ticks for VBlank-based playback.
+    pub fn frames(&self, nb_ticks: u64) -> u64 {
+        let nb_cycles = nb_ticks * u64::from(cycles_per_tick(self.timer_divider));
+        let cycles_per_frame = u64::from(cycles_per_tick(None));
+        (nb_cycles + cycles_per_frame / 2) / cycles_per_frame
+    }
+
+    /// The average tempo of the part that loops, taking `Fxx` into account, if played with the
+    /// given timer divider (like [`Self::seconds`]).
+    pub fn bpm(&self, timer_divider: Option<u8>) -> f64 {
+        let rows_per_second = self.loop_rows as f64 / self.seconds(self.loop_ticks, timer_divider);
+        rows_per_second * 60.0 / 4.0
+    }
+
+    /// Serialises the timing as a JSON object, including how long it lasts in seconds both with
+    /// VBlank-based playback and with the song's timer divider (if it has one).
+    pub fn to_json(&self) -> String {
+        let playback = |timer_divider| {
+            format!(
+                r#"{{"intro_seconds":{:.3},"loop_seconds":{:.3},"bpm":{:.2}}}"#,
+                self.seconds(self.intro_ticks, timer_divider),
+                self.seconds(self.loop_ticks, timer_divider),
+                self.bpm(timer_divider),
+            )
+        };
+        format!(
+            concat!(
+                r#"{{"intro_ticks":{},"intro_frames":{},"loop_ticks":{},"loop_frames":{},"#,
+                r#""loop_order_row":{},"loop_row":{},"timer_divider":{},"vblank":{},"timer":{}}}"#,
+            ),
+            self.intro_ticks,
+            self.frames(self.intro_ticks),
+            self.loop_ticks,
+            self.frames(self.loop_ticks),
+            self.loop_order_idx,
+            self.loop_row,
+            self.timer_divider
+                .map_or("null".into(), |divider| divider.to_string()),
+            playback(None),
+            self.timer_divider
+                .map_or("null".into(), |divider| playback(Some(divider))),
+        )
+    }
+}
+
+/// Computes how long the song takes to play before it loops, and how long it takes to loop.
+///
+/// The song is assumed to be valid (see [`validate`](crate::validate())).
+pub fn timing(song: &Song) -> Timing {
+    let playthrough = play_through(song);
+    let intro_ticks = playthrough.ticks_before(playthrough.loop_start);
+    let loop_point = playthrough.rows[playthrough.loop_start];
+    Timing {
+        intro_ticks,
+        loop_ticks: playthrough.ticks_before(playthrough.rows.len()) - intro_ticks,
+        loop_rows: playthrough.rows.len() - playthrough.loop_start,
+        loop_order_idx: loop_point.order_idx,
+        loop_row: loop_point.row,
+        timer_divider: song.timer_divider,
+    }
+}
+
+/// Follows the order the way the driver does, from the beginning until playback loops; see
+/// [`rows`].
+#[derive(Debug, Clone)]
+pub struct Rows<'song, 'input> {
+    song: &'song Song<'input>,
+    /// The order row and row that will be played next.
+    position: (usize, usize),
+    /// Which rows of each order row have been played.
+    reached: Vec<u64>,
+    looped: bool,
+}
+
+/// Iterates over the rows that get played (as their order row's index and their own), in order,
+/// until the song loops; [`Rows::loop_point`] then tells which row it loops back to.
+///
+/// There being no conditional jumps, a row being reached again means that playback is looping.
+/// Since validation walks the song this way too, the song isn't assumed to be valid: `Bxx` and
+/// `Dxx` with an out-of-bounds target are ignored (and reported by [`validate`](crate::validate())).
+pub fn rows<'song, 'input>(song: &'song Song<'input>) -> Rows<'song, 'input> {
+    Rows {
+        song,
+        position: (0, 0),
+        reached: vec![0; song.order_matrix.len()],
+        looped: false,
+    }
+}
+
+impl Rows<'_, '_> {
+    /// The row that playback loops back to, once every row has been iterated over.
+    pub fn loop_point(&self) -> Option<(usize, usize)> {
+        self.looped.then_some(self.position)
+    }
+}
+
+impl Iterator for Rows<'_, '_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (order_idx, row) = self.position;
+        if self.looped || self.reached[order_idx] & (1 << row) != 0 {
+            self.looped = true;
+            return None;
+        }
+        self.reached[order_idx] |= 1 << row;
+
+        let nb_orders = self.song.order_matrix.len();
+        let mut next_order = None;
+        let mut next_row = None;
+        for &pattern_id in &self.song.order_matrix[order_idx] {
+            let cell = &self.song.patterns[pattern_id][row];
+            match (cell.effect_code, cell.effect_param) {
+                (EffectId::PatternBreak, param @ 1..=PATTERN_LENGTH) => {
+                    next_row = Some(usize::from(param) - 1);
+                    next_order.get_or_insert((order_idx + 1) % nb_orders);
+                }
+                (EffectId::PosJump, param) if param != 0 && usize::from(param) <= nb_orders => {
+                    next_order = Some(usize::from(param) - 1);
+                }
+                _ => {}
+            }
+        }
+
+        // Go to the next row, or follow the overrides if any are set.
+        self.position = if let Some(order) = next_order {
+            (order, next_row.unwrap_or(0))
+        } else if row + 1 == usize::from(PATTERN_LENGTH) {
+            ((order_idx + 1) % nb_orders, 0)
+        } else {
+            (order_idx, row + 1)
+        };
+        Some((order_idx, row))
+    }
+}
+
+/// Plays the song from the beginning until it loops (see [`rows`]).
+///
+/// The song is assumed to be valid (see [`validate`](crate::validate())). The tempo is not taken
+/// into account for looping, although `Fxx` could make the second time around last a different
+/// amount of time than the first.
+pub fn play_through(song: &Song) -> Playthrough {
+    let mut ticks_per_row = song.ticks_per_row;
+    let mut song_rows = self::rows(song);
+    let rows: Vec<_> = song_rows
+        .by_ref()
+        .map(|(order_idx, row)| {
+            for &pattern_id in &song.order_matrix[order_idx] {
+                let cell = &song.patterns[pattern_id][row];
+                // This takes effect on the row's first tick, so it applies to the row itself.
+                if cell.effect_code == EffectId::SetTempo {
+                    ticks_per_row = cell.effect_param;
+                }
+            }
+            PlayedRow {
+                order_idx,
+                row,
+                // The row timer is decremented before being checked, so 0 acts like 256.
+                nb_ticks: match ticks_per_row {
+                    0 => 256,
+                    ticks => ticks.into(),
+                },
+            }
+        })
+        .collect();
+
+    let loop_point = song_rows.loop_point();
+    let loop_start = rows
+        .iter()
+        .position(|row| Some((row.order_idx, row.row)) == loop_point)
+        .expect("Playback must loop back to a row that has been played");
+    Playthrough { rows, loop_start }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{song::PatternCell, uge::test_song};
+
+    #[test]
+    fn rows_follow_jumps() {
+        let mut song = test_song();
+        song.order_matrix = vec![[0, 1, 1, 1], [2, 1, 1, 1], [1, 1, 1, 1]];
+        let effect = |effect_code, effect_param| PatternCell {
+            effect_code,
+            effect_param,
+            ..Default::default()
+        };
+        song.patterns[0][1] = effect(EffectId::PatternBreak, 5);
+        // Out-of-bounds jumps are ignored.
+        song.patterns[2][5] = effect(EffectId::PosJump, 9);
+        song.patterns[2][6] = effect(EffectId::PosJump, 3);
+
+        let mut rows = rows(&song);
+        let played: Vec<_> = rows.by_ref().collect();
+        let expected: Vec<_> = [(0, 0), (0, 1), (1, 4), (1, 5), (1, 6)]
+            .into_iter()
+            .chain((0..64).map(|row| (2, row)))
+            .collect();
+        assert_eq!(played, expected);
+        assert_eq!(rows.loop_point(), Some((0, 0)));
+        assert_eq!(play_through(&song).loop_start, 0);
+    }
+
+    #[test]
+    fn timing_per_playback_method() {
+        let mut song = test_song();
+        song.timer_divider = Some(192);
+        let timing = timing(&song);
+        // Two order rows of 64 rows at 7 ticks each, with no `Bxx` or `Dxx`.
+        assert_eq!(timing.intro_ticks, 0);
+        assert_eq!(timing.loop_ticks, 2 * 64 * 7);
+        assert_eq!(timing.loop_rows, 2 * 64);
+
+        // The song's ticks are 64 timer periods of 1024 cycles each, against 70224 for VBlank.
+        let ratio = 70_224.0 / (64.0 * 1024.0);
+        let timer_seconds = timing.seconds(timing.loop_ticks, Some(192));
+        let vblank_seconds = timing.seconds(timing.loop_ticks, None);
+        assert!((vblank_seconds / timer_seconds - ratio).abs() < 1e-9);
+        assert!((timing.bpm(Some(192)) / timing.bpm(None) - ratio).abs() < 1e-9);
+        assert!((timing.bpm(Some(192)) - bpm(7, Some(192))).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,545 @@
+//! A model of the Game Boy's APU, good enough to listen to songs.
+//!
+//! This is not cycle-accurate (e.g. the DMG's "zombie mode" and most of its obscure behaviour are
+//! not emulated), but it is deterministic, so that renders of the same song can be compared.
+
+use crate::{driver::*, playback::CPU_CLOCK};
+
+const FRAME_SEQ_PERIOD: u32 = CPU_CLOCK / 512;
+/// The DMG's high-pass filter's "charge factor", per CPU cycle.
+const HPF_CHARGE: f64 = 0.999958;
+
+const DUTY_CYCLES: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Debug)]
+pub(super) struct Apu {
+    regs: [u8; 0x30],
+
+    ch1: Pulse,
+    sweep: Sweep,
+    ch2: Pulse,
+    ch3: Wave,
+    ch4: Noise,
+
+    frame_seq_timer: u32,
+    frame_seq_step: u8,
+
+    sample_rate: u32,
+    /// How many cycles have been emulated.
+    now: u64,
+    nb_samples: u64,
+    /// The output, summed over the cycles of the sample being computed.
+    sample_acc: [f64; 2],
+    hpf_charge: f64,
+    hpf_caps: [f64; 2],
+}
+
+#[derive(Debug, Default)]
+struct Pulse {
+    enabled: bool,
+    dac: bool,
+    duty: u8,
+    duty_pos: u8,
+    period: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+#[derive(Debug, Default)]
+struct Sweep {
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+}
+
+#[derive(Debug, Default)]
+struct Wave {
+    enabled: bool,
+    dac: bool,
+    level: u8,
+    period: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    length: Length,
+}
+
+#[derive(Debug, Default)]
+struct Noise {
+    enabled: bool,
+    dac: bool,
+    timer: u32,
+    lfsr: u16,
+    length: Length,
+    envelope: Envelope,
+}
+
+#[derive(Debug, Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+#[derive(Debug, Default)]
+struct Envelope {
+    volume: u8,
+    up: bool,
+    pace: u8,
+    timer: u8,
+}
+
+impl Apu {
+    pub(super) fn new(sample_rate: u32) -> Self {
+        Self {
+            regs: [0; 0x30],
+            ch1: Default::default(),
+            sweep: Default::default(),
+            ch2: Default::default(),
+            ch3: Default::default(),
+            ch4: Default::default(),
+            frame_seq_timer: FRAME_SEQ_PERIOD,
+            frame_seq_step: 0,
+            sample_rate,
+            now: 0,
+            nb_samples: 0,
+            sample_acc: [0.0; 2],
+            hpf_charge: HPF_CHARGE.powf(f64::from(CPU_CLOCK) / f64::from(sample_rate)),
+            hpf_caps: [0.0; 2],
+        }
+    }
+
+    /// Emulates the given amount of CPU cycles, appending the samples that they produce.
+    pub(super) fn run(&mut self, nb_cycles: u32, samples: &mut Vec<[i16; 2]>) {
+        let end = self.now + u64::from(nb_cycles);
+        while self.now < end {
+            let next_sample =
+                (self.nb_samples + 1) * u64::from(CPU_CLOCK) / u64::from(self.sample_rate);
+            // Run until something happens.
+            let step = [
+                end - self.now,
+                next_sample - self.now,
+                self.frame_seq_timer.into(),
+                self.ch1.next_event(),
+                self.ch2.next_event(),
+                self.ch3.next_event(),
+                self.ch4.next_event(),
+            ]
+            .into_iter()
+            .min()
+            .unwrap();
+            let step32 = step as u32; // All of the timers are 32-bit.
+
+            let [left, right] = self.output();
+            self.sample_acc[0] += left * step as f64;
+            self.sample_acc[1] += right * step as f64;
+            self.now += step;
+
+            self.ch1.advance(step32);
+            self.ch2.advance(step32);
+            self.ch3.advance(step32, &self.regs);
+            self.ch4.advance(step32, self.regs[reg_idx(NR43)]);
+            self.frame_seq_timer -= step32;
+            if self.frame_seq_timer == 0 {
+                self.frame_seq_timer = FRAME_SEQ_PERIOD;
+                self.clock_frame_seq();
+            }
+
+            if self.now == next_sample {
+                let sample_len = (next_sample
+                    - self.nb_samples * u64::from(CPU_CLOCK) / u64::from(self.sample_rate))
+                    as f64;
+                self.nb_samples += 1;
+                let sample = std::array::from_fn(|i| {
+                    // Emulate the high-pass filter, which removes the DACs' DC offset.
+                    let input = std::mem::take(&mut self.sample_acc[i]) / sample_len;
+                    let output = input - self.hpf_caps[i];
+                    self.hpf_caps[i] = input - output * self.hpf_charge;
+                    // Once the DC offset is removed, the output can swing twice as far as the input,
+                    // so leave some headroom.
+                    (output * f64::from(i16::MAX) / 2.0).clamp(i16::MIN.into(), i16::MAX.into())
+                        as i16
+                });
+                samples.push(sample);
+            }
+        }
+    }
+
+    fn output(&self) -> [f64; 2] {
+        let dac = |enabled: bool, amplitude: u8| {
+            if enabled {
+                1.0 - f64::from(amplitude) / 7.5
+            } else {
+                0.0
+            }
+        };
+        let channels = [
+            dac(self.ch1.dac, self.ch1.amplitude()),
+            dac(self.ch2.dac, self.ch2.amplitude()),
+            dac(self.ch3.dac, self.ch3.amplitude()),
+            dac(self.ch4.dac, self.ch4.amplitude()),
+        ];
+
+        let panning = self.regs[reg_idx(NR51)];
+        let volume = self.regs[reg_idx(NR50)];
+        let mix = |shift: u8| {
+            let sum: f64 = channels
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| panning & 1 << (i + usize::from(shift)) != 0)
+                .map(|(_, output)| output)
+                .sum();
+            let volume = f64::from((volume >> shift & 7) + 1) / 8.0;
+            sum * volume / 4.0
+        };
+        [mix(4), mix(0)]
+    }
+
+    fn clock_frame_seq(&mut self) {
+        if self.frame_seq_step % 2 == 0 {
+            self.ch1.length.clock(&mut self.ch1.enabled);
+            self.ch2.length.clock(&mut self.ch2.enabled);
+            self.ch3.length.clock(&mut self.ch3.enabled);
+            self.ch4.length.clock(&mut self.ch4.enabled);
+        }
+        if self.frame_seq_step % 4 == 2 {
+            self.clock_sweep();
+        }
+        if self.frame_seq_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_seq_step = (self.frame_seq_step + 1) % 8;
+    }
+
+    fn clock_sweep(&mut self) {
+        let nr10 = self.regs[reg_idx(NR10)];
+        let pace = nr10 >> 4 & 7;
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.timer = if pace == 0 { 8 } else { pace };
+        if !self.sweep.enabled || pace == 0 {
+            return;
+        }
+
+        if let Some(period) = self.next_sweep_period() {
+            if nr10 & 7 != 0 {
+                self.sweep.shadow = period;
+                self.ch1.period = period;
+                self.regs[reg_idx(NR13)] = period as u8;
+                self.regs[reg_idx(NR14)] = self.regs[reg_idx(NR14)] & !7 | (period >> 8) as u8;
+                // The new period is checked for overflow again, but not written back.
+                self.next_sweep_period();
+            }
+        }
+    }
+
+    /// Computes the next period that the sweep would apply, disabling CH1 if that overflows.
+    fn next_sweep_period(&mut self) -> Option<u16> {
+        let nr10 = self.regs[reg_idx(NR10)];
+        let delta = self.sweep.shadow >> (nr10 & 7);
+        let period = if nr10 & 0x08 != 0 {
+            self.sweep.shadow - delta
+        } else {
+            self.sweep.shadow + delta
+        };
+        if period > 0x7FF {
+            self.ch1.enabled = false;
+            None
+        } else {
+            Some(period)
+        }
+    }
+
+    fn trigger_sweep(&mut self) {
+        let nr10 = self.regs[reg_idx(NR10)];
+        let pace = nr10 >> 4 & 7;
+        self.sweep.shadow = self.ch1.period;
+        self.sweep.timer = if pace == 0 { 8 } else { pace };
+        self.sweep.enabled = pace != 0 || nr10 & 7 != 0;
+        if nr10 & 7 != 0 {
+            self.next_sweep_period();
+        }
+    }
+}
+
+impl Registers for Apu {
+    fn read(&mut self, reg: u8) -> u8 {
+        let value = self.regs[reg_idx(reg)];
+        match reg {
+            NR52 => {
+                let statuses = [
+                    self.ch1.enabled,
+                    self.ch2.enabled,
+                    self.ch3.enabled,
+                    self.ch4.enabled,
+                ]
+                .into_iter()
+                .enumerate()
+                .fold(0, |statuses, (i, enabled)| statuses | (enabled as u8) << i);
+                value & 0x80 | 0x70 | statuses
+            }
+            NR10..=0x3F => value | read_mask(reg),
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        if !(NR10..WAVE_RAM + 16).contains(&reg) {
+            return;
+        }
+        self.regs[reg_idx(reg)] = value;
+
+        match reg {
+            NR10 => {}
+            NR11 => self.ch1.write_nrx1(value),
+            NR12 => self.ch1.write_nrx2(value),
+            NR13 => self.ch1.period = self.ch1.period & 0x700 | u16::from(value),
+            NR14 => {
+                let triggered = self.ch1.write_nrx4(value, self.regs[reg_idx(NR12)]);
+                if triggered {
+                    self.trigger_sweep();
+                }
+            }
+
+            NR21 => self.ch2.write_nrx1(value),
+            NR22 => self.ch2.write_nrx2(value),
+            NR23 => self.ch2.period = self.ch2.period & 0x700 | u16::from(value),
+            NR24 => {
+                self.ch2.write_nrx4(value, self.regs[reg_idx(NR22)]);
+            }
+
+            NR30 => {
+                self.ch3.dac = value & 0x80 != 0;
+                if !self.ch3.dac {
+                    self.ch3.enabled = false;
+                }
+            }
+            NR31 => self.ch3.length.counter = 256 - u16::from(value),
+            NR32 => self.ch3.level = value >> 5 & 3,
+            NR33 => self.ch3.period = self.ch3.period & 0x700 | u16::from(value),
+            NR34 => {
+                self.ch3.period = self.ch3.period & 0xFF | u16::from(value & 7) << 8;
+                self.ch3.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.ch3.enabled = self.ch3.dac;
+                    if self.ch3.length.counter == 0 {
+                        self.ch3.length.counter = 256;
+                    }
+                    self.ch3.timer = self.ch3.reload();
+                    self.ch3.position = 0;
+                }
+            }
+
+            NR41 => self.ch4.length.counter = 64 - u16::from(value & 0x3F),
+            NR42 => {
+                self.ch4.dac = value & 0xF8 != 0;
+                if !self.ch4.dac {
+                    self.ch4.enabled = false;
+                }
+            }
+            NR43 => {}
+            NR44 => {
+                self.ch4.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.ch4.enabled = self.ch4.dac;
+                    if self.ch4.length.counter == 0 {
+                        self.ch4.length.counter = 64;
+                    }
+                    self.ch4.envelope.trigger(self.regs[reg_idx(NR42)]);
+                    self.ch4.timer = Noise::reload(self.regs[reg_idx(NR43)]);
+                    self.ch4.lfsr = 0x7FFF;
+                }
+            }
+
+            // NR5x are only read when mixing, and wave RAM when CH3 advances.
+            _ => {}
+        }
+    }
+}
+
+fn reg_idx(reg: u8) -> usize {
+    usize::from(reg - NR10)
+}
+
+impl Pulse {
+    fn write_nrx1(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length.counter = 64 - u16::from(value & 0x3F);
+    }
+
+    fn write_nrx2(&mut self, value: u8) {
+        self.dac = value & 0xF8 != 0;
+        if !self.dac {
+            self.enabled = false;
+        }
+    }
+
+    /// Returns whether the channel was triggered.
+    fn write_nrx4(&mut self, value: u8, nrx2: u8) -> bool {
+        self.period = self.period & 0xFF | u16::from(value & 7) << 8;
+        self.length.enabled = value & 0x40 != 0;
+        let triggered = value & 0x80 != 0;
+        if triggered {
+            self.enabled = self.dac;
+            if self.length.counter == 0 {
+                self.length.counter = 64;
+            }
+            self.timer = (2048 - u32::from(self.period)) * 4;
+            self.envelope.trigger(nrx2);
+        }
+        triggered
+    }
+
+    fn amplitude(&self) -> u8 {
+        if self.enabled && DUTY_CYCLES[usize::from(self.duty)] & 0x80 >> self.duty_pos != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn next_event(&self) -> u64 {
+        if self.enabled {
+            self.timer.into()
+        } else {
+            u64::MAX
+        }
+    }
+
+    fn advance(&mut self, nb_cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= nb_cycles;
+        if self.timer == 0 {
+            self.timer = (2048 - u32::from(self.period)) * 4;
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+    }
+}
+
+impl Wave {
+    fn reload(&self) -> u32 {
+        (2048 - u32::from(self.period)) * 2
+    }
+
+    fn amplitude(&self) -> u8 {
+        match self.level {
+            _ if !self.enabled => 0,
+            0 => 0,
+            level => self.sample >> (level - 1),
+        }
+    }
+
+    fn next_event(&self) -> u64 {
+        if self.enabled {
+            self.timer.into()
+        } else {
+            u64::MAX
+        }
+    }
+
+    fn advance(&mut self, nb_cycles: u32, regs: &[u8; 0x30]) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= nb_cycles;
+        if self.timer == 0 {
+            self.timer = self.reload();
+            self.position = (self.position + 1) % 32;
+            let byte = regs[reg_idx(WAVE_RAM) + usize::from(self.position / 2)];
+            self.sample = if self.position % 2 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+}
+
+impl Noise {
+    /// How many cycles between LFSR clocks; `u32::MAX` if it isn't clocked at all.
+    fn reload(nr43: u8) -> u32 {
+        match nr43 >> 4 {
+            // The LFSR isn't clocked at all with these shifts.
+            14.. => u32::MAX,
+            shift => NOISE_DIVISORS[usize::from(nr43 & 7)] << shift,
+        }
+    }
+
+    fn amplitude(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn next_event(&self) -> u64 {
+        if self.enabled {
+            self.timer.into()
+        } else {
+            u64::MAX
+        }
+    }
+
+    fn advance(&mut self, nb_cycles: u32, nr43: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == u32::MAX {
+            // NR43 may have been written to since.
+            self.timer = Self::reload(nr43);
+            return;
+        }
+        self.timer -= nb_cycles;
+        if self.timer == 0 {
+            self.timer = Self::reload(nr43);
+            let feedback = (self.lfsr ^ self.lfsr >> 1) & 1;
+            self.lfsr = self.lfsr >> 1 | feedback << 14;
+            if nr43 & 0x08 != 0 {
+                self.lfsr = self.lfsr & !(1 << 6) | feedback << 6;
+            }
+        }
+    }
+}
+
+impl Length {
+    fn clock(&mut self, channel_enabled: &mut bool) {
+        if self.enabled && self.counter != 0 {
+            self.counter -= 1;
+            if self.counter == 0 {
+                *channel_enabled = false;
+            }
+        }
+    }
+}
+
+impl Envelope {
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.up = nrx2 & 0x08 != 0;
+        self.pace = nrx2 & 7;
+        self.timer = self.pace;
+    }
+
+    fn clock(&mut self) {
+        if self.pace == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.pace;
+            if self.up && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.up && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
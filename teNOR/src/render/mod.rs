@@ -0,0 +1,148 @@
+//! Synthesises songs to audio, by running a model of the driver against a model of the APU.
+//!
+//! This allows listening to songs (and comparing how they sound) without an emulator or hUGETracker.
+
+use std::io::{self, Write};
+
+use crate::{
+    driver::{Driver, Registers, NR50, NR51, NR52},
+    playback, Song,
+};
+
+mod apu;
+use apu::Apu;
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    /// How many times the part of the song that loops is played, after the part that doesn't.
+    pub nb_loops: u32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            nb_loops: 1,
+        }
+    }
+}
+
+/// Renders the song to a 16-bit stereo WAV file.
+///
+/// The song is assumed to be valid (see [`validate`](crate::validate())).
+pub fn render(song: &Song, options: &RenderOptions, mut output: impl Write) -> io::Result<()> {
+    let nb_ticks = playback::play_through(song).nb_ticks(options.nb_loops);
+    let cycles_per_tick = playback::cycles_per_tick(song.timer_divider);
+
+    let mut apu = Apu::new(options.sample_rate);
+    // Set up the APU like games typically do before starting the driver.
+    apu.write(NR52, 0x80);
+    apu.write(NR50, 0x77);
+    apu.write(NR51, 0xFF);
+
+    let mut driver = Driver::new(song, 0, &mut apu);
+    let mut samples = vec![];
+    for _ in 0..nb_ticks {
+        driver.tick(&mut apu);
+        apu.run(cycles_per_tick, &mut samples);
+    }
+
+    write_wav(&samples, options.sample_rate, &mut output)?;
+    output.flush()
+}
+
+fn write_wav(samples: &[[i16; 2]], sample_rate: u32, output: &mut dyn Write) -> io::Result<()> {
+    const NB_CHANNELS: u16 = 2;
+    const BLOCK_SIZE: u16 = NB_CHANNELS * 2;
+    let data_size = u32::try_from(samples.len() * usize::from(BLOCK_SIZE))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "WAV file is too long"))?;
+
+    output.write_all(b"RIFF")?;
+    output.write_all(&(4 + (8 + 16) + (8 + data_size)).to_le_bytes())?;
+    output.write_all(b"WAVE")?;
+
+    output.write_all(b"fmt ")?;
+    output.write_all(&16u32.to_le_bytes())?;
+    output.write_all(&1u16.to_le_bytes())?; // PCM.
+    output.write_all(&NB_CHANNELS.to_le_bytes())?;
+    output.write_all(&sample_rate.to_le_bytes())?;
+    output.write_all(&(sample_rate * u32::from(BLOCK_SIZE)).to_le_bytes())?;
+    output.write_all(&BLOCK_SIZE.to_le_bytes())?;
+    output.write_all(&16u16.to_le_bytes())?; // Bits per sample.
+
+    output.write_all(b"data")?;
+    output.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        for channel in sample {
+            output.write_all(&channel.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{playback::CPU_CLOCK, uge::test_song};
+
+    /// A sample rate that divides the CPU clock, so that each sample spans the same number of cycles.
+    const SAMPLE_RATE: u32 = CPU_CLOCK / 256;
+
+    #[test]
+    fn render_test_song() {
+        let song = test_song();
+        let mut wav = vec![];
+        render(
+            &song,
+            &RenderOptions {
+                sample_rate: SAMPLE_RATE,
+                nb_loops: 1,
+            },
+            &mut wav,
+        )
+        .expect("Rendering to a `Vec` shouldn't fail");
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(wav[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        assert_eq!(u16_at(20), 1); // PCM.
+        assert_eq!(u16_at(22), 2); // Stereo.
+        assert_eq!(u32_at(24), SAMPLE_RATE);
+        assert_eq!(u32_at(28), SAMPLE_RATE * 4);
+        assert_eq!(u16_at(32), 4);
+        assert_eq!(u16_at(34), 16);
+        assert_eq!(&wav[36..40], b"data");
+
+        let samples: Vec<[i16; 2]> = wav[44..]
+            .chunks_exact(4)
+            .map(|sample| {
+                [
+                    i16::from_le_bytes([sample[0], sample[1]]),
+                    i16::from_le_bytes([sample[2], sample[3]]),
+                ]
+            })
+            .collect();
+        assert_eq!(u32_at(40) as usize, samples.len() * 4);
+        let nb_ticks = playback::play_through(&song).nb_ticks(1);
+        let cycles_per_tick = playback::cycles_per_tick(song.timer_divider);
+        assert_eq!(
+            samples.len() as u64,
+            nb_ticks * u64::from(cycles_per_tick) / 256
+        );
+
+        // The first note plays on CH2 (and CH4) at row 3, i.e. tick 21; nothing plays before it.
+        let tick_sample = |tick: u64| (tick * u64::from(cycles_per_tick) / 256) as usize;
+        assert!(samples[tick_sample(14)..tick_sample(21)]
+            .iter()
+            .all(|&sample| sample == [0, 0]));
+        let row = &samples[tick_sample(21)..tick_sample(28)];
+        assert!(row.iter().any(|sample| sample[0] > 0 && sample[1] > 0));
+        assert!(row.iter().any(|sample| sample[0] < 0 && sample[1] < 0));
+    }
+}
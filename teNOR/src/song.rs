@@ -1,6 +1,8 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt::Display};
 
-#[derive(Debug, Clone)]
+use crate::optimise::InstrKind;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Song<'input> {
     pub name: Cow<'input, str>,
     pub artist: Cow<'input, str>,
@@ -15,11 +17,23 @@ pub struct Song<'input> {
 
     pub patterns: Vec<Pattern>,
     pub order_matrix: Vec<[usize; 4]>,
-    #[allow(dead_code)]
     pub routines: RoutineBank<'input>,
+
+    pub leftovers: SongLeftovers,
+}
+
+/// What `.uge` files store about a song, but that has no effect on it; this is only kept so that
+/// songs can be written back as they were read (see [`write_song`](crate::write_song())).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SongLeftovers {
+    /// hUGETracker keeps the timer divider even while timer-based tempo is disabled.
+    pub timer_divider: u8,
+    /// The IDs of the patterns that were missing from the file; they are treated as blank, and
+    /// omitted again when written back if they still are.
+    pub missing_patterns: Vec<usize>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InstrCollection<'input> {
     pub duty: InstrumentBank<'input>,
     pub wave: InstrumentBank<'input>,
@@ -28,15 +42,82 @@ pub struct InstrCollection<'input> {
 
 pub type InstrumentBank<'input> = [Instrument<'input>; 15];
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Instrument<'input> {
     pub name: Cow<'input, str>,
     pub length: Option<u8>,
     pub kind: InstrumentKind,
     pub subpattern: Option<Subpattern>,
+
+    pub leftovers: InstrumentLeftovers,
+}
+
+/// What `.uge` files store about an instrument, but that has no effect on it: hUGETracker keeps the
+/// settings of every kind of instrument (so that switching kinds doesn't lose them), the length and
+/// the subpattern even while they are disabled, and 64 subpattern rows although only 32 are used.
+///
+/// This is only kept so that songs can be written back as they were read; the corresponding fields
+/// of [`Instrument`] take precedence over these whenever they are set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstrumentLeftovers {
+    pub length: u8,
+    pub initial_volume: u8,
+    pub envelope_dir: EnvelopeDirection,
+    pub envelope_pace: u8,
+    pub sweep_time: u8,
+    pub sweep_dir: SweepDirection,
+    pub sweep_shift: u8,
+    pub duty: DutyType,
+    pub output_level: WaveOutputLevel,
+    pub wave_id: u8,
+    pub lfsr_width: LfsrWidth,
+    pub subpattern: Box<[RawSubpatternCell; 64]>,
 }
 
-#[derive(Debug, Clone)]
+impl Default for InstrumentLeftovers {
+    fn default() -> Self {
+        Self {
+            length: 0,
+            initial_volume: 0,
+            envelope_dir: EnvelopeDirection::Up,
+            envelope_pace: 0,
+            sweep_time: 0,
+            sweep_dir: SweepDirection::Up,
+            sweep_shift: 0,
+            duty: DutyType::Percent12_5,
+            output_level: WaveOutputLevel::Mute,
+            wave_id: 0,
+            lfsr_width: LfsrWidth::Fifteen,
+            subpattern: Box::new([Default::default(); 64]),
+        }
+    }
+}
+
+/// A subpattern row, as stored in `.uge` files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawSubpatternCell {
+    pub offset: u8,
+    /// Unused, but stored nonetheless.
+    pub instrument: u8,
+    /// 0 for "go to the next row", otherwise the row to jump to, 1-indexed.
+    pub jump_index: u8,
+    pub effect_code: EffectId,
+    pub effect_param: u8,
+}
+
+impl Default for RawSubpatternCell {
+    fn default() -> Self {
+        Self {
+            offset: Note::None as u8,
+            instrument: 0,
+            jump_index: 0,
+            effect_code: EffectId::Arpeggio,
+            effect_param: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InstrumentKind {
     Square {
         initial_volume: u8,
@@ -239,3 +320,148 @@ pub enum EffectId {
     NoteCut = 0xE,
     SetTempo = 0xF,
 }
+
+/// Formats the note like hUGETracker does, e.g. `C#5`, or `...` if there is none.
+impl Display for Note {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const NAMES: [&str; 12] = [
+            "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-",
+        ];
+
+        match self {
+            Self::None => f.write_str("..."),
+            note => {
+                let idx = *note as u8;
+                write!(f, "{}{}", NAMES[usize::from(idx % 12)], idx / 12 + 3)
+            }
+        }
+    }
+}
+
+/// Formats the cell like hUGETracker does, e.g. `C#5 01 C0F`, with dots for blank fields.
+impl Display for PatternCell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.note)?;
+        match self.instrument {
+            0 => f.write_str("..")?,
+            instrument => write!(f, "{instrument:02}")?,
+        }
+        match (self.effect_code, self.effect_param) {
+            (EffectId::Arpeggio, 0) => f.write_str(" ..."),
+            (effect_code, effect_param) => {
+                write!(f, " {:X}{effect_param:02X}", effect_code as u8)
+            }
+        }
+    }
+}
+
+/// Where something is in a song, e.g. a problem found by [`validate`](crate::validate()); all
+/// indices are 0-based.
+#[derive(Debug, Clone, Copy)]
+pub enum Location {
+    /// A row of the main grid, as reached through the order.
+    Row {
+        order_idx: usize,
+        /// 0 for CH1, 1 for CH2, and so on.
+        channel: usize,
+        pattern_id: usize,
+        row: usize,
+    },
+    SubpatternRow {
+        kind: InstrKind,
+        /// 1-based, like in cells.
+        instrument: u8,
+        row: usize,
+    },
+    Pattern {
+        pattern_id: usize,
+    },
+    OrderRow {
+        order_idx: usize,
+    },
+    Instrument {
+        kind: InstrKind,
+        /// 1-based, like in cells.
+        instrument: u8,
+    },
+    Routine {
+        routine_id: usize,
+    },
+}
+
+fn kind_name(kind: InstrKind) -> &'static str {
+    match kind {
+        InstrKind::Duty => "duty",
+        InstrKind::Wave => "wave",
+        InstrKind::Noise => "noise",
+    }
+}
+
+impl Location {
+    /// Serialises the location as a JSON object, whose `kind` field tells which variant it is.
+    pub fn to_json(&self) -> String {
+        match self {
+            Self::Row {
+                order_idx,
+                channel,
+                pattern_id,
+                row,
+            } => format!(
+                r#"{{"kind":"row","order_row":{order_idx},"channel":{channel},"pattern":{pattern_id},"row":{row}}}"#
+            ),
+            Self::SubpatternRow {
+                kind,
+                instrument,
+                row,
+            } => format!(
+                r#"{{"kind":"subpattern_row","instrument_kind":"{}","instrument":{instrument},"row":{row}}}"#,
+                kind_name(*kind),
+            ),
+            Self::Pattern { pattern_id } => {
+                format!(r#"{{"kind":"pattern","pattern":{pattern_id}}}"#)
+            }
+            Self::OrderRow { order_idx } => {
+                format!(r#"{{"kind":"order_row","order_row":{order_idx}}}"#)
+            }
+            Self::Instrument { kind, instrument } => format!(
+                r#"{{"kind":"instrument","instrument_kind":"{}","instrument":{instrument}}}"#,
+                kind_name(*kind),
+            ),
+            Self::Routine { routine_id } => {
+                format!(r#"{{"kind":"routine","routine":{routine_id}}}"#)
+            }
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Row {
+                order_idx,
+                channel,
+                pattern_id,
+                row,
+            } => write!(
+                f,
+                "order row {order_idx}, CH{} (pattern {pattern_id}), row {row}",
+                channel + 1,
+            ),
+            Self::SubpatternRow {
+                kind,
+                instrument,
+                row,
+            } => write!(
+                f,
+                "{} instrument {instrument}'s subpattern, row {row}",
+                kind_name(*kind),
+            ),
+            Self::Pattern { pattern_id } => write!(f, "pattern {pattern_id}"),
+            Self::OrderRow { order_idx } => write!(f, "order row {order_idx}"),
+            Self::Instrument { kind, instrument } => {
+                write!(f, "{} instrument {instrument}", kind_name(*kind))
+            }
+            Self::Routine { routine_id } => write!(f, "routine {routine_id}"),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Converts songs between playback methods (VBlank or timer, and timer dividers), keeping them
+//! playing at the same speed as much as possible.
+
+use crate::{
+    json, playback,
+    song::{EffectId, Song},
+    validate::Severity,
+};
+
+/// How one of the song's tempos was converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TempoChange {
+    /// How many ticks each row lasted before the conversion (`0` meaning 256, like for the driver).
+    pub old_ticks: u16,
+    /// How many ticks each row lasts after the conversion.
+    pub new_ticks: u16,
+    /// Whether this is the song's initial tempo.
+    pub initial: bool,
+    /// How many `Fxx` effects set this tempo.
+    pub nb_effects: usize,
+}
+
+impl TempoChange {
+    /// How much faster (if positive) or slower (if negative) the song plays after the conversion,
+    /// as a ratio; this is only non-zero due to rounding.
+    pub fn error(&self, old_divider: Option<u8>, new_divider: Option<u8>) -> f64 {
+        playback::bpm(self.new_ticks, new_divider) / playback::bpm(self.old_ticks, old_divider)
+            - 1.0
+    }
+
+    /// Describes the [error](Self::error) that the conversion introduced, as a warning.
+    pub fn message(&self, old_divider: Option<u8>, new_divider: Option<u8>) -> String {
+        let error = self.error(old_divider, new_divider);
+        format!(
+            "Converting the tempo makes rows of {} tick{} play {:.1}% {}",
+            self.old_ticks,
+            if self.old_ticks == 1 { "" } else { "s" },
+            error.abs() * 100.0,
+            if error < 0.0 { "slower" } else { "faster" },
+        )
+    }
+
+    /// Serialises the [warning](Self::message) like a [`Diagnostic`](crate::validate::Diagnostic)
+    /// (without a location); `file` is the song that was converted.
+    pub fn to_json(
+        &self,
+        file: &str,
+        (old_divider, new_divider): (Option<u8>, Option<u8>),
+    ) -> String {
+        json::diagnostic(
+            file,
+            Severity::Warning,
+            None,
+            &self.message(old_divider, new_divider),
+            None,
+            None,
+        )
+    }
+}
+
+/// A note delay or cut (`7xx` or `Exx`) whose parameter the conversion rounded down to 0, which
+/// makes it act on the very tick the row starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroedEffect {
+    pub pattern_id: usize,
+    pub row: usize,
+    pub effect_code: EffectId,
+    /// The parameter before the conversion.
+    pub old_param: u8,
+}
+
+impl ZeroedEffect {
+    /// Describes how the effect's behaviour changed, as a warning.
+    pub fn message(&self) -> String {
+        let effect_code = self.effect_code as u8;
+        format!(
+            "Converting the tempo rounds `{effect_code:X}{:02X}` down to `{effect_code:X}00`, so it {} on the row's first tick",
+            self.old_param,
+            match self.effect_code {
+                EffectId::NoteCut => "cuts the note",
+                _ => "doesn't delay the note anymore, playing it",
+            },
+        )
+    }
+
+    /// Serialises the [warning](Self::message) like a [`Diagnostic`](crate::validate::Diagnostic);
+    /// `file` is the song that was converted.
+    pub fn to_json(&self, file: &str) -> String {
+        json::diagnostic(
+            file,
+            Severity::Warning,
+            None,
+            &self.message(),
+            Some((self.effect_code, self.old_param)),
+            Some(&format!(
+                r#"{{"kind":"pattern_row","pattern":{},"row":{}}}"#,
+                self.pattern_id, self.row,
+            )),
+        )
+    }
+}
+
+/// Switches the song to another playback method, scaling its tempo (and everything else that is
+/// counted in ticks, i.e. note delays and cuts) so that it plays as close as possible to how it did.
+///
+/// Returns how each of the tempos used in the song was converted, initial tempo first, and the note
+/// delays and cuts that don't last any ticks anymore.
+/// Effects that work per tick (slides, vibrato, arpeggios) are not changed, so they may sound
+/// different anyway.
+pub fn convert_tempo(
+    song: &mut Song,
+    timer_divider: Option<u8>,
+) -> (Vec<TempoChange>, Vec<ZeroedEffect>) {
+    let ratio = f64::from(playback::cycles_per_tick(song.timer_divider))
+        / f64::from(playback::cycles_per_tick(timer_divider));
+    let scale = |ticks: u16, min: u16, max: u16| {
+        ((f64::from(ticks) * ratio).round() as u16).clamp(min, max)
+    };
+    let row_ticks = |param: u8| match param {
+        0 => 256,
+        ticks => u16::from(ticks),
+    };
+    let mut changes = vec![];
+    let mut convert_tempo = |param: &mut u8, initial: bool| {
+        let old_ticks = row_ticks(*param);
+        let new_ticks = scale(old_ticks, 1, 256);
+        *param = new_ticks as u8; // 256 wraps to 0, which the driver treats as such.
+
+        match changes
+            .iter_mut()
+            .find(|change: &&mut TempoChange| change.old_ticks == old_ticks)
+        {
+            Some(change) => {
+                change.initial |= initial;
+                change.nb_effects += usize::from(!initial);
+            }
+            None => changes.push(TempoChange {
+                old_ticks,
+                new_ticks,
+                initial,
+                nb_effects: usize::from(!initial),
+            }),
+        }
+    };
+
+    let mut zeroed_effects = vec![];
+
+    convert_tempo(&mut song.ticks_per_row, true);
+    for (pattern_id, pattern) in song.patterns.iter_mut().enumerate() {
+        for (row, cell) in pattern.iter_mut().enumerate() {
+            match cell.effect_code {
+                EffectId::SetTempo => convert_tempo(&mut cell.effect_param, false),
+                EffectId::NoteDelay | EffectId::NoteCut => {
+                    let old_param = cell.effect_param;
+                    cell.effect_param = scale(old_param.into(), 0, 255) as u8;
+                    if old_param != 0 && cell.effect_param == 0 {
+                        zeroed_effects.push(ZeroedEffect {
+                            pattern_id,
+                            row,
+                            effect_code: cell.effect_code,
+                            old_param,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    let instruments = &mut song.instruments;
+    for bank in [
+        &mut instruments.duty,
+        &mut instruments.wave,
+        &mut instruments.noise,
+    ] {
+        for subpattern in bank
+            .iter_mut()
+            .filter_map(|instr| instr.subpattern.as_mut())
+        {
+            // fortISSimO ignores these, but hUGEDriver doesn't (see the `tempo-in-subpatterns` lint).
+            for cell in subpattern {
+                if cell.effect_code == EffectId::SetTempo {
+                    convert_tempo(&mut cell.effect_param, false);
+                }
+            }
+        }
+    }
+
+    song.timer_divider = timer_divider;
+    (changes, zeroed_effects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uge::test_song;
+
+    #[test]
+    fn zeroed_effects() {
+        let mut song = test_song();
+        // A fast timer's ticks are much shorter than frames.
+        song.timer_divider = Some(250);
+        song.patterns[0][4].effect_code = EffectId::NoteCut;
+        song.patterns[0][4].effect_param = 3;
+        song.patterns[0][5].effect_code = EffectId::NoteDelay;
+        song.patterns[0][5].effect_param = 0;
+        song.patterns[2][6].effect_code = EffectId::NoteDelay;
+        song.patterns[2][6].effect_param = 200;
+
+        let (changes, zeroed_effects) = convert_tempo(&mut song, None);
+        assert_eq!(song.timer_divider, None);
+        assert_eq!(song.ticks_per_row, 1);
+        assert_eq!(changes[0].old_ticks, 7);
+        assert_eq!(song.patterns[2][6].effect_param, 17);
+        // Only the parameters that were non-zero are reported.
+        assert_eq!(
+            zeroed_effects,
+            [ZeroedEffect {
+                pattern_id: 0,
+                row: 4,
+                effect_code: EffectId::NoteCut,
+                old_param: 3,
+            }]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Logs the writes that fortISSimO makes to the APU's registers, tick by tick.
+//!
+//! This is meant as a reference for how the driver should behave: unlike audio, the log can be
+//! compared exactly, e.g. against a trace of the actual driver running in an emulator.
+
+use std::io::{self, Write};
+
+use crate::{
+    driver::{self, Driver, Registers, NR10, WAVE_RAM},
+    playback, Song,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceOptions {
+    /// How many times the part of the song that loops is played, after the part that doesn't.
+    pub nb_loops: u32,
+    /// What the game does to the driver, and before which tick; events for tick 0 happen before
+    /// the song is even selected. Must be sorted by tick.
+    pub events: Vec<(u64, Event)>,
+}
+
+/// Something that a game does to the driver while it's playing, typically around a sound effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Writes this to `hUGE_MutedChannels`.
+    SetMutedChannels(u8),
+    /// Writes `hUGE_NO_WAVE` to `hUGE_LoadedWaveID`.
+    InvalidateWave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegWrite {
+    /// The low byte of the register's address.
+    pub reg: u8,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RegisterLog {
+    /// The writes performed by `hUGE_SelectSong`.
+    pub init: Vec<RegWrite>,
+    /// The writes performed by each call to `hUGE_TickSound`.
+    pub ticks: Vec<Vec<RegWrite>>,
+}
+
+/// The APU's registers, without anything behind them.
+#[derive(Debug)]
+struct RegisterFile {
+    regs: [u8; 0x100],
+    writes: Vec<RegWrite>,
+}
+
+impl Registers for RegisterFile {
+    fn read(&mut self, reg: u8) -> u8 {
+        self.regs[usize::from(reg)] | driver::read_mask(reg)
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        self.regs[usize::from(reg)] = value;
+        self.writes.push(RegWrite { reg, value });
+    }
+}
+
+/// Plays the song (which is assumed to be valid, see [`validate`](crate::validate())), and logs
+/// every write to the APU's registers.
+///
+/// The APU is assumed to have been set up like in [`render`](crate::render()) beforehand.
+pub fn trace(song: &Song, options: &TraceOptions) -> RegisterLog {
+    let nb_ticks = playback::play_through(song).nb_ticks(options.nb_loops);
+    let mut regs = RegisterFile {
+        regs: [0; 0x100],
+        writes: vec![],
+    };
+    regs.regs[usize::from(driver::NR50)] = 0x77;
+    regs.regs[usize::from(driver::NR51)] = 0xFF;
+    regs.regs[usize::from(driver::NR52)] = 0x80;
+
+    let mut events = options.events.iter().peekable();
+    let mut muted_channels = 0;
+    let mut invalidate_wave = false;
+    while let Some((_, event)) = events.next_if(|(tick, _)| *tick == 0) {
+        match event {
+            Event::SetMutedChannels(mask) => muted_channels = *mask,
+            // `hUGE_SelectSong` already does this.
+            Event::InvalidateWave => invalidate_wave = true,
+        }
+    }
+    let mut driver = Driver::new(song, muted_channels, &mut regs);
+    if invalidate_wave {
+        driver.invalidate_wave();
+    }
+    let init = std::mem::take(&mut regs.writes);
+
+    let ticks = (0..nb_ticks)
+        .map(|tick| {
+            while let Some((_, event)) = events.next_if(|(at, _)| *at <= tick) {
+                match event {
+                    Event::SetMutedChannels(mask) => driver.set_muted_channels(*mask),
+                    Event::InvalidateWave => driver.invalidate_wave(),
+                }
+            }
+            driver.tick(&mut regs);
+            std::mem::take(&mut regs.writes)
+        })
+        .collect();
+
+    RegisterLog { init, ticks }
+}
+
+impl RegisterLog {
+    /// Writes the log as text, one write per line: the tick (or `init` for `hUGE_SelectSong`), the
+    /// register's address, the value written, and the register's name.
+    pub fn write_text(&self, mut output: impl Write) -> io::Result<()> {
+        let mut write_line = |tick: &dyn std::fmt::Display, write: &RegWrite| {
+            writeln!(
+                output,
+                "{tick} ${:04X} ${:02X} {}",
+                0xFF00 | u16::from(write.reg),
+                write.value,
+                reg_name(write.reg),
+            )
+        };
+        for write in &self.init {
+            write_line(&"init", write)?;
+        }
+        for (tick, writes) in self.ticks.iter().enumerate() {
+            for write in writes {
+                write_line(&tick, write)?;
+            }
+        }
+        output.flush()
+    }
+}
+
+fn reg_name(reg: u8) -> String {
+    if reg >= WAVE_RAM {
+        format!("WAVE{:X}", reg - WAVE_RAM)
+    } else {
+        let idx = reg - NR10;
+        format!("NR{}{}", idx / 5 + 1, idx % 5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        driver::{NR24, NR41, NR42, NR43, NR44},
+        uge::test_song,
+    };
+
+    /// How many ticks each of the test song's order rows lasts. Its only note is on row 3 (i.e.
+    /// tick 21) of each, on CH2 and CH4 in even order rows, and on CH1 and CH3 in odd ones.
+    const ORDER_ROW_TICKS: usize = 64 * 7;
+
+    fn trace_song(events: Vec<(u64, Event)>) -> RegisterLog {
+        trace(
+            &test_song(),
+            &TraceOptions {
+                nb_loops: 2,
+                events,
+            },
+        )
+    }
+
+    fn writes(writes: &[(u8, u8)]) -> Vec<RegWrite> {
+        writes
+            .iter()
+            .map(|&(reg, value)| RegWrite { reg, value })
+            .collect()
+    }
+
+    fn loads_wave(writes: &[RegWrite]) -> bool {
+        writes.iter().any(|write| write.reg >= WAVE_RAM)
+    }
+
+    #[test]
+    fn note_trigger() {
+        let log = trace_song(vec![]);
+        assert_eq!(log.ticks.len(), 4 * ORDER_ROW_TICKS);
+
+        assert_eq!(log.ticks[20], []);
+        // CH2 gets its instrument, then C-5's period ($60A) along with the trigger and length bits;
+        // "NR20" is written to as well, like the driver does.
+        assert_eq!(
+            log.ticks[21][..5],
+            writes(&[
+                (NR24 - 4, 0x24),
+                (NR24 - 3, 0xA0),
+                (NR24 - 2, 0xF9),
+                (NR24 - 1, 0x0A),
+                (NR24, 0xC6),
+            ]),
+        );
+        // CH4 is triggered on the same row, with its own instrument.
+        assert_eq!(
+            log.ticks[21][5..9],
+            writes(&[(NR42, 0x47), (NR41, 0x08), (NR43, 0x87), (NR44, 0xC0)]),
+        );
+    }
+
+    #[test]
+    fn wave_reload() {
+        let first_note = ORDER_ROW_TICKS + 21;
+        let second_note = first_note + 2 * ORDER_ROW_TICKS;
+
+        // The wave is only loaded when it isn't already.
+        let log = trace_song(vec![]);
+        assert!(loads_wave(&log.ticks[first_note]));
+        assert!(!loads_wave(&log.ticks[second_note]));
+
+        let log = trace_song(vec![(second_note as u64 - 100, Event::InvalidateWave)]);
+        assert!(loads_wave(&log.ticks[first_note]));
+        let wave_ram: Vec<_> = log.ticks[second_note]
+            .iter()
+            .filter(|write| write.reg >= WAVE_RAM)
+            .copied()
+            .collect();
+        // The wave instrument uses wave 2.
+        assert_eq!(
+            wave_ram,
+            (WAVE_RAM..WAVE_RAM + 16)
+                .map(|reg| RegWrite { reg, value: 0x22 })
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn muted_channel() {
+        let note = 2 * ORDER_ROW_TICKS + 21;
+        let touches_ch2 = |writes: &[RegWrite]| {
+            writes
+                .iter()
+                .any(|write| (NR24 - 4..=NR24).contains(&write.reg))
+        };
+
+        let log = trace_song(vec![]);
+        assert!(touches_ch2(&log.ticks[note]));
+
+        let log = trace_song(vec![(note as u64 - 17, Event::SetMutedChannels(0b0010))]);
+        assert!(!touches_ch2(&log.ticks[note]));
+        // The other channels are unaffected.
+        assert_eq!(
+            log.ticks[note][..4],
+            writes(&[(NR42, 0x47), (NR41, 0x08), (NR43, 0x87), (NR44, 0xC0)]),
+        );
+    }
+}
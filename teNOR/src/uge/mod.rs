@@ -1,4 +1,4 @@
-//! This module is entirely concerned with deserialising `.uge` files.
+//! This module is entirely concerned with (de)serialising `.uge` files.
 //! The type definitions are extracted from `hugedatatypes.pas` and `song.pas`.
 
 use std::{borrow::Cow, fmt::Display, num::TryFromIntError};
@@ -7,28 +7,34 @@ use nom::{
     bytes::complete::take,
     combinator::all_consuming,
     error::context,
-    multi::{fill, length_count},
+    multi::{count, fill, length_count},
     number::complete::le_u32,
     Finish, IResult,
 };
 
 use crate::song::{
-    EffectId, InstrCollection, Instrument, InstrumentBank, InstrumentKind, Pattern, Routine,
-    RoutineBank, Song, Subpattern, Wave, WaveBank,
+    EffectId, InstrCollection, Instrument, InstrumentBank, InstrumentKind, InstrumentLeftovers,
+    Pattern, RawSubpatternCell, Routine, RoutineBank, Song, SongLeftovers, Subpattern,
+    SubpatternCell, Wave, WaveBank,
 };
 
+mod write;
+#[cfg(test)]
+pub(crate) use write::tests::song as test_song;
+pub use write::write_song;
+
 type PResult<'input, O> = IResult<&'input [u8], O, InnerError<'input>>;
 
 pub fn parse_song(input: &[u8]) -> Result<Song<'_>, ParseError<'_>> {
     let (song_input, version) = integer(input).map_err(|_| ParseErrorKind::NotUge)?;
 
-    let parser = match version {
-        n @ 0..=5 => return Err(ParseErrorKind::UnsupportedVersion(n).into()),
-        6 => song_v6,
+    let result = match version {
+        0..=5 => all_consuming(|input| song_v0(version, input))(song_input),
+        6 => all_consuming(song_v6)(song_input),
         n @ 7.. => return Err(ParseErrorKind::TooNew(n).into()),
     };
 
-    match all_consuming(parser)(song_input).finish() {
+    match result.finish() {
         Ok((_input, song)) => Ok(song),
         Err(inner) => Err(ParseErrorKind::BadData { input, inner }.into()),
     }
@@ -40,7 +46,6 @@ pub struct ParseError<'input>(ParseErrorKind<'input>);
 #[derive(Debug, Clone)]
 enum ParseErrorKind<'input> {
     NotUge,
-    UnsupportedVersion(u32),
     TooNew(u32),
     BadData {
         input: &'input [u8],
@@ -58,14 +63,21 @@ impl Display for ParseError<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.0 {
             ParseErrorKind::NotUge => write!(f, "This is too short to be a UGE file"),
-            ParseErrorKind::UnsupportedVersion(n) => write!(f, "UGE version {n} is not supported; please open and save this in hUGETracker to upgrade it"),
-            ParseErrorKind::TooNew(n) => write!(f, "UGE version {n} is not supported; please pester someone to update teNOR!"),
-            ParseErrorKind::BadData { input, inner: InnerError(errors) } => (|| {
+            ParseErrorKind::TooNew(n) => write!(
+                f,
+                "UGE version {n} is not supported; please pester someone to update teNOR!"
+            ),
+            ParseErrorKind::BadData {
+                input,
+                inner: InnerError(errors),
+            } => (|| {
                 writeln!(f, "There was an error parsing the UGE file!")?;
                 let base_ptr = input.as_ptr();
                 for (input, kind) in errors {
                     // SAFETY: both pointers originate from the same slice, which is still live (we keep holding `input`).
-                    writeln!(f, "\t(0x{:<4x} bytes into the data) {kind}", unsafe { input.as_ptr().offset_from(base_ptr) })?;
+                    writeln!(f, "\t(0x{:<4x} bytes into the data) {kind}", unsafe {
+                        input.as_ptr().offset_from(base_ptr)
+                    })?;
                 }
                 write!(f, "(Either the file is corrupted, or our UGE parser has a bug. If the latter, please attach your UGE file and the above in your bug report!)")
             })(),
@@ -73,8 +85,62 @@ impl Display for ParseError<'_> {
     }
 }
 
-fn song_v6(input: &[u8]) -> PResult<Song<'_>> {
-    fn inner(input: &[u8]) -> PResult<Song<'_>> {
+/// Versions 0 through 5 are similar enough to be handled together; they are upgraded to the v6 model
+/// the same way hUGETracker does when opening them.
+fn song_v0(version: Integer, input: &[u8]) -> PResult<'_, Song<'_>> {
+    let inner = |input| {
+        let (input, name) = short_string(input)?;
+        let (input, artist) = short_string(input)?;
+        let (input, comment) = short_string(input)?;
+        let (input, instruments) = if version < 3 {
+            instr_bank_v1(input)?
+        } else {
+            instr_collection_v2(input)?
+        };
+        let (input, waves) = if version < 3 {
+            wave_bank_v1(input)?
+        } else {
+            wave_bank_v2(input)?
+        };
+        let (input, ticks_per_row) = try_convert(input, integer)?;
+        let (input, (patterns, missing_patterns)) = if version < 5 {
+            let (input, patterns) = pattern_map_v1(input)?;
+            (input, (patterns, vec![]))
+        } else {
+            pattern_map_v2(input, pattern_v1)?
+        };
+        let (input, order_matrix) = order_matrix(input)?;
+        let (input, routines) = if version < 2 {
+            (input, Default::default())
+        } else {
+            routine_bank(input)?
+        };
+
+        Ok((
+            input,
+            Song {
+                name,
+                artist,
+                comment,
+                instruments,
+                waves,
+                ticks_per_row,
+                timer_divider: None, // The timer was only introduced in v6.
+                patterns,
+                order_matrix,
+                routines,
+                leftovers: SongLeftovers {
+                    timer_divider: 0,
+                    missing_patterns,
+                },
+            },
+        ))
+    };
+    context("parsing v0-5 song from here", inner)(input)
+}
+
+fn song_v6(input: &[u8]) -> PResult<'_, Song<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, Song<'_>> {
         let (input, name) = short_string(input)?;
         let (input, artist) = short_string(input)?;
         let (input, comment) = short_string(input)?;
@@ -86,7 +152,7 @@ fn song_v6(input: &[u8]) -> PResult<Song<'_>> {
         let timer_divider = timer_divider
             .try_into()
             .map_err(|_| InnerError::err(input, InnerErrorKind::BadTimerDivider(timer_divider)))?;
-        let (input, patterns) = pattern_map_v2(new_input)?;
+        let (input, (patterns, missing_patterns)) = pattern_map_v2(new_input, pattern_v2)?;
         let (input, order_matrix) = order_matrix(input)?;
         let (input, routines) = routine_bank(input)?;
 
@@ -103,6 +169,10 @@ fn song_v6(input: &[u8]) -> PResult<Song<'_>> {
                 patterns,
                 order_matrix,
                 routines,
+                leftovers: SongLeftovers {
+                    timer_divider,
+                    missing_patterns,
+                },
             },
         ))
     }
@@ -111,8 +181,8 @@ fn song_v6(input: &[u8]) -> PResult<Song<'_>> {
 
 // Instruments.
 
-fn instr_collection_v3(input: &[u8]) -> PResult<InstrCollection<'_>> {
-    fn inner(input: &[u8]) -> PResult<InstrCollection<'_>> {
+fn instr_collection_v3(input: &[u8]) -> PResult<'_, InstrCollection<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, InstrCollection<'_>> {
         let (input, duty) = instr_bank_v3(input)?;
         if cfg!(debug_assertions) {
             if let Some((i, instr)) = duty
@@ -149,8 +219,8 @@ fn instr_collection_v3(input: &[u8]) -> PResult<InstrCollection<'_>> {
     context("parsing v3 instr collection from here", inner)(input)
 }
 
-fn instr_bank_v3(input: &[u8]) -> PResult<InstrumentBank<'_>> {
-    fn inner(input: &[u8]) -> PResult<InstrumentBank<'_>> {
+fn instr_bank_v3(input: &[u8]) -> PResult<'_, InstrumentBank<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, InstrumentBank<'_>> {
         let mut bank = std::array::from_fn(|_| Default::default());
         let (input, ()) = fill(instrument_v3, &mut bank)(input)?;
         Ok((input, bank))
@@ -158,64 +228,231 @@ fn instr_bank_v3(input: &[u8]) -> PResult<InstrumentBank<'_>> {
     context("parsing v3 instrument bank from here", inner)(input)
 }
 
-fn instrument_v3(input: &[u8]) -> PResult<Instrument<'_>> {
-    fn inner(input: &[u8]) -> PResult<Instrument<'_>> {
+fn instrument_v3(input: &[u8]) -> PResult<'_, Instrument<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, Instrument<'_>> {
         let kind_input = input;
-        let (input, kind) = nom::number::complete::le_u32(input)?;
-        let (input, name) = short_string(input)?;
-        let (new_input, length) = try_convert(input, integer)?;
-        let (input, length_enabled) = boolean(new_input)?;
-        let (input, initial_volume) = nom::number::complete::u8(input)?;
-        let (input, envelope_dir) = try_convert(input, nom::number::complete::le_u32)?;
-        let (input, envelope_pace) = nom::number::complete::u8(input)?;
-        let (input, sweep_time) = try_convert(input, nom::number::complete::le_u32)?;
-        let (input, sweep_dir) = try_convert(input, nom::number::complete::le_u32)?;
-        let (input, sweep_shift) = try_convert(input, nom::number::complete::le_u32)?;
-        let (input, duty) = try_convert(input, nom::number::complete::u8)?;
-        let (input, output_level) = try_convert(input, nom::number::complete::le_u32)?;
-        let (input, waveform) = try_convert(input, nom::number::complete::le_u32)?;
-        let (input, lfsr_width) = try_convert(input, nom::number::complete::le_u32)?;
+        let (input, fields) = instrument_fields(input)?;
         let (input, subpattern_enabled) = boolean(input)?;
-        let (input, subpattern) = subpattern_v2(input)?;
+        let (input, raw_subpattern) = subpattern_v2(input)?;
+
+        let kind = fields.kind;
+        let subpattern = subpattern_enabled.then(|| subpattern(&raw_subpattern));
+        let instrument = fields
+            .into_instrument(kind, subpattern, raw_subpattern)
+            .map_err(|err_kind| InnerError::err(kind_input, err_kind))?;
+        Ok((input, instrument))
+    }
+    context("parsing v3 instrument from here", inner)(input)
+}
 
+/// Before v3, there was a single bank of instruments, each of which could be of any kind.
+/// hUGETracker upgrades these by copying the whole bank into each of the three new ones.
+fn instr_bank_v1(input: &[u8]) -> PResult<'_, InstrCollection<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, InstrCollection<'_>> {
+        let (input, bank) = count(instrument_v2, 15)(input)?;
+
+        let upgrade = |kind| {
+            std::array::from_fn(|i| {
+                let (fields, noise_macro): &(InstrumentFields, _) = &bank[i];
+                // Only the noise channel ever applied the macro.
+                let subpattern = if kind == 2 { *noise_macro } else { None };
+                fields
+                    .clone()
+                    .into_instrument(kind, subpattern, Box::new([Default::default(); 64]))
+                    .expect("Forced instrument kind should be valid")
+            })
+        };
         Ok((
             input,
-            Instrument {
-                name,
-                length: length_enabled.then_some(length),
-                kind: match kind {
-                    0 => InstrumentKind::Square {
-                        initial_volume,
-                        envelope_dir,
-                        envelope_pace,
-                        sweep_time,
-                        sweep_dir,
-                        sweep_shift,
-                        duty,
-                    },
-                    1 => InstrumentKind::Wave {
-                        output_level,
-                        wave_id: waveform,
-                    },
-                    2 => InstrumentKind::Noise {
-                        initial_volume,
-                        envelope_dir,
-                        envelope_pace,
-                        lfsr_width,
-                    },
-                    n => return Err(InnerError::err(kind_input, InnerErrorKind::BadInstrType(n))),
-                },
-                subpattern: subpattern_enabled.then_some(subpattern),
+            InstrCollection {
+                duty: upgrade(0),
+                wave: upgrade(1),
+                noise: upgrade(2),
             },
         ))
     }
-    context("parsing v3 instrument from here", inner)(input)
+    context("parsing v1 instrument bank from here", inner)(input)
+}
+
+fn instr_collection_v2(input: &[u8]) -> PResult<'_, InstrCollection<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, InstrCollection<'_>> {
+        let (input, duty) = instr_bank_v2(input)?;
+        let (input, wave) = instr_bank_v2(input)?;
+        let (input, noise) = instr_bank_v2(input)?;
+        Ok((input, InstrCollection { duty, wave, noise }))
+    }
+    context("parsing v2 instr collection from here", inner)(input)
+}
+
+fn instr_bank_v2(input: &[u8]) -> PResult<'_, InstrumentBank<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, InstrumentBank<'_>> {
+        let mut bank = std::array::from_fn(|_| Default::default());
+        let (input, ()) = fill(
+            |kind_input| {
+                let (input, (fields, noise_macro)) = instrument_v2(kind_input)?;
+                let kind = fields.kind;
+                // Only the noise channel ever applied the macro.
+                let subpattern = if kind == 2 { noise_macro } else { None };
+                let instrument = fields
+                    .into_instrument(kind, subpattern, Box::new([Default::default(); 64]))
+                    .map_err(|err_kind| InnerError::err(kind_input, err_kind))?;
+                Ok((input, instrument))
+            },
+            &mut bank,
+        )(input)?;
+        Ok((input, bank))
+    }
+    context("parsing v2 instrument bank from here", inner)(input)
+}
+
+/// Instead of subpatterns, instruments could only have a "noise macro", which is converted to one.
+fn instrument_v2(input: &[u8]) -> PResult<'_, (InstrumentFields<'_>, Option<Subpattern>)> {
+    fn inner(input: &[u8]) -> PResult<'_, (InstrumentFields<'_>, Option<Subpattern>)> {
+        let (input, fields) = instrument_fields(input)?;
+        let (input, _unused) = nom::number::complete::u8(input)?;
+        let (input, noise_macro) = take(6u8)(input)?;
+
+        let noise_macro: [u8; 6] = noise_macro.try_into().unwrap();
+        Ok((
+            input,
+            (fields, noise_macro_v2(noise_macro.map(|n| n as i8))),
+        ))
+    }
+    context("parsing v2 instrument from here", inner)(input)
+}
+
+/// Each of the macro's values is applied, in order, on the 6 ticks following the note's; after
+/// that, the subpattern stays on an empty row, so the note goes back to having no offset.
+fn noise_macro_v2(noise_macro: [i8; 6]) -> Option<Subpattern> {
+    if noise_macro.iter().all(|&offset| offset == 0) {
+        return None;
+    }
+
+    let mut subpattern: Subpattern = std::array::from_fn(|i| crate::song::SubpatternCell {
+        offset: 90, // No offset.
+        next_row_idx: (i as u8 + 1) % 32,
+        effect_code: EffectId::Arpeggio,
+        effect_param: 0,
+    });
+    for (cell, offset) in subpattern[1..].iter_mut().zip(noise_macro) {
+        cell.offset = (36 + i16::from(offset)).clamp(0, crate::LAST_NOTE.into()) as u8;
+    }
+    // Stay on an empty row forever afterwards.
+    subpattern[noise_macro.len() + 1].next_row_idx = noise_macro.len() as u8 + 1;
+    Some(subpattern)
+}
+
+/// The fields common to all instrument versions, regardless of their kind.
+#[derive(Debug, Clone)]
+struct InstrumentFields<'input> {
+    kind: u32,
+    name: Cow<'input, str>,
+    length: u8,
+    length_enabled: bool,
+    initial_volume: u8,
+    envelope_dir: crate::song::EnvelopeDirection,
+    envelope_pace: u8,
+    sweep_time: u8,
+    sweep_dir: crate::song::SweepDirection,
+    sweep_shift: u8,
+    duty: crate::song::DutyType,
+    output_level: crate::song::WaveOutputLevel,
+    waveform: u8,
+    lfsr_width: crate::song::LfsrWidth,
+}
+
+fn instrument_fields(input: &[u8]) -> PResult<'_, InstrumentFields<'_>> {
+    let (input, kind) = nom::number::complete::le_u32(input)?;
+    let (input, name) = short_string(input)?;
+    let (new_input, length) = try_convert(input, integer)?;
+    let (input, length_enabled) = boolean(new_input)?;
+    let (input, initial_volume) = nom::number::complete::u8(input)?;
+    let (input, envelope_dir) = try_convert(input, nom::number::complete::le_u32)?;
+    let (input, envelope_pace) = nom::number::complete::u8(input)?;
+    let (input, sweep_time) = try_convert(input, nom::number::complete::le_u32)?;
+    let (input, sweep_dir) = try_convert(input, nom::number::complete::le_u32)?;
+    let (input, sweep_shift) = try_convert(input, nom::number::complete::le_u32)?;
+    let (input, duty) = try_convert(input, nom::number::complete::u8)?;
+    let (input, output_level) = try_convert(input, nom::number::complete::le_u32)?;
+    let (input, waveform) = try_convert(input, nom::number::complete::le_u32)?;
+    let (input, lfsr_width) = try_convert(input, nom::number::complete::le_u32)?;
+
+    Ok((
+        input,
+        InstrumentFields {
+            kind,
+            name,
+            length,
+            length_enabled,
+            initial_volume,
+            envelope_dir,
+            envelope_pace,
+            sweep_time,
+            sweep_dir,
+            sweep_shift,
+            duty,
+            output_level,
+            waveform,
+            lfsr_width,
+        },
+    ))
+}
+
+impl<'input> InstrumentFields<'input> {
+    fn into_instrument(
+        self,
+        kind: u32,
+        subpattern: Option<Subpattern>,
+        raw_subpattern: Box<[RawSubpatternCell; 64]>,
+    ) -> Result<Instrument<'input>, InnerErrorKind> {
+        Ok(Instrument {
+            name: self.name,
+            length: self.length_enabled.then_some(self.length),
+            kind: match kind {
+                0 => InstrumentKind::Square {
+                    initial_volume: self.initial_volume,
+                    envelope_dir: self.envelope_dir,
+                    envelope_pace: self.envelope_pace,
+                    sweep_time: self.sweep_time,
+                    sweep_dir: self.sweep_dir,
+                    sweep_shift: self.sweep_shift,
+                    duty: self.duty,
+                },
+                1 => InstrumentKind::Wave {
+                    output_level: self.output_level,
+                    wave_id: self.waveform,
+                },
+                2 => InstrumentKind::Noise {
+                    initial_volume: self.initial_volume,
+                    envelope_dir: self.envelope_dir,
+                    envelope_pace: self.envelope_pace,
+                    lfsr_width: self.lfsr_width,
+                },
+                n => return Err(InnerErrorKind::BadInstrType(n)),
+            },
+            subpattern,
+            leftovers: InstrumentLeftovers {
+                length: self.length,
+                initial_volume: self.initial_volume,
+                envelope_dir: self.envelope_dir,
+                envelope_pace: self.envelope_pace,
+                sweep_time: self.sweep_time,
+                sweep_dir: self.sweep_dir,
+                sweep_shift: self.sweep_shift,
+                duty: self.duty,
+                output_level: self.output_level,
+                wave_id: self.waveform,
+                lfsr_width: self.lfsr_width,
+                subpattern: raw_subpattern,
+            },
+        })
+    }
 }
 
 // Waves.
 
-fn wave_bank_v2(input: &[u8]) -> PResult<WaveBank> {
-    fn inner(input: &[u8]) -> PResult<WaveBank> {
+fn wave_bank_v2(input: &[u8]) -> PResult<'_, WaveBank> {
+    fn inner(input: &[u8]) -> PResult<'_, WaveBank> {
         let mut bank = [Default::default(); 16];
         let (input, ()) = fill(wave_v2, &mut bank)(input)?;
         Ok((input, bank))
@@ -223,8 +460,25 @@ fn wave_bank_v2(input: &[u8]) -> PResult<WaveBank> {
     context("parsing v2 wave bank from here", inner)(input)
 }
 
-fn wave_v2(input: &[u8]) -> PResult<Wave> {
-    fn inner(wave_input: &[u8]) -> PResult<Wave> {
+/// Before v3, waves were mistakenly declared as 33 bytes long; the last one is unused.
+fn wave_bank_v1(input: &[u8]) -> PResult<'_, WaveBank> {
+    fn inner(input: &[u8]) -> PResult<'_, WaveBank> {
+        let mut bank = [Default::default(); 16];
+        let (input, ()) = fill(
+            |input| {
+                let (input, wave) = wave_v2(input)?;
+                let (input, _unused) = nom::number::complete::u8(input)?;
+                Ok((input, wave))
+            },
+            &mut bank,
+        )(input)?;
+        Ok((input, bank))
+    }
+    context("parsing v1 wave bank from here", inner)(input)
+}
+
+fn wave_v2(input: &[u8]) -> PResult<'_, Wave> {
+    fn inner(wave_input: &[u8]) -> PResult<'_, Wave> {
         let (input, raw_wave) = take(32u8)(wave_input)?;
 
         let sanitize = |index| {
@@ -249,34 +503,64 @@ fn wave_v2(input: &[u8]) -> PResult<Wave> {
 
 // Patterns.
 
-fn pattern_map_v2(input: &[u8]) -> PResult<Vec<Pattern>> {
-    fn inner(input: &[u8]) -> PResult<Vec<Pattern>> {
+/// Before v5, patterns were stored in order, without their IDs.
+fn pattern_map_v1(input: &[u8]) -> PResult<'_, Vec<Pattern>> {
+    context(
+        "parsing v1 pattern map from here",
+        length_count(integer, pattern_v1),
+    )(input)
+}
+
+/// The format of the patterns themselves changed independently (in v6).
+///
+/// Also returns the IDs of the patterns that are missing from the map.
+fn pattern_map_v2(
+    input: &[u8],
+    pattern: fn(&[u8]) -> PResult<'_, Pattern>,
+) -> PResult<'_, (Vec<Pattern>, Vec<usize>)> {
+    let inner = |input| {
         let (mut input, nb_entries) = try_convert(input, integer)?;
         let mut patterns = Vec::with_capacity(nb_entries);
+        let mut present = Vec::with_capacity(nb_entries);
         for _ in 0..nb_entries {
-            let (new_input, (id, cells)) = pattern_map_entry_v2(input)?;
+            let (new_input, (id, cells)) = pattern_map_entry_v2(input, pattern)?;
             input = new_input;
             if patterns.len() <= id {
                 patterns.resize_with(id + 1, || std::array::from_fn(|_| Default::default()));
+                present.resize(id + 1, false);
             }
             patterns[id] = cells;
+            present[id] = true;
         }
-        Ok((input, patterns))
-    }
+        let missing = (0..present.len()).filter(|&id| !present[id]).collect();
+        Ok((input, (patterns, missing)))
+    };
     context("parsing v2 pattern map from here", inner)(input)
 }
 
-fn pattern_map_entry_v2(input: &[u8]) -> PResult<(usize, Pattern)> {
-    fn inner(input: &[u8]) -> PResult<(usize, Pattern)> {
+fn pattern_map_entry_v2(
+    input: &[u8],
+    pattern: fn(&[u8]) -> PResult<'_, Pattern>,
+) -> PResult<'_, (usize, Pattern)> {
+    let inner = |input| {
         let (input, id) = try_convert(input, integer)?;
-        let (input, cells) = pattern_v2(input)?;
+        let (input, cells) = pattern(input)?;
         Ok((input, (id, cells)))
-    }
+    };
     context("parsing v2 pattern map entry from here", inner)(input)
 }
 
-fn pattern_v2(input: &[u8]) -> PResult<Pattern> {
-    fn inner(input: &[u8]) -> PResult<Pattern> {
+fn pattern_v1(input: &[u8]) -> PResult<'_, Pattern> {
+    fn inner(input: &[u8]) -> PResult<'_, Pattern> {
+        let mut pattern = [Default::default(); 64];
+        let (input, ()) = fill(|input| try_convert(input, cell_v1), &mut pattern)(input)?;
+        Ok((input, pattern))
+    }
+    context("parsing v1 pattern from here", inner)(input)
+}
+
+fn pattern_v2(input: &[u8]) -> PResult<'_, Pattern> {
+    fn inner(input: &[u8]) -> PResult<'_, Pattern> {
         let mut pattern = [Default::default(); 64];
         let (input, ()) = fill(|input| try_convert(input, cell_v2), &mut pattern)(input)?;
         Ok((input, pattern))
@@ -284,33 +568,44 @@ fn pattern_v2(input: &[u8]) -> PResult<Pattern> {
     context("parsing v2 pattern from here", inner)(input)
 }
 
-fn subpattern_v2(input: &[u8]) -> PResult<Subpattern> {
-    fn inner(input: &[u8]) -> PResult<Subpattern> {
-        let mut pattern: Subpattern = [Default::default(); 32];
-        let (mut input, ()) = fill(|input| try_convert(input, cell_v2), &mut pattern)(input)?;
-        // The remainder of the pattern is encoded, but not used.
-        for _ in 32..64 {
-            (input, _) = cell_v2(input)?;
-        }
-        // Adjust the jump targets. There is actually a reason for doing this!
-        //
-        // hUGETracker encodes them as "0 for no jump, otherwise the target column, 1-indexed".
-        // However, there are only 5 bits to encode this information, which introduces a subtle bug:
-        // 32 needs 6 bits to be encoded!
-        // hUGETracker's export emits "32" verbatim, and hUGEDriver's `dn` macro silently truncates
-        // that to 0, meaning "no jump".
-        // We fix this by making each row *unconditionally* jump! The 32 row IDs fit in 5 bits.
-        for (i, cell) in pattern.iter_mut().enumerate() {
-            cell.next_row_idx = match cell.next_row_idx {
-                0 => (i as u8 + 1) % 32, // i is in 0..32
-                n => n - 1,
-            };
-        }
-        Ok((input, pattern))
+/// The rows are kept raw, since the last 32 are encoded, but not used; see [`subpattern`] for the
+/// first 32.
+fn subpattern_v2(input: &[u8]) -> PResult<'_, Box<[RawSubpatternCell; 64]>> {
+    fn inner(input: &[u8]) -> PResult<'_, Box<[RawSubpatternCell; 64]>> {
+        let mut rows = Box::new([Default::default(); 64]);
+        let (input, ()) = fill(|input| try_convert(input, cell_v2), &mut rows[..])(input)?;
+        Ok((input, rows))
     }
     context("parsing v2 (sub)pattern from here", inner)(input)
 }
 
+fn subpattern(rows: &[RawSubpatternCell; 64]) -> Subpattern {
+    std::array::from_fn(|i| subpattern_cell(&rows[i], i))
+}
+
+/// Converts one of the first 32 rows of a subpattern.
+pub(super) fn subpattern_cell(raw: &RawSubpatternCell, row: usize) -> SubpatternCell {
+    // Adjust the jump target. There is actually a reason for doing this!
+    //
+    // hUGETracker encodes them as "0 for no jump, otherwise the target column, 1-indexed".
+    // However, there are only 5 bits to encode this information, which introduces a subtle bug:
+    // 32 needs 6 bits to be encoded!
+    // hUGETracker's export emits "32" verbatim, and hUGEDriver's `dn` macro silently truncates
+    // that to 0, meaning "no jump".
+    // We fix this by making each row *unconditionally* jump! The 32 row IDs fit in 5 bits.
+    let next_row_idx = match raw.jump_index {
+        0 => (row as u8 + 1) % 32, // `row` is in 0..32
+        // hUGETracker clamps an index greater than 32 as that.
+        n => n.min(32) - 1,
+    };
+    SubpatternCell {
+        offset: raw.offset,
+        next_row_idx,
+        effect_code: raw.effect_code,
+        effect_param: raw.effect_param,
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 struct RawCell {
     /// Actually a note offset in subpatterns, so this is not a `Note`.
@@ -345,8 +640,25 @@ impl RawCell {
     }
 }
 
-fn cell_v2(input: &[u8]) -> PResult<RawCell> {
-    fn inner(input: &[u8]) -> PResult<RawCell> {
+/// Cells only gained a jump index along with subpatterns.
+fn cell_v1(input: &[u8]) -> PResult<'_, RawCell> {
+    fn inner(input: &[u8]) -> PResult<'_, RawCell> {
+        let (input, note) = try_convert(input, integer)?;
+        let (input, instrument) = try_convert(input, integer)?;
+        let (input, effect_code) = try_convert(input, integer)?;
+        let (input, effect_params) = nom::number::complete::u8(input)?;
+
+        Ok((
+            input,
+            RawCell::try_new(note, instrument, 0, effect_code, effect_params)
+                .map_err(|err_kind| InnerError::err(input, err_kind))?,
+        ))
+    }
+    context("parsing v1 cell from here", inner)(input)
+}
+
+fn cell_v2(input: &[u8]) -> PResult<'_, RawCell> {
+    fn inner(input: &[u8]) -> PResult<'_, RawCell> {
         let (input, note) = try_convert(input, integer)?;
         let (input, instrument) = try_convert(input, integer)?;
         let (input, jump_index) = try_convert(input, integer)?;
@@ -364,8 +676,8 @@ fn cell_v2(input: &[u8]) -> PResult<RawCell> {
 
 // Order.
 
-fn order_matrix(input: &[u8]) -> PResult<Vec<[usize; 4]>> {
-    fn inner(input: &[u8]) -> PResult<Vec<[usize; 4]>> {
+fn order_matrix(input: &[u8]) -> PResult<'_, Vec<[usize; 4]>> {
+    fn inner(input: &[u8]) -> PResult<'_, Vec<[usize; 4]>> {
         let mut orders = std::array::from_fn(|_| Default::default());
         let (input, ()) = fill(order_column, &mut orders)(input)?;
 
@@ -386,7 +698,7 @@ fn order_matrix(input: &[u8]) -> PResult<Vec<[usize; 4]>> {
     context("parsing order matrix from here", inner)(input)
 }
 
-fn order_column(input: &[u8]) -> PResult<Vec<usize>> {
+fn order_column(input: &[u8]) -> PResult<'_, Vec<usize>> {
     context(
         "parsing order \"column\" from here",
         length_count(integer, |input| try_convert(input, integer)),
@@ -395,8 +707,8 @@ fn order_column(input: &[u8]) -> PResult<Vec<usize>> {
 
 // Routines.
 
-fn routine_bank(input: &[u8]) -> PResult<RoutineBank> {
-    fn inner(input: &[u8]) -> PResult<RoutineBank> {
+fn routine_bank(input: &[u8]) -> PResult<'_, RoutineBank<'_>> {
+    fn inner(input: &[u8]) -> PResult<'_, RoutineBank<'_>> {
         let mut routines = std::array::from_fn(|_| Default::default());
         let (input, ()) = fill(routine, &mut routines)(input)?;
         Ok((input, routines))
@@ -404,18 +716,18 @@ fn routine_bank(input: &[u8]) -> PResult<RoutineBank> {
     context("parsing routine bank from here", inner)(input)
 }
 
-fn routine(input: &[u8]) -> PResult<Routine> {
+fn routine(input: &[u8]) -> PResult<'_, Routine<'_>> {
     context("parsing routine from here", ansi_string)(input)
 }
 
 // Elementary types.
 
 type Integer = u32;
-fn integer(input: &[u8]) -> PResult<Integer> {
+fn integer(input: &[u8]) -> PResult<'_, Integer> {
     context("parsing Integer from here", le_u32)(input)
 }
 
-fn boolean(input: &[u8]) -> PResult<bool> {
+fn boolean(input: &[u8]) -> PResult<'_, bool> {
     context("parsing Boolean from here", nom::number::complete::u8)(input).and_then(|(input, n)| {
         match n {
             0 => Ok((input, false)),
@@ -425,16 +737,16 @@ fn boolean(input: &[u8]) -> PResult<bool> {
     })
 }
 
-fn short_string(input: &[u8]) -> PResult<Cow<'_, str>> {
-    fn inner(input: &[u8]) -> PResult<Cow<'_, str>> {
+fn short_string(input: &[u8]) -> PResult<'_, Cow<'_, str>> {
+    fn inner(input: &[u8]) -> PResult<'_, Cow<'_, str>> {
         let (input, len) = nom::number::complete::u8(input)?;
         take(255u8)(input).map(|(input, raw)| (input, String::from_utf8_lossy(&raw[..len.into()])))
     }
     context("parsing ShortString from here", inner)(input)
 }
 
-fn ansi_string(input: &[u8]) -> PResult<Cow<'_, str>> {
-    fn inner(input: &[u8]) -> PResult<Cow<'_, str>> {
+fn ansi_string(input: &[u8]) -> PResult<'_, Cow<'_, str>> {
+    fn inner(input: &[u8]) -> PResult<'_, Cow<'_, str>> {
         let (input, len) = try_convert(input, nom::number::complete::le_u32)?;
         take(len)(input).map(|(input, raw)| (input, String::from_utf8_lossy(&raw[..len])))
     }
@@ -626,18 +938,14 @@ impl TryConstrain<crate::song::PatternCell> for RawCell {
     }
 }
 
-impl TryConstrain<crate::song::SubpatternCell> for RawCell {
-    fn try_constrain(self) -> Result<crate::song::SubpatternCell, InnerErrorKind> {
-        let offset = self.note;
-        // hUGETracker clamps an index greater than 32 as that.
-        let next_row_idx = self.jump_index.min(32);
-        let effect_code = self.effect_code;
-        let effect_param = self.effect_params;
-        Ok(crate::song::SubpatternCell {
-            offset,
-            next_row_idx,
-            effect_code,
-            effect_param,
+impl TryConstrain<RawSubpatternCell> for RawCell {
+    fn try_constrain(self) -> Result<RawSubpatternCell, InnerErrorKind> {
+        Ok(RawSubpatternCell {
+            offset: self.note,
+            instrument: self.instrument,
+            jump_index: self.jump_index,
+            effect_code: self.effect_code,
+            effect_param: self.effect_params,
         })
     }
 }
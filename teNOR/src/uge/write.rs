@@ -0,0 +1,782 @@
+//! The inverse of the parser: songs are always written in the latest version (v6).
+//! Each function mirrors its parsing counterpart.
+
+use std::io::{self, Write};
+
+use crate::song::{
+    DutyType, EnvelopeDirection, InstrCollection, Instrument, InstrumentKind, LfsrWidth, Pattern,
+    RawSubpatternCell, Song, Subpattern, SweepDirection, WaveBank, WaveOutputLevel,
+};
+
+use super::subpattern_cell;
+
+/// Writes a song as a `.uge` file that hUGETracker can open.
+///
+/// Writing back a song read by [`parse_song`](super::parse_song) produces the same data, including
+/// what hUGETracker stores but doesn't use (see [`SongLeftovers`](crate::song::SongLeftovers)).
+pub fn write_song(song: &Song, mut output: impl Write) -> io::Result<()> {
+    song_v6(&mut output, song)?;
+    output.flush()
+}
+
+fn song_v6(output: &mut dyn Write, song: &Song) -> io::Result<()> {
+    integer(output, 6)?;
+    short_string(output, &song.name)?;
+    short_string(output, &song.artist)?;
+    short_string(output, &song.comment)?;
+    instr_collection_v3(output, &song.instruments)?;
+    wave_bank_v2(output, &song.waves)?;
+    integer(output, song.ticks_per_row.into())?;
+    boolean(output, song.timer_divider.is_some())?;
+    integer(
+        output,
+        song.timer_divider
+            .unwrap_or(song.leftovers.timer_divider)
+            .into(),
+    )?;
+    pattern_map_v2(output, &song.patterns, &song.leftovers.missing_patterns)?;
+    order_matrix(output, &song.order_matrix)?;
+    for routine in &song.routines {
+        ansi_string(output, routine)?;
+    }
+    Ok(())
+}
+
+// Instruments.
+
+fn instr_collection_v3(output: &mut dyn Write, instruments: &InstrCollection) -> io::Result<()> {
+    for bank in [&instruments.duty, &instruments.wave, &instruments.noise] {
+        for instr in bank {
+            instrument_v3(output, instr)?;
+        }
+    }
+    Ok(())
+}
+
+fn instrument_v3(output: &mut dyn Write, instr: &Instrument) -> io::Result<()> {
+    // Fields that don't apply to the instrument's kind are written back as they were read.
+    let leftovers = &instr.leftovers;
+    let (kind, initial_volume, envelope_dir, envelope_pace) = match instr.kind {
+        InstrumentKind::Square {
+            initial_volume,
+            envelope_dir,
+            envelope_pace,
+            ..
+        } => (0, initial_volume, envelope_dir, envelope_pace),
+        InstrumentKind::Wave { .. } => (
+            1,
+            leftovers.initial_volume,
+            leftovers.envelope_dir,
+            leftovers.envelope_pace,
+        ),
+        InstrumentKind::Noise {
+            initial_volume,
+            envelope_dir,
+            envelope_pace,
+            ..
+        } => (2, initial_volume, envelope_dir, envelope_pace),
+    };
+    let (sweep_time, sweep_dir, sweep_shift, duty) = match instr.kind {
+        InstrumentKind::Square {
+            sweep_time,
+            sweep_dir,
+            sweep_shift,
+            duty,
+            ..
+        } => (sweep_time, sweep_dir, sweep_shift, duty),
+        _ => (
+            leftovers.sweep_time,
+            leftovers.sweep_dir,
+            leftovers.sweep_shift,
+            leftovers.duty,
+        ),
+    };
+    let (output_level, waveform) = match instr.kind {
+        InstrumentKind::Wave {
+            output_level,
+            wave_id,
+        } => (output_level, wave_id),
+        _ => (leftovers.output_level, leftovers.wave_id),
+    };
+    let lfsr_width = match instr.kind {
+        InstrumentKind::Noise { lfsr_width, .. } => lfsr_width,
+        _ => leftovers.lfsr_width,
+    };
+
+    integer(output, kind)?;
+    short_string(output, &instr.name)?;
+    integer(output, instr.length.unwrap_or(leftovers.length).into())?;
+    boolean(output, instr.length.is_some())?;
+    output.write_all(&[initial_volume])?;
+    integer(
+        output,
+        match envelope_dir {
+            EnvelopeDirection::Up => 0,
+            EnvelopeDirection::Down => 1,
+        },
+    )?;
+    output.write_all(&[envelope_pace])?;
+    integer(output, sweep_time.into())?;
+    integer(
+        output,
+        match sweep_dir {
+            SweepDirection::Up => 0,
+            SweepDirection::Down => 1,
+        },
+    )?;
+    integer(output, sweep_shift.into())?;
+    output.write_all(&[match duty {
+        DutyType::Percent12_5 => 0,
+        DutyType::Percent25 => 1,
+        DutyType::Percent50 => 2,
+        DutyType::Percent75 => 3,
+    }])?;
+    integer(
+        output,
+        match output_level {
+            WaveOutputLevel::Mute => 0,
+            WaveOutputLevel::Full => 1,
+            WaveOutputLevel::Half => 2,
+            WaveOutputLevel::Quarter => 3,
+        },
+    )?;
+    integer(output, waveform.into())?;
+    integer(
+        output,
+        match lfsr_width {
+            LfsrWidth::Fifteen => 0,
+            LfsrWidth::Seven => 1,
+        },
+    )?;
+    boolean(output, instr.subpattern.is_some())?;
+    subpattern_v2(output, instr.subpattern.as_ref(), &leftovers.subpattern)
+}
+
+// Waves.
+
+fn wave_bank_v2(output: &mut dyn Write, waves: &WaveBank) -> io::Result<()> {
+    for wave in waves {
+        // One sample per byte.
+        let samples: Vec<u8> = wave
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0xF])
+            .collect();
+        output.write_all(&samples)?;
+    }
+    Ok(())
+}
+
+// Patterns.
+
+fn pattern_map_v2(
+    output: &mut dyn Write,
+    patterns: &[Pattern],
+    missing_patterns: &[usize],
+) -> io::Result<()> {
+    // Patterns that were missing are omitted again, unless they have been filled since.
+    let present = |(id, pattern): &(usize, &Pattern)| {
+        !missing_patterns.contains(id) || pattern.iter().any(|cell| *cell != Default::default())
+    };
+    integer(
+        output,
+        count(patterns.iter().enumerate().filter(present).count())?,
+    )?;
+    for (id, pattern) in patterns.iter().enumerate().filter(present) {
+        integer(output, count(id)?)?;
+        for cell in pattern {
+            cell_v2(
+                output,
+                cell.note as u32,
+                cell.instrument,
+                0,
+                cell.effect_code as u32,
+                cell.effect_param,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The raw rows are written as-is, except where they don't match the subpattern (if it's enabled).
+fn subpattern_v2(
+    output: &mut dyn Write,
+    subpattern: Option<&Subpattern>,
+    raw_rows: &[RawSubpatternCell; 64],
+) -> io::Result<()> {
+    for (i, raw) in raw_rows.iter().enumerate() {
+        let raw = match subpattern.and_then(|subpattern| subpattern.get(i)) {
+            Some(cell) if subpattern_cell(raw, i) != *cell => RawSubpatternCell {
+                offset: cell.offset,
+                instrument: raw.instrument,
+                // Undo the parser's jump adjustment; "no jump" is preferred, since that's what
+                // hUGETracker displays most legibly.
+                jump_index: if usize::from(cell.next_row_idx) == (i + 1) % 32 {
+                    0
+                } else {
+                    cell.next_row_idx + 1
+                },
+                effect_code: cell.effect_code,
+                effect_param: cell.effect_param,
+            },
+            // This also keeps rows that are encoded in a different way than we would.
+            _ => *raw,
+        };
+        cell_v2(
+            output,
+            raw.offset.into(),
+            raw.instrument,
+            raw.jump_index,
+            raw.effect_code as u32,
+            raw.effect_param,
+        )?;
+    }
+    Ok(())
+}
+
+fn cell_v2(
+    output: &mut dyn Write,
+    note: u32,
+    instrument: u8,
+    jump_index: u8,
+    effect_code: u32,
+    effect_params: u8,
+) -> io::Result<()> {
+    integer(output, note)?;
+    integer(output, instrument.into())?;
+    integer(output, jump_index.into())?;
+    integer(output, effect_code)?;
+    output.write_all(&[effect_params])
+}
+
+// Order.
+
+fn order_matrix(output: &mut dyn Write, order_matrix: &[[usize; 4]]) -> io::Result<()> {
+    for channel in 0..4 {
+        // For some reason, hUGE stores one extra zero per "column".
+        integer(output, count(order_matrix.len() + 1)?)?;
+        for row in order_matrix {
+            integer(output, count(row[channel])?)?;
+        }
+        integer(output, 0)?;
+    }
+    Ok(())
+}
+
+// Elementary types.
+
+fn integer(output: &mut dyn Write, n: u32) -> io::Result<()> {
+    output.write_all(&n.to_le_bytes())
+}
+
+fn count(n: usize) -> io::Result<u32> {
+    n.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{n} is too large for a UGE file"),
+        )
+    })
+}
+
+fn boolean(output: &mut dyn Write, b: bool) -> io::Result<()> {
+    output.write_all(&[b.into()])
+}
+
+fn short_string(output: &mut dyn Write, string: &str) -> io::Result<()> {
+    let Ok(len) = u8::try_from(string.len()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"{string}\" is longer than 255 bytes"),
+        ));
+    };
+    let mut raw = [0; 256];
+    raw[0] = len;
+    raw[1..][..string.len()].copy_from_slice(string.as_bytes());
+    output.write_all(&raw)
+}
+
+fn ansi_string(output: &mut dyn Write, string: &str) -> io::Result<()> {
+    integer(output, count(string.len())?)?;
+    output.write_all(string.as_bytes())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::song::{
+        EffectId, InstrumentLeftovers, Note, PatternCell, SongLeftovers, SubpatternCell,
+    };
+
+    fn raw_rows(seed: u8) -> Box<[RawSubpatternCell; 64]> {
+        Box::new(std::array::from_fn(|i| RawSubpatternCell {
+            offset: ((usize::from(seed) + i) % 72) as u8,
+            instrument: (i % 16) as u8,
+            // Some of these are out of range, and get clamped when parsed.
+            jump_index: (i * 7 % 40) as u8,
+            effect_code: EffectId::VolSlide,
+            effect_param: seed ^ i as u8,
+        }))
+    }
+
+    /// A song that uses every field that hUGETracker stores but that doesn't matter.
+    pub fn song() -> Song<'static> {
+        let mut song = Song {
+            name: "Round trip".into(),
+            artist: "teNOR".into(),
+            comment: "Leftovers included".into(),
+            instruments: InstrCollection {
+                duty: std::array::from_fn(|_| Instrument {
+                    kind: InstrumentKind::Square {
+                        initial_volume: 0,
+                        envelope_dir: EnvelopeDirection::Up,
+                        envelope_pace: 0,
+                        sweep_time: 0,
+                        sweep_dir: SweepDirection::Up,
+                        sweep_shift: 0,
+                        duty: DutyType::Percent12_5,
+                    },
+                    ..Default::default()
+                }),
+                wave: Default::default(),
+                noise: std::array::from_fn(|_| Instrument {
+                    kind: InstrumentKind::Noise {
+                        initial_volume: 0,
+                        envelope_dir: EnvelopeDirection::Up,
+                        envelope_pace: 0,
+                        lfsr_width: LfsrWidth::Fifteen,
+                    },
+                    ..Default::default()
+                }),
+            },
+            waves: std::array::from_fn(|i| [i as u8 * 0x11; 16]),
+            ticks_per_row: 7,
+            timer_divider: None,
+            patterns: vec![[Default::default(); 64]; 3],
+            order_matrix: vec![[0, 2, 0, 2], [2, 0, 2, 0]],
+            routines: Default::default(),
+            leftovers: SongLeftovers {
+                timer_divider: 200,
+                missing_patterns: vec![1],
+            },
+        };
+        song.patterns[2][3] = PatternCell {
+            note: Note::C_5,
+            instrument: 1,
+            effect_code: EffectId::SetVol,
+            effect_param: 0x0F,
+        };
+        song.routines[4] = "ld a, 42".into();
+
+        let leftovers = InstrumentLeftovers {
+            length: 17,
+            initial_volume: 9,
+            envelope_dir: EnvelopeDirection::Down,
+            envelope_pace: 3,
+            sweep_time: 5,
+            sweep_dir: SweepDirection::Down,
+            sweep_shift: 6,
+            duty: DutyType::Percent75,
+            output_level: WaveOutputLevel::Half,
+            wave_id: 11,
+            lfsr_width: LfsrWidth::Seven,
+            subpattern: raw_rows(0),
+        };
+        let square = InstrumentKind::Square {
+            initial_volume: 15,
+            envelope_dir: EnvelopeDirection::Up,
+            envelope_pace: 1,
+            sweep_time: 2,
+            sweep_dir: SweepDirection::Up,
+            sweep_shift: 4,
+            duty: DutyType::Percent50,
+        };
+        let raw_subpattern = raw_rows(12);
+        song.instruments.duty[0] = Instrument {
+            name: "Enabled subpattern".into(),
+            length: Some(32),
+            kind: square.clone(),
+            subpattern: Some(std::array::from_fn(|i| {
+                subpattern_cell(&raw_subpattern[i], i)
+            })),
+            leftovers: InstrumentLeftovers {
+                subpattern: raw_subpattern,
+                ..leftovers.clone()
+            },
+        };
+        song.instruments.duty[1] = Instrument {
+            name: "Disabled subpattern".into(),
+            length: None,
+            kind: square,
+            subpattern: None,
+            leftovers: leftovers.clone(),
+        };
+        song.instruments.wave[0] = Instrument {
+            name: "Wave".into(),
+            length: None,
+            kind: InstrumentKind::Wave {
+                output_level: WaveOutputLevel::Full,
+                wave_id: 2,
+            },
+            subpattern: None,
+            leftovers: leftovers.clone(),
+        };
+        song.instruments.noise[0] = Instrument {
+            name: "Noise".into(),
+            length: Some(8),
+            kind: InstrumentKind::Noise {
+                initial_volume: 4,
+                envelope_dir: EnvelopeDirection::Down,
+                envelope_pace: 7,
+                lfsr_width: LfsrWidth::Fifteen,
+            },
+            subpattern: None,
+            leftovers,
+        };
+        song
+    }
+
+    fn write(song: &Song) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_song(song, &mut bytes).expect("Writing to a `Vec` shouldn't fail");
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = write(&song());
+        let parsed = super::super::parse_song(&bytes).expect("Written song should parse");
+
+        let rewritten = write(&parsed);
+        assert_eq!(rewritten, bytes);
+        let reparsed = super::super::parse_song(&rewritten).expect("Rewritten song should parse");
+        assert_eq!(reparsed, parsed);
+
+        assert_eq!(parsed.leftovers.timer_divider, 200);
+        assert_eq!(parsed.leftovers.missing_patterns, [1]);
+        assert_eq!(parsed.instruments.duty[1].leftovers.subpattern, raw_rows(0));
+        assert_eq!(parsed.instruments.duty[1].leftovers.length, 17);
+        assert_eq!(
+            parsed.instruments.wave[0].leftovers.duty,
+            DutyType::Percent75
+        );
+        assert_eq!(parsed.instruments.noise[0].leftovers.wave_id, 11);
+    }
+
+    #[test]
+    fn edits_are_written() {
+        let bytes = write(&song());
+        let mut song = super::super::parse_song(&bytes).expect("Written song should parse");
+        let edited = SubpatternCell {
+            offset: 40,
+            next_row_idx: 0,
+            effect_code: EffectId::SetPanning,
+            effect_param: 0x11,
+        };
+        song.instruments.duty[0].subpattern.as_mut().unwrap()[5] = edited;
+        song.timer_divider = Some(100);
+        song.patterns[1][0].note = Note::C_5;
+
+        let bytes = write(&song);
+        let reparsed = super::super::parse_song(&bytes).expect("Edited song should parse");
+        assert_eq!(reparsed.instruments.duty[0].subpattern.unwrap()[5], edited);
+        // The row's unused field, and the unused rows, are still there.
+        let rows = &reparsed.instruments.duty[0].leftovers.subpattern;
+        assert_eq!(rows[5].instrument, 5);
+        assert_eq!(rows[32..], raw_rows(12)[32..]);
+        assert_eq!(reparsed.timer_divider, Some(100));
+        assert_eq!(reparsed.patterns[1][0].note, Note::C_5);
+        assert!(reparsed.leftovers.missing_patterns.is_empty());
+    }
+
+    /// The parts of a song that versions 0 through 5 stored the same way as v6.
+    fn header_v0(output: &mut Vec<u8>, version: u32) {
+        integer(output, version).unwrap();
+        short_string(output, "Old song").unwrap();
+        short_string(output, "Someone").unwrap();
+        short_string(output, "").unwrap();
+    }
+
+    /// Instruments used to have a single format for all kinds; all fields but the kind are the
+    /// same for every instrument here.
+    fn instrument_v2(output: &mut Vec<u8>, kind: u32, name: &str, noise_macro: [i8; 6]) {
+        integer(output, kind).unwrap();
+        short_string(output, name).unwrap();
+        integer(output, 10).unwrap(); // Length...
+        boolean(output, true).unwrap(); // ...enabled.
+        output.push(12); // Initial volume.
+        integer(output, 1).unwrap(); // Envelope down.
+        output.push(3); // Envelope pace.
+        integer(output, 5).unwrap(); // Sweep time.
+        integer(output, 1).unwrap(); // Sweep down.
+        integer(output, 2).unwrap(); // Sweep shift.
+        output.push(3); // 75% duty.
+        integer(output, 2).unwrap(); // Half volume.
+        integer(output, 4).unwrap(); // Wave ID.
+        integer(output, 1).unwrap(); // 7-bit LFSR.
+        output.push(0); // Unused.
+        output.extend(noise_macro.map(|offset| offset as u8));
+    }
+
+    /// What any instrument written by [`instrument_v2`] is upgraded to, in the bank of that kind.
+    fn upgraded_instrument(
+        kind: u32,
+        name: &'static str,
+        subpattern: Option<Subpattern>,
+    ) -> Instrument<'static> {
+        Instrument {
+            name: name.into(),
+            length: Some(10),
+            kind: match kind {
+                0 => InstrumentKind::Square {
+                    initial_volume: 12,
+                    envelope_dir: EnvelopeDirection::Down,
+                    envelope_pace: 3,
+                    sweep_time: 5,
+                    sweep_dir: SweepDirection::Down,
+                    sweep_shift: 2,
+                    duty: DutyType::Percent75,
+                },
+                1 => InstrumentKind::Wave {
+                    output_level: WaveOutputLevel::Half,
+                    wave_id: 4,
+                },
+                _ => InstrumentKind::Noise {
+                    initial_volume: 12,
+                    envelope_dir: EnvelopeDirection::Down,
+                    envelope_pace: 3,
+                    lfsr_width: LfsrWidth::Seven,
+                },
+            },
+            subpattern,
+            leftovers: InstrumentLeftovers {
+                length: 10,
+                initial_volume: 12,
+                envelope_dir: EnvelopeDirection::Down,
+                envelope_pace: 3,
+                sweep_time: 5,
+                sweep_dir: SweepDirection::Down,
+                sweep_shift: 2,
+                duty: DutyType::Percent75,
+                output_level: WaveOutputLevel::Half,
+                wave_id: 4,
+                lfsr_width: LfsrWidth::Seven,
+                subpattern: Box::new([Default::default(); 64]),
+            },
+        }
+    }
+
+    const NOISE_MACRO: [i8; 6] = [1, -2, 0, 3, 0, 0];
+    /// What [`NOISE_MACRO`] is converted to.
+    fn noise_macro_subpattern() -> Subpattern {
+        let mut subpattern = std::array::from_fn(|i| SubpatternCell {
+            offset: 90,
+            next_row_idx: (i as u8 + 1) % 32,
+            effect_code: EffectId::Arpeggio,
+            effect_param: 0,
+        });
+        // The note's tick is played as-is, then each of the macro's values on the next 6.
+        for (row, offset) in [(1, 37), (2, 34), (3, 36), (4, 39), (5, 36), (6, 36)] {
+            subpattern[row].offset = offset;
+        }
+        // Then the subpattern stays on an empty row.
+        subpattern[7].next_row_idx = 7;
+        subpattern
+    }
+
+    /// Waves were stored one sample per byte; before v3, with an extra byte after each.
+    fn wave_bank_v1(output: &mut Vec<u8>, padded: bool) {
+        for i in 0..16 {
+            output.extend((0..32).map(|j| (i + j) % 16));
+            if padded {
+                output.push(0);
+            }
+        }
+    }
+
+    fn upgraded_waves() -> WaveBank {
+        std::array::from_fn(|i| {
+            std::array::from_fn(|j| {
+                let sample = |k| ((i + k) % 16) as u8;
+                sample(j * 2) << 4 | sample(j * 2 + 1)
+            })
+        })
+    }
+
+    /// Cells didn't have a jump index before subpatterns were introduced.
+    fn pattern_v1(output: &mut Vec<u8>, note_row: Option<usize>) {
+        for row in 0..64 {
+            if note_row == Some(row) {
+                integer(output, Note::C_5 as u32).unwrap();
+                integer(output, 1).unwrap();
+                integer(output, EffectId::SetVol as u32).unwrap();
+                output.push(0x0F);
+            } else {
+                integer(output, Note::None as u32).unwrap();
+                integer(output, 0).unwrap();
+                integer(output, 0).unwrap();
+                output.push(0);
+            }
+        }
+    }
+
+    fn upgraded_pattern(note_row: Option<usize>) -> Pattern {
+        let mut pattern = [PatternCell::default(); 64];
+        if let Some(row) = note_row {
+            pattern[row] = PatternCell {
+                note: Note::C_5,
+                instrument: 1,
+                effect_code: EffectId::SetVol,
+                effect_param: 0x0F,
+            };
+        }
+        pattern
+    }
+
+    fn upgraded_song(
+        instruments: InstrCollection<'static>,
+        patterns: Vec<Pattern>,
+        order_matrix: Vec<[usize; 4]>,
+    ) -> Song<'static> {
+        Song {
+            name: "Old song".into(),
+            artist: "Someone".into(),
+            comment: "".into(),
+            instruments,
+            waves: upgraded_waves(),
+            ticks_per_row: 6,
+            timer_divider: None,
+            patterns,
+            order_matrix,
+            routines: Default::default(),
+            leftovers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn parse_v1() {
+        let mut bytes = vec![];
+        header_v0(&mut bytes, 1);
+        // A single bank, for all kinds of instruments.
+        for i in 0..15 {
+            match i {
+                0 => instrument_v2(&mut bytes, 0, "Lead", [0; 6]),
+                1 => instrument_v2(&mut bytes, 2, "Hi-hat", NOISE_MACRO),
+                _ => instrument_v2(&mut bytes, 0, "", [0; 6]),
+            }
+        }
+        wave_bank_v1(&mut bytes, true);
+        integer(&mut bytes, 6).unwrap(); // Ticks per row.
+        integer(&mut bytes, 2).unwrap(); // Number of patterns.
+        pattern_v1(&mut bytes, None);
+        pattern_v1(&mut bytes, Some(3));
+        order_matrix(&mut bytes, &[[0, 1, 0, 1]]).unwrap();
+        // No routines yet.
+
+        let name = |i| match i {
+            0 => "Lead",
+            1 => "Hi-hat",
+            _ => "",
+        };
+        // The bank is copied into each kind's, and only the noise instruments keep the macro.
+        let bank = |kind| {
+            std::array::from_fn(|i| {
+                let noise_macro = (kind == 2 && i == 1).then(noise_macro_subpattern);
+                upgraded_instrument(kind, name(i), noise_macro)
+            })
+        };
+        let expected = upgraded_song(
+            InstrCollection {
+                duty: bank(0),
+                wave: bank(1),
+                noise: bank(2),
+            },
+            vec![upgraded_pattern(None), upgraded_pattern(Some(3))],
+            vec![[0, 1, 0, 1]],
+        );
+        let parsed = super::super::parse_song(&bytes).expect("Old song should parse");
+        assert_eq!(parsed, expected);
+    }
+
+    /// Versions 3 and 5 only differ by how patterns are stored.
+    fn song_v3(version: u32) -> Vec<u8> {
+        let mut bytes = vec![];
+        header_v0(&mut bytes, version);
+        // One bank per kind of instrument, but still in the old format.
+        for kind in 0..3 {
+            instrument_v2(&mut bytes, kind, "Lead", [0; 6]);
+            instrument_v2(&mut bytes, kind, "Macro", NOISE_MACRO);
+            for _ in 2..15 {
+                instrument_v2(&mut bytes, kind, "", [0; 6]);
+            }
+        }
+        wave_bank_v1(&mut bytes, false);
+        integer(&mut bytes, 6).unwrap(); // Ticks per row.
+        if version < 5 {
+            integer(&mut bytes, 2).unwrap(); // Number of patterns.
+            pattern_v1(&mut bytes, None);
+            pattern_v1(&mut bytes, Some(3));
+            order_matrix(&mut bytes, &[[0, 1, 0, 1]]).unwrap();
+        } else {
+            // Patterns are stored along with their IDs, which may skip some.
+            integer(&mut bytes, 2).unwrap(); // Number of patterns.
+            integer(&mut bytes, 0).unwrap();
+            pattern_v1(&mut bytes, None);
+            integer(&mut bytes, 2).unwrap();
+            pattern_v1(&mut bytes, Some(3));
+            order_matrix(&mut bytes, &[[0, 2, 0, 2]]).unwrap();
+        }
+        for i in 0..16 {
+            ansi_string(&mut bytes, if i == 3 { "ret" } else { "" }).unwrap();
+        }
+        bytes
+    }
+
+    fn upgraded_song_v3(patterns: Vec<Pattern>, order_matrix: Vec<[usize; 4]>) -> Song<'static> {
+        // Only the noise instruments apply their macro.
+        let bank = |kind| {
+            std::array::from_fn(|i| match i {
+                0 => upgraded_instrument(kind, "Lead", None),
+                1 => upgraded_instrument(kind, "Macro", (kind == 2).then(noise_macro_subpattern)),
+                _ => upgraded_instrument(kind, "", None),
+            })
+        };
+        let mut song = upgraded_song(
+            InstrCollection {
+                duty: bank(0),
+                wave: bank(1),
+                noise: bank(2),
+            },
+            patterns,
+            order_matrix,
+        );
+        song.routines[3] = "ret".into();
+        song
+    }
+
+    #[test]
+    fn parse_v3() {
+        let expected = upgraded_song_v3(
+            vec![upgraded_pattern(None), upgraded_pattern(Some(3))],
+            vec![[0, 1, 0, 1]],
+        );
+        let bytes = song_v3(3);
+        let parsed = super::super::parse_song(&bytes).expect("Old song should parse");
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn parse_v5() {
+        let mut expected = upgraded_song_v3(
+            vec![
+                upgraded_pattern(None),
+                upgraded_pattern(None),
+                upgraded_pattern(Some(3)),
+            ],
+            vec![[0, 2, 0, 2]],
+        );
+        // The skipped pattern is blank, and remembered as missing.
+        expected.leftovers.missing_patterns = vec![1];
+        let bytes = song_v3(5);
+        let parsed = super::super::parse_song(&bytes).expect("Old song should parse");
+        assert_eq!(parsed, expected);
+    }
+}
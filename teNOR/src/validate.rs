@@ -0,0 +1,644 @@
+//! Checks songs for problems that would make them play incorrectly, or not at all.
+//!
+//! Some problems are always errors, since the song can't be exported correctly at all; the others
+//! are [lints](Lint), which can be allowed, or turned into warnings or errors, individually.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::{
+    json,
+    optimise::InstrKind,
+    playback,
+    song::{EffectId, InstrumentKind, Location, Note, PatternCell, Song},
+    LAST_NOTE, PATTERN_LENGTH,
+};
+
+/// Reports every problem found in the song.
+///
+/// Problems in rows are reported in the order in which playback would encounter them (only rows
+/// that can actually be reached are checked, since the others are never exported anyway), followed
+/// by problems with instruments, patterns, etc. as a whole.
+///
+/// Songs that have any [`Severity::Error`] must not be passed to [`optimise`](crate::optimise),
+/// which assumes that they are valid.
+pub fn validate(song: &Song, lint_levels: &LintLevels) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut report = |problem: Problem, location, effect| {
+        let severity = match problem.lint().map(|lint| lint_levels.get(lint)) {
+            None | Some(Level::Deny) => Severity::Error,
+            Some(Level::Warn) => Severity::Warning,
+            Some(Level::Allow) => return,
+        };
+        diagnostics.push(Diagnostic {
+            severity,
+            location,
+            effect,
+            problem,
+        });
+    };
+
+    let nb_orders = song.order_matrix.len();
+    let mut used_instrs = [0u16; 3];
+    let mut reached_patterns = vec![false; song.patterns.len()];
+    // `None` if the order row is never reached, otherwise whether any of its reached cells isn't blank.
+    let mut order_rows_used = vec![None; nb_orders];
+
+    for (order_idx, row) in playback::rows(song) {
+        for (channel, pattern_id) in song.order_matrix[order_idx].iter().cloned().enumerate() {
+            let cell = &song.patterns[pattern_id][row];
+            let kind = InstrKind::from_channel_id(channel);
+            let location = Location::Row {
+                order_idx,
+                channel,
+                pattern_id,
+                row,
+            };
+            let effect = Some((cell.effect_code, cell.effect_param));
+
+            match (cell.effect_code, cell.effect_param) {
+                // Following the order ignores these, so they don't affect which rows are checked.
+                (EffectId::PatternBreak, param) if param == 0 || param > PATTERN_LENGTH => {
+                    report(Problem::PatternBreakOutOfBounds, location, effect);
+                }
+                (EffectId::PosJump, param) if param == 0 || usize::from(param) > nb_orders => {
+                    report(Problem::PosJumpOutOfBounds { nb_orders }, location, effect);
+                }
+                (EffectId::ChangeTimbre, 16..) if kind == InstrKind::Wave => {
+                    report(Problem::WaveOutOfBounds, location, effect);
+                }
+                (EffectId::TonePorta, _) if kind == InstrKind::Noise => {
+                    report(Problem::TonePortaOnNoise, location, effect);
+                }
+                (EffectId::TonePorta, _) if cell.note == Note::None => {
+                    report(Problem::TonePortaWithoutNote, location, effect);
+                }
+                _ => {}
+            }
+            // Differences with hUGEDriver.
+            match (cell.effect_code, cell.effect_param) {
+                (EffectId::TonePorta, _) if cell.instrument != 0 && kind != InstrKind::Noise => {
+                    report(Problem::TonePortaWithInstrument, location, effect);
+                }
+                (EffectId::Vibrato, 1..) => report(Problem::VibratoShape, location, effect),
+                (EffectId::CallRoutine, param) => {
+                    report(Problem::RoutineNumber { param }, location, effect);
+                }
+                _ => {}
+            }
+
+            // Bit 0 is unused, since it marks "no instrument".
+            used_instrs[kind as usize] |= 1 << cell.instrument;
+            reached_patterns[pattern_id] = true;
+            let used = order_rows_used[order_idx].get_or_insert(false);
+            *used |= *cell != PatternCell::default();
+        }
+    }
+
+    for (kind, instruments) in [
+        (InstrKind::Duty, &song.instruments.duty),
+        (InstrKind::Wave, &song.instruments.wave),
+        (InstrKind::Noise, &song.instruments.noise),
+    ] {
+        for (i, instrument) in instruments.iter().enumerate() {
+            let id = i as u8 + 1;
+            if used_instrs[kind as usize] & (1 << id) == 0 {
+                // Instruments without a name are assumed to be unused slots, not forgotten instruments.
+                if !instrument.name.is_empty() {
+                    report(
+                        Problem::UnusedInstrument,
+                        Location::Instrument {
+                            kind,
+                            instrument: id,
+                        },
+                        None,
+                    );
+                }
+                continue;
+            }
+            if let InstrumentKind::Wave {
+                wave_id: wave_id @ 16..,
+                ..
+            } = instrument.kind
+            {
+                report(
+                    Problem::InstrumentWaveOutOfBounds { wave_id },
+                    Location::Instrument {
+                        kind,
+                        instrument: id,
+                    },
+                    None,
+                );
+            }
+            let Some(subpattern) = &instrument.subpattern else {
+                continue;
+            };
+
+            // Subpatterns being entirely self-contained, they loop as soon as a row is revisited.
+            let mut reached = 0u32;
+            let mut row = 0;
+            while reached & (1 << row) == 0 {
+                reached |= 1 << row;
+
+                let cell = &subpattern[row];
+                let location = Location::SubpatternRow {
+                    kind,
+                    instrument: id,
+                    row,
+                };
+                let effect = Some((cell.effect_code, cell.effect_param));
+                if let (EffectId::ChangeTimbre, 16..) = (cell.effect_code, cell.effect_param) {
+                    if kind == InstrKind::Wave {
+                        report(Problem::WaveOutOfBounds, location, effect);
+                    }
+                }
+                match (cell.effect_code, cell.effect_param) {
+                    (EffectId::Vibrato, 1..) => {
+                        report(Problem::VibratoInSubpattern, location, effect);
+                    }
+                    (EffectId::SetTempo, _) => report(Problem::TempoInSubpattern, location, effect),
+                    (EffectId::NoteDelay, _) => {
+                        report(Problem::AbsoluteNoteInSubpattern, location, effect);
+                    }
+                    _ => {}
+                }
+                // hUGETracker stores `J32` as 32, which hUGEDriver truncates to 0 ("no jump"); but
+                // on row 31, both mean going to row 32.
+                if cell.next_row_idx == 31 && row != 30 {
+                    report(Problem::SubpatternJumpToRow32, location, None);
+                }
+                if (LAST_NOTE..).contains(&cell.offset) && cell.offset != Note::None as u8 {
+                    report(
+                        Problem::NoteAboveLastNote {
+                            offset: cell.offset,
+                        },
+                        location,
+                        None,
+                    );
+                }
+
+                row = cell.next_row_idx.into();
+            }
+        }
+    }
+
+    let mut referenced_patterns: Vec<usize> = song.order_matrix.iter().flatten().cloned().collect();
+    referenced_patterns.sort_unstable();
+    referenced_patterns.dedup();
+    for pattern_id in referenced_patterns {
+        if !reached_patterns[pattern_id] {
+            report(
+                Problem::UnreachablePattern,
+                Location::Pattern { pattern_id },
+                None,
+            );
+        }
+    }
+
+    for (order_idx, used) in order_rows_used.into_iter().enumerate() {
+        if used == Some(false) {
+            report(
+                Problem::EmptyOrderRow,
+                Location::OrderRow { order_idx },
+                None,
+            );
+        }
+    }
+
+    for (routine_id, routine) in song.routines.iter().enumerate() {
+        if !routine.trim().is_empty() {
+            report(
+                Problem::IgnoredRoutine,
+                Location::Routine { routine_id },
+                None,
+            );
+        }
+    }
+
+    diagnostics
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Location,
+    /// The effect of the offending cell, if the problem is in one.
+    pub effect: Option<(EffectId, u8)>,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The song can be exported, but will likely not play as intended.
+    Warning,
+    /// The song cannot be exported (correctly, or at all).
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// `Bxx` targets an order row that doesn't exist.
+    PosJumpOutOfBounds {
+        nb_orders: usize,
+    },
+    /// `Dxx` targets a row that doesn't exist.
+    PatternBreakOutOfBounds,
+    /// `9xx` on CH3 refers to a wave that doesn't exist.
+    WaveOutOfBounds,
+    /// A wave instrument that gets played refers to a wave that doesn't exist.
+    InstrumentWaveOutOfBounds {
+        wave_id: u8,
+    },
+    TonePortaWithoutNote,
+    TonePortaOnNoise,
+    UnusedInstrument,
+    UnreachablePattern,
+    EmptyOrderRow,
+    NoteAboveLastNote {
+        offset: u8,
+    },
+    IgnoredRoutine,
+    TonePortaWithInstrument,
+    VibratoShape,
+    RoutineNumber {
+        param: u8,
+    },
+    VibratoInSubpattern,
+    TempoInSubpattern,
+    AbsoluteNoteInSubpattern,
+    SubpatternJumpToRow32,
+}
+
+impl Problem {
+    /// Which lint controls whether this problem is reported; `None` if it's always an error.
+    pub fn lint(&self) -> Option<Lint> {
+        match self {
+            Self::PosJumpOutOfBounds { .. }
+            | Self::PatternBreakOutOfBounds
+            | Self::WaveOutOfBounds
+            | Self::InstrumentWaveOutOfBounds { .. } => None,
+            Self::TonePortaWithoutNote => Some(Lint::TonePortaWithoutNote),
+            Self::TonePortaOnNoise => Some(Lint::TonePortaOnNoise),
+            Self::UnusedInstrument => Some(Lint::UnusedInstruments),
+            Self::UnreachablePattern => Some(Lint::UnreachablePatterns),
+            Self::EmptyOrderRow => Some(Lint::EmptyOrderRows),
+            Self::NoteAboveLastNote { .. } => Some(Lint::NotesAboveLastNote),
+            Self::IgnoredRoutine => Some(Lint::IgnoredRoutines),
+            Self::TonePortaWithInstrument => Some(Lint::TonePortaWithInstrument),
+            Self::VibratoShape => Some(Lint::VibratoShape),
+            Self::RoutineNumber { .. } => Some(Lint::RoutineNumbers),
+            Self::VibratoInSubpattern => Some(Lint::VibratoInSubpatterns),
+            Self::TempoInSubpattern => Some(Lint::TempoInSubpatterns),
+            Self::AbsoluteNoteInSubpattern => Some(Lint::AbsoluteNotesInSubpatterns),
+            Self::SubpatternJumpToRow32 => Some(Lint::SubpatternJumpsToRow32),
+        }
+    }
+}
+
+/// Problems that don't prevent exporting a song, but may still be worth fixing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lint {
+    /// `3xx` on a row without a note, which thus has nothing to slide towards.
+    TonePortaWithoutNote,
+    /// `3xx` on CH4, which fortISSimO does not support.
+    TonePortaOnNoise,
+    /// Named instruments that are never played.
+    UnusedInstruments,
+    /// Patterns that are in the order, but never played (e.g. because of `Bxx`).
+    UnreachablePatterns,
+    /// Order rows whose played cells are all blank.
+    EmptyOrderRows,
+    /// Subpattern note offsets too high to be applied, and thus ignored.
+    NotesAboveLastNote,
+    /// Routines written in the `.uge` file, which teNOR does not export.
+    IgnoredRoutines,
+    /// `3xx` on a row with an instrument, which hUGEDriver reloads but fortISSimO ignores.
+    TonePortaWithInstrument,
+    /// `4xx`, which is a triangle under fortISSimO but a square under hUGEDriver.
+    VibratoShape,
+    /// `6xx`, which calls one of 16 routines under hUGEDriver, but the only one under fortISSimO.
+    RoutineNumbers,
+    /// `4xx` in subpatterns, which fortISSimO ignores.
+    VibratoInSubpatterns,
+    /// `Fxx` in subpatterns, which fortISSimO ignores.
+    TempoInSubpatterns,
+    /// `7xx` in subpatterns, which fortISSimO plays as an absolute note.
+    AbsoluteNotesInSubpatterns,
+    /// `J32` in subpatterns, which hUGEDriver ignores.
+    SubpatternJumpsToRow32,
+}
+
+impl Lint {
+    pub const ALL: [Self; 14] = [
+        Self::TonePortaWithoutNote,
+        Self::TonePortaOnNoise,
+        Self::UnusedInstruments,
+        Self::UnreachablePatterns,
+        Self::EmptyOrderRows,
+        Self::NotesAboveLastNote,
+        Self::IgnoredRoutines,
+        Self::TonePortaWithInstrument,
+        Self::VibratoShape,
+        Self::RoutineNumbers,
+        Self::VibratoInSubpatterns,
+        Self::TempoInSubpatterns,
+        Self::AbsoluteNotesInSubpatterns,
+        Self::SubpatternJumpsToRow32,
+    ];
+
+    /// The lints about cells that play differently under hUGEDriver (and thus in hUGETracker) than
+    /// under fortISSimO; they can all be set at once with the name `hugedriver-differences`.
+    pub const HUGEDRIVER_DIFFERENCES: [Self; 7] = [
+        Self::TonePortaWithInstrument,
+        Self::VibratoShape,
+        Self::RoutineNumbers,
+        Self::VibratoInSubpatterns,
+        Self::TempoInSubpatterns,
+        Self::AbsoluteNotesInSubpatterns,
+        Self::SubpatternJumpsToRow32,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TonePortaWithoutNote => "tone-porta-without-note",
+            Self::TonePortaOnNoise => "tone-porta-on-noise",
+            Self::UnusedInstruments => "unused-instruments",
+            Self::UnreachablePatterns => "unreachable-patterns",
+            Self::EmptyOrderRows => "empty-order-rows",
+            Self::NotesAboveLastNote => "notes-above-last-note",
+            Self::IgnoredRoutines => "ignored-routines",
+            Self::TonePortaWithInstrument => "tone-porta-with-instrument",
+            Self::VibratoShape => "vibrato-shape",
+            Self::RoutineNumbers => "routine-numbers",
+            Self::VibratoInSubpatterns => "vibrato-in-subpatterns",
+            Self::TempoInSubpatterns => "tempo-in-subpatterns",
+            Self::AbsoluteNotesInSubpatterns => "absolute-notes-in-subpatterns",
+            Self::SubpatternJumpsToRow32 => "subpattern-jumps-to-row-32",
+        }
+    }
+
+    pub fn default_level(&self) -> Level {
+        match self {
+            Self::TonePortaWithoutNote
+            | Self::TonePortaOnNoise
+            | Self::NotesAboveLastNote
+            | Self::IgnoredRoutines
+            | Self::TonePortaWithInstrument
+            | Self::VibratoInSubpatterns
+            | Self::TempoInSubpatterns
+            | Self::AbsoluteNotesInSubpatterns
+            | Self::SubpatternJumpsToRow32 => Level::Warn,
+            // These are common in works in progress, and harmless since teNOR prunes them anyway.
+            Self::UnusedInstruments | Self::UnreachablePatterns | Self::EmptyOrderRows => {
+                Level::Allow
+            }
+            // These would fire on every use of the effect, even in songs written for fortISSimO.
+            Self::VibratoShape | Self::RoutineNumbers => Level::Allow,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// The lint isn't reported at all.
+    Allow,
+    /// The lint is reported as a warning.
+    Warn,
+    /// The lint is reported as an error.
+    Deny,
+}
+
+/// Which level each lint is at; lints that haven't been set are at their default level.
+#[derive(Debug, Clone, Default)]
+pub struct LintLevels {
+    levels: HashMap<Lint, Level>,
+    /// Overrides the level of all lints that would otherwise warn.
+    warnings: Option<Level>,
+}
+
+impl LintLevels {
+    pub fn get(&self, lint: Lint) -> Level {
+        match self
+            .levels
+            .get(&lint)
+            .copied()
+            .unwrap_or(lint.default_level())
+        {
+            Level::Warn => self.warnings.unwrap_or(Level::Warn),
+            level => level,
+        }
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    /// Like [`set`](Self::set), but by name; the special name `warnings` applies to all lints that
+    /// would warn, so that e.g. they can all be denied at once, and `hugedriver-differences` to all
+    /// of [`Lint::HUGEDRIVER_DIFFERENCES`].
+    pub fn set_by_name(&mut self, name: &str, level: Level) -> Result<(), String> {
+        if name == "warnings" {
+            self.warnings = Some(level);
+        } else if name == "hugedriver-differences" {
+            for lint in Lint::HUGEDRIVER_DIFFERENCES {
+                self.set(lint, level);
+            }
+        } else {
+            self.set(name.parse()?, level);
+        }
+        Ok(())
+    }
+
+    /// Applies a configuration file's settings, which are lines of the form `<lint> = <level>`.
+    /// Blank lines and comments (starting with `#`) are ignored, and levels may be quoted, so that
+    /// the file can be valid TOML.
+    pub fn apply_config(&mut self, config: &str) -> Result<(), String> {
+        for (line_no, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((name, level)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `<lint> = <level>`", line_no + 1));
+            };
+            let level = level.trim().trim_matches('"').parse()?;
+            self.set_by_name(name.trim(), level)
+                .map_err(|err| format!("line {}: {err}", line_no + 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|lint| lint.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(Lint::name).collect();
+                format!(
+                    "unknown lint \"{s}\" (expected `warnings`, `hugedriver-differences`, or one of: {})",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "warn" => Ok(Self::Warn),
+            "deny" => Ok(Self::Deny),
+            _ => Err(format!(
+                "unknown lint level \"{s}\" (expected `allow`, `warn`, or `deny`)"
+            )),
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PosJumpOutOfBounds { nb_orders } => write!(
+                f,
+                "Position jump must target an order row between 1 and {nb_orders}"
+            ),
+            Self::PatternBreakOutOfBounds => write!(
+                f,
+                "Pattern break must target a row between 1 and {PATTERN_LENGTH}"
+            ),
+            Self::WaveOutOfBounds => write!(f, "Wave ID must be between 0 and 15"),
+            Self::InstrumentWaveOutOfBounds { wave_id } => write!(
+                f,
+                "Instrument uses wave {wave_id}, but wave IDs must be between 0 and 15"
+            ),
+            Self::TonePortaWithoutNote => {
+                write!(f, "Tone portamento has no note to slide towards")
+            }
+            Self::TonePortaOnNoise => write!(f, "Tone portamento is not supported on CH4"),
+            Self::UnusedInstrument => write!(f, "Instrument is never played"),
+            Self::UnreachablePattern => write!(f, "Pattern is in the order, but never played"),
+            Self::EmptyOrderRow => write!(f, "Order row is entirely blank"),
+            Self::NoteAboveLastNote { offset } => write!(
+                f,
+                "Note offset {offset} is not below {LAST_NOTE}, so it is ignored"
+            ),
+            Self::IgnoredRoutine => write!(f, "Routine is not exported by teNOR"),
+            Self::TonePortaWithInstrument => write!(
+                f,
+                "Tone portamento ignores the instrument under fortISSimO, but reloads it under hUGEDriver"
+            ),
+            Self::VibratoShape => write!(
+                f,
+                "Vibrato is a triangle under fortISSimO, but a square under hUGEDriver, which also reads its parameter differently"
+            ),
+            Self::RoutineNumber { param } => write!(
+                f,
+                "hUGEDriver calls routine {}, but fortISSimO calls the song's only routine, with ${param:02X} as the argument",
+                param & 0x0F
+            ),
+            Self::VibratoInSubpattern => write!(
+                f,
+                "Vibrato is not supported in subpatterns by fortISSimO, so it is ignored"
+            ),
+            Self::TempoInSubpattern => write!(
+                f,
+                "Tempo changes are not supported in subpatterns by fortISSimO, so this is ignored"
+            ),
+            Self::AbsoluteNoteInSubpattern => write!(
+                f,
+                "In subpatterns, fortISSimO plays note delays as an absolute note instead"
+            ),
+            Self::SubpatternJumpToRow32 => write!(
+                f,
+                "Jumps to row 32 are ignored by hUGEDriver, but not by fortISSimO"
+            ),
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Serialises the diagnostic as a single-line JSON object; `file` is the song it is about.
+    pub fn to_json(&self, file: &str) -> String {
+        json::diagnostic(
+            file,
+            self.severity,
+            self.problem.lint(),
+            &self.problem.to_string(),
+            self.effect,
+            Some(&self.location.to_json()),
+        )
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (", self.problem)?;
+        if let Some((effect_code, effect_param)) = self.effect {
+            write!(f, "effect {:X}{effect_param:02X}, ", effect_code as u8)?;
+        }
+        write!(f, "at {})", self.location)?;
+        if let Some(lint) = self.problem.lint() {
+            write!(f, " [{lint}]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{song::WaveOutputLevel, uge::test_song};
+
+    #[test]
+    fn instrument_wave_out_of_bounds() {
+        let mut song = test_song();
+        // Play pattern 2, whose only note uses instrument 1, on CH3.
+        song.order_matrix = vec![[0, 0, 2, 0]];
+        song.instruments.wave[0].kind = InstrumentKind::Wave {
+            output_level: WaveOutputLevel::Full,
+            wave_id: 20,
+        };
+
+        let diagnostics = validate(&song, &LintLevels::default());
+        assert!(diagnostics.iter().any(|diagnostic| matches!(
+            diagnostic,
+            Diagnostic {
+                severity: Severity::Error,
+                location: Location::Instrument {
+                    kind: InstrKind::Wave,
+                    instrument: 1,
+                },
+                problem: Problem::InstrumentWaveOutOfBounds { wave_id: 20 },
+                ..
+            }
+        )));
+
+        // Unused instruments can't break anything.
+        song.order_matrix = vec![[0, 0, 0, 0]];
+        let diagnostics = validate(&song, &LintLevels::default());
+        assert!(!diagnostics.iter().any(|diagnostic| matches!(
+            diagnostic.problem,
+            Problem::InstrumentWaveOutOfBounds { .. }
+        )));
+    }
+}